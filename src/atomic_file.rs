@@ -0,0 +1,21 @@
+//! Replacing files so that a crash part way through leaves either the old
+//! file or the new one, never a truncated one.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Writes `contents` to a temporary file next to `path`, then renames it over `path`.
+///
+/// The temporary file is named after `path` with `.tmp` appended, so a
+/// leftover one is never mistaken for the file itself.
+pub fn write(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temp_path = PathBuf::from(path).into_os_string();
+    temp_path.push(".tmp");
+
+    let mut file = File::create(&temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+
+    fs::rename(&temp_path, path)
+}
@@ -0,0 +1,267 @@
+//! Stand-in for the google places `searchText` endpoint, serving canned
+//! responses from a fixtures directory so a crawl can run without a network or
+//! an API key:
+//!
+//! ```text
+//! cargo run --bin mock_places -- --fixtures tests/fixtures/places
+//! coffee_map --google-places-url http://127.0.0.1:8765 --no-cache crawl --replay tests/fixtures/katana/sample.jsonl
+//! ```
+//!
+//! A request's `textQuery` is turned into a slug, lowercase words joined by
+//! `-`, and answered from the fixtures:
+//!
+//! - `<slug>.json` is served with status 200.
+//! - `<slug>.<status>.json`, e.g. `<slug>.503.json`, is served with that
+//!   status. When `<slug>.json` exists too, the error is only served for the
+//!   first request, like a transient failure that a retry gets past.
+//! - Search terms without a fixture get `{}`, google's answer when nothing matched.
+//!
+//! Requests without an `X-Goog-FieldMask` header are refused with 400, as google does.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use clap::Parser;
+use serde_json::Value;
+
+#[path = "../words.rs"]
+mod words;
+
+use words::words;
+
+const SEARCH_TEXT_PATH: &str = "/v1/places:searchText";
+
+/// Serve canned google places `searchText` responses from a fixtures directory.
+#[derive(Parser, Debug)]
+struct Args {
+    /// Directory holding `<slug>.json` and `<slug>.<status>.json` responses.
+    #[arg(long, default_value = "tests/fixtures/places")]
+    fixtures: PathBuf,
+
+    /// Port to listen on, on 127.0.0.1.
+    #[arg(long, default_value_t = 8765)]
+    port: u16,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    body: String,
+}
+
+/// Fixtures and the slugs whose error fixture has already been served.
+struct Fixtures {
+    directory: PathBuf,
+    failed_once: Mutex<HashMap<String, usize>>,
+}
+
+fn main() {
+    let args = Args::parse();
+    let listener = TcpListener::bind(("127.0.0.1", args.port)).unwrap_or_else(|err| {
+        eprintln!("could not listen on port {}: {}", args.port, err);
+        std::process::exit(1);
+    });
+    let fixtures = Arc::new(Fixtures {
+        directory: args.fixtures,
+        failed_once: Mutex::new(HashMap::new()),
+    });
+
+    println!(
+        "serving {} from {} on http://127.0.0.1:{}",
+        SEARCH_TEXT_PATH,
+        fixtures.directory.display(),
+        args.port
+    );
+
+    for stream in listener.incoming().flatten() {
+        let fixtures = Arc::clone(&fixtures);
+        thread::spawn(move || serve_connection(stream, &fixtures));
+    }
+}
+
+fn serve_connection(stream: TcpStream, fixtures: &Fixtures) {
+    let Some(request) = read_request(&stream) else {
+        return;
+    };
+
+    let response = respond(&request, fixtures);
+
+    let _ = write!(
+        &stream,
+        "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.body.len(),
+        response.body
+    );
+}
+
+fn read_request(stream: &TcpStream) -> Option<Request> {
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).ok()?;
+    let mut request_line = request_line.split_whitespace();
+    let method = request_line.next()?.to_string();
+    let path = request_line.next()?.to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut header_line = String::new();
+        if reader.read_line(&mut header_line).is_err() || header_line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header_line.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+
+    let content_length = headers
+        .get("content-length")
+        .and_then(|length| length.parse::<usize>().ok())
+        .unwrap_or_default();
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).ok()?;
+
+    Some(Request {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+fn respond(request: &Request, fixtures: &Fixtures) -> Response {
+    if request.method != "POST" || request.path != SEARCH_TEXT_PATH {
+        return error(404, "not found");
+    }
+
+    if !request.headers.contains_key("x-goog-fieldmask") {
+        return error(400, "FieldMask is a required parameter");
+    }
+
+    let Some(text_query) = serde_json::from_slice::<Value>(&request.body)
+        .ok()
+        .and_then(|body| body.get("textQuery")?.as_str().map(str::to_string))
+    else {
+        return error(400, "textQuery is a required parameter");
+    };
+
+    let slug = words(&text_query).collect::<Vec<String>>().join("-");
+    let response = fixtures.response(&slug);
+    println!("{} -> {} ({})", text_query, slug, response.status);
+
+    response
+}
+
+impl Fixtures {
+    fn response(&self, slug: &str) -> Response {
+        let success = fs::read_to_string(self.directory.join(format!("{}.json", slug))).ok();
+        let failure = error_fixture(&self.directory, slug);
+
+        match (failure, success) {
+            (Some(failure), Some(success)) => {
+                let mut failed_once = self.failed_once.lock().unwrap();
+                let failures = failed_once.entry(slug.to_string()).or_default();
+                *failures += 1;
+
+                if *failures == 1 {
+                    failure
+                } else {
+                    Response {
+                        status: 200,
+                        body: success,
+                    }
+                }
+            }
+            (Some(failure), None) => failure,
+            (None, Some(success)) => Response {
+                status: 200,
+                body: success,
+            },
+            (None, None) => Response {
+                status: 200,
+                body: "{}".to_string(),
+            },
+        }
+    }
+}
+
+/// The `<slug>.<status>.json` fixture, if there is one.
+fn error_fixture(directory: &Path, slug: &str) -> Option<Response> {
+    fs::read_dir(directory).ok()?.flatten().find_map(|entry| {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let status = file_name
+            .strip_prefix(slug)?
+            .strip_prefix('.')?
+            .strip_suffix(".json")?
+            .parse::<u16>()
+            .ok()?;
+
+        Some(Response {
+            status,
+            body: fs::read_to_string(entry.path()).ok()?,
+        })
+    })
+}
+
+fn error(status: u16, message: &str) -> Response {
+    Response {
+        status,
+        body: serde_json::json!({ "error": { "code": status, "message": message } }).to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn search(fixtures: &Fixtures, text_query: &str, field_mask: bool) -> Response {
+        let mut headers = HashMap::new();
+        if field_mask {
+            headers.insert("x-goog-fieldmask".to_string(), "places.id".to_string());
+        }
+
+        respond(
+            &Request {
+                method: "POST".to_string(),
+                path: SEARCH_TEXT_PATH.to_string(),
+                headers,
+                body: serde_json::json!({ "textQuery": text_query })
+                    .to_string()
+                    .into_bytes(),
+            },
+            fixtures,
+        )
+    }
+
+    #[test]
+    fn serves_bundled_fixtures_failing_once_where_there_is_an_error_fixture() {
+        let fixtures = Fixtures {
+            directory: PathBuf::from(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/fixtures/places"
+            )),
+            failed_once: Mutex::new(HashMap::new()),
+        };
+        let kaffebar = "Kaffebar Nord Nørrebrogade 12, 2200 Copenhagen, Denmark";
+
+        assert_eq!(search(&fixtures, kaffebar, true).status, 503);
+        assert_eq!(search(&fixtures, kaffebar, true).status, 200);
+        assert!(search(&fixtures, "Mobile Espresso Cart", true)
+            .body
+            .contains("\"places\""));
+        assert_eq!(search(&fixtures, "Unknown Cafe", true).body, "{}");
+        assert_eq!(search(&fixtures, "Mobile Espresso Cart", false).status, 400);
+    }
+}
@@ -1,68 +1,257 @@
-use std::collections::HashMap;
-use std::{fs, path::Path};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::{fs, io};
 
+use chrono::Utc;
 use kml::{types::Placemark, Kml, KmlDocument};
+use url::Url;
 
+use crate::ect_page;
+use crate::geocoder;
 use crate::model::{IOError, PlacemarkComputation};
 use crate::write_kml;
 
 const CACHE_FILENAME: &str = "cache.kml";
+/// Prefix of the files holding placemarks added by a crawl that has not
+/// finished yet. They sort after [`CACHE_FILENAME`] and in the order written.
+const CACHE_UPDATE_PREFIX: &str = "cache_update_";
 
+/// Placemark attribute holding the ECT page a placemark was found from.
+pub const ECT_URL_ATTR: &str = "ect_url";
+
+/// Cached placemarks keyed by search term and by the geocoder that found them,
+/// indexed by the ECT page they were found from.
+///
+/// Placemarks cached before the geocoder was recorded are google's, see
+/// [`geocoder::found_by`]. A placemark placed at its ECT page's coordinates
+/// serves every geocoder.
+#[derive(Clone, Default)]
+pub struct PlacemarkCache {
+    by_search_term: HashMap<String, Vec<Placemark>>,
+    search_terms_by_ect_url: HashMap<String, String>,
+}
+
+impl PlacemarkCache {
+    /// Cache of `placemarks`, where later placemarks replace earlier ones with
+    /// the same search term and geocoder. Placemarks without a search term are left out.
+    pub fn new(placemarks: Vec<Placemark>) -> Self {
+        let mut cache = PlacemarkCache::default();
+
+        for placemark in placemarks {
+            if let Some(search_term) = placemark.attrs.get("search_term").cloned() {
+                cache.insert(search_term, placemark);
+            }
+        }
+
+        cache
+    }
+
+    fn insert(&mut self, search_term: String, placemark: Placemark) {
+        if let Some(ect_url) = placemark
+            .attrs
+            .get(ECT_URL_ATTR)
+            .and_then(|ect_url| Url::parse(ect_url).ok())
+        {
+            self.search_terms_by_ect_url
+                .insert(ect_page::endpoint_key(&ect_url), search_term.clone());
+        }
+
+        let placemarks = self.by_search_term.entry(search_term).or_default();
+        placemarks.retain(|cached| geocoder::found_by(cached) != geocoder::found_by(&placemark));
+        placemarks.push(placemark);
+    }
+
+    /// The placemark cached for `search_term` that `geocoder` may use.
+    pub fn get(&self, search_term: &String, geocoder: &str) -> Option<&Placemark> {
+        self.by_search_term
+            .get(search_term)?
+            .iter()
+            .find(|placemark| {
+                geocoder::found_by(placemark).is_none_or(|found_by| found_by == geocoder)
+            })
+    }
+
+    /// The search term and placemark previously found from the ECT page
+    /// `ect_url` that `geocoder` may use.
+    pub fn get_by_ect_url(&self, ect_url: &Url, geocoder: &str) -> Option<(&String, &Placemark)> {
+        let search_term = self
+            .search_terms_by_ect_url
+            .get(&ect_page::endpoint_key(ect_url))?;
+
+        Some((search_term, self.get(search_term, geocoder)?))
+    }
+
+    /// [`ect_page::endpoint_key`]s of every cached ECT page.
+    pub fn ect_url_keys(&self) -> HashSet<String> {
+        self.search_terms_by_ect_url.keys().cloned().collect()
+    }
+
+    /// Every cached search term with each of its placemarks.
+    pub fn entries(&self) -> impl Iterator<Item = (&String, &Placemark)> {
+        self.by_search_term
+            .iter()
+            .flat_map(|(search_term, placemarks)| {
+                placemarks
+                    .iter()
+                    .map(move |placemark| (search_term, placemark))
+            })
+    }
+
+    /// One placemark per search term, the one `geocoder` may use.
+    pub fn placemarks_for(&self, geocoder: &str) -> Vec<Placemark> {
+        self.by_search_term
+            .keys()
+            .filter_map(|search_term| self.get(search_term, geocoder))
+            .cloned()
+            .collect()
+    }
+
+    pub fn into_placemarks(self) -> Vec<Placemark> {
+        self.by_search_term.into_values().flatten().collect()
+    }
+
+    pub fn search_term_count(&self) -> usize {
+        self.by_search_term.len()
+    }
+}
+
+/// Writes `new_placemarks` to a cache update file of their own, leaving the
+/// rest of the cache untouched. Their search terms override earlier entries
+/// when the cache is loaded.
+pub fn append(cache_folder: &str, new_placemarks: &[PlacemarkComputation]) -> Result<(), IOError> {
+    if new_placemarks.is_empty() {
+        return Ok(());
+    }
+
+    let filename = format!(
+        "{}{}.kml",
+        CACHE_UPDATE_PREFIX,
+        Utc::now().format("%Y%m%dT%H%M%S%.9f")
+    );
+
+    write_kml::generate_kml_document(
+        new_placemarks
+            .iter()
+            .map(|computation| computation.get_placemark().clone())
+            .collect(),
+        cache_folder.to_string(),
+        filename,
+    )
+}
+
+/// Rewrites the whole cache with `new_placemarks` added, merging and removing
+/// the update files written by [`append`].
 pub fn update(
     cache_folder: String,
-    cache: HashMap<String, Placemark>,
-    new_placemarks: &Vec<PlacemarkComputation>,
+    cache: &PlacemarkCache,
+    new_placemarks: &[PlacemarkComputation],
 ) -> Result<(), IOError> {
-    let mut new_cache = HashMap::clone(&cache);
+    let mut new_cache = cache.clone();
 
     for placemark in new_placemarks {
         let search_term = placemark.get_search_term().extract_str().clone();
 
-        search_term.map(|search_term| new_cache.insert(search_term, placemark.clone()));
+        new_cache.insert(search_term, placemark.get_placemark().clone());
     }
 
     write_kml::generate_kml_document(
-        new_cache.into_values().collect::<Vec<Placemark>>(),
-        cache_folder,
+        new_cache.into_placemarks(),
+        cache_folder.clone(),
         CACHE_FILENAME.to_string(),
-    )
+    )?;
+
+    remove_update_files(Path::new(&cache_folder)).map_err(IOError::CheckpointWrite)
+}
+
+fn remove_update_files(cache_folder: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(cache_folder)? {
+        let path = entry?.path();
+        if is_update_file(&path) {
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
 }
 
-pub fn make_existing_placemarks_hashmap(cache_folder: String) -> HashMap<String, Placemark> {
-    let current_kml_folder = Path::new(cache_folder.as_str());
-    let existing_placemarks = read_placemarks_in_directory(current_kml_folder);
+/// Reads the cache in `cache_folder`, which holds nothing yet when it does
+/// not exist.
+///
+/// An update file that cannot be read, e.g. one a crawl killed by an older
+/// version left half written, is skipped with a warning; its cafes are
+/// geocoded again. An unreadable `cache.kml` is an error, as carrying on would
+/// overwrite it with what little else was read.
+pub fn load(cache_folder: String) -> Result<PlacemarkCache, IOError> {
+    let mut existing_placemarks = vec![];
+
+    for path in kml_files_in(Path::new(cache_folder.as_str()))? {
+        match read_placemarks_from_file(&path) {
+            Ok(placemarks) => existing_placemarks.extend(placemarks),
+            Err(err) if is_update_file(&path) => {
+                println!("{}; skipping the update file", err)
+            }
+            Err(err) => return Err(err),
+        }
+    }
 
     println!(
         "existing coffee map contains {} entries",
         existing_placemarks.len()
     );
 
-    existing_placemarks
-        .into_iter()
-        .filter_map(|placemark| {
-            let searchterm = placemark.attrs.get("search_term")?;
+    Ok(PlacemarkCache::new(existing_placemarks))
+}
 
-            Some((searchterm.clone(), placemark))
-        })
-        .collect()
+/// The kml files in `folder`, in filename order; a folder that does not exist
+/// yet holds none.
+fn kml_files_in(folder: &Path) -> Result<Vec<PathBuf>, IOError> {
+    let read_error = |err: io::Error| IOError::CacheRead(format!("{}: {}", folder.display(), err));
+    let entries = match fs::read_dir(folder) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(read_error(err)),
+    };
+
+    let mut kml_files = vec![];
+    for entry in entries {
+        let path = entry.map_err(read_error)?.path();
+        if path.extension().is_some_and(|extension| extension == "kml") {
+            kml_files.push(path);
+        }
+    }
+    kml_files.sort();
+
+    Ok(kml_files)
 }
 
-pub fn read_placemarks_in_directory<P: AsRef<Path>>(path: P) -> Vec<Placemark> {
-    fs::read_dir(path)
-        .unwrap()
-        .map(|file_path| read_placemarks_from_file(file_path.unwrap().path()))
-        .flatten()
-        .collect()
+fn is_update_file(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with(CACHE_UPDATE_PREFIX))
 }
 
-pub fn read_placemarks_from_file<P>(path: P) -> Vec<Placemark>
+pub fn read_placemarks_from_file<P>(path: P) -> Result<Vec<Placemark>, IOError>
 where
     P: AsRef<Path>,
 {
-    let kml_string = fs::read_to_string(path).unwrap();
-    let kml: Kml = kml_string.parse().unwrap();
+    let path = path.as_ref();
 
-    parse_placemarks(kml).unwrap()
+    fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|kml_string| parse_kml(&kml_string))
+        .map_err(|err| IOError::CacheRead(format!("{}: {}", path.display(), err)))
+}
+
+fn parse_kml(kml_string: &str) -> Result<Vec<Placemark>, String> {
+    // The kml parser reads a document cut short as one with fewer placemarks.
+    if !kml_string.trim_end().ends_with("</kml>") {
+        return Err("the file ends before the closing </kml>".to_string());
+    }
+
+    let kml: Kml = kml_string
+        .parse()
+        .map_err(|err: kml::Error| err.to_string())?;
+
+    parse_placemarks(kml).ok_or_else(|| "not a kml document of placemarks".to_string())
 }
 
 fn parse_placemarks(kml: Kml) -> Option<Vec<Placemark>> {
@@ -80,14 +269,111 @@ fn parse_placemarks(kml: Kml) -> Option<Vec<Placemark>> {
         _ => None,
     }?;
 
-    let placemarks = kml_document_elements
-        .into_iter()
-        .filter_map(|element| match element {
-            Kml::Placemark(placemark) => Some(placemark.to_owned()),
-            _ => None,
+    Some(placemarks_in(kml_document_elements))
+}
+
+/// Placemarks among `elements`, including those inside folders such as the
+/// one holding closed cafes.
+fn placemarks_in(elements: &[Kml]) -> Vec<Placemark> {
+    elements
+        .iter()
+        .flat_map(|element| match element {
+            Kml::Placemark(placemark) => vec![placemark.to_owned()],
+            Kml::Folder { attrs: _, elements } => placemarks_in(elements),
+            _ => vec![],
         })
-        .into_iter()
-        .collect();
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geocoder::GEOCODER_ATTR;
+
+    #[test]
+    fn reads_a_cache_folder_that_does_not_exist_yet_as_empty() {
+        let folder =
+            std::env::temp_dir().join(format!("coffee_map_missing_cache_{}", std::process::id()));
+
+        assert!(kml_files_in(&folder).unwrap().is_empty());
+        assert_eq!(
+            load(folder.to_string_lossy().to_string())
+                .unwrap()
+                .search_term_count(),
+            0
+        );
+    }
+
+    #[test]
+    fn refuses_a_truncated_cache_file_but_skips_a_truncated_update_file() {
+        let folder =
+            std::env::temp_dir().join(format!("coffee_map_truncated_cache_{}", std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        let truncated = "<kml xmlns=\"http://www.opengis.net/kml/2.2\"><Document><Placemark>";
+
+        fs::write(folder.join("cache_update_1.kml"), truncated).unwrap();
+        let skipped = load(folder.display().to_string()).map(|cache| cache.search_term_count());
+        fs::write(folder.join(CACHE_FILENAME), truncated).unwrap();
+        let refused = load(folder.display().to_string());
+        fs::remove_dir_all(&folder).unwrap();
+
+        assert!(matches!(skipped, Ok(0)));
+        assert!(matches!(refused, Err(IOError::CacheRead(_))));
+    }
+
+    fn placemark(search_term: &str, geocoder: Option<&str>, name: &str) -> Placemark {
+        let mut placemark = Placemark {
+            name: Some(name.to_string()),
+            attrs: HashMap::from([("search_term".to_string(), search_term.to_string())]),
+            ..Default::default()
+        };
+        if let Some(geocoder) = geocoder {
+            placemark
+                .attrs
+                .insert(GEOCODER_ATTR.to_string(), geocoder.to_string());
+        }
+
+        placemark
+    }
+
+    /// A google placemark cached before the geocoder was recorded.
+    fn legacy_placemark(search_term: &str) -> Placemark {
+        let mut placemark = placemark(search_term, None, search_term);
+        placemark
+            .attrs
+            .insert("id".to_string(), format!("ChIJ{}", search_term));
 
-    Some(placemarks)
+        placemark
+    }
+
+    #[test]
+    fn serves_each_geocoder_only_its_own_placemarks() {
+        let cache = PlacemarkCache::new(vec![
+            placemark("alpha", Some("google"), "old google alpha"),
+            placemark("alpha", Some("fake"), "fake alpha"),
+            placemark("alpha", Some("google"), "google alpha"),
+            placemark("beta", None, "beta from its ECT page"),
+            legacy_placemark("gamma"),
+        ]);
+        let name = |search_term: &str, geocoder: &str| {
+            cache
+                .get(&search_term.to_string(), geocoder)
+                .and_then(|placemark| placemark.name.clone())
+        };
+
+        assert_eq!(name("alpha", "google").as_deref(), Some("google alpha"));
+        assert_eq!(name("alpha", "fake").as_deref(), Some("fake alpha"));
+        assert_eq!(name("alpha", "nominatim"), None);
+        assert_eq!(
+            name("beta", "nominatim").as_deref(),
+            Some("beta from its ECT page")
+        );
+        assert_eq!(name("gamma", "google").as_deref(), Some("gamma"));
+        assert_eq!(name("gamma", "fake"), None);
+        assert_eq!(name("gamma", "nominatim"), None);
+        assert_eq!(cache.search_term_count(), 3);
+        assert_eq!(cache.placemarks_for("google").len(), 3);
+        assert_eq!(cache.placemarks_for("fake").len(), 2);
+        assert_eq!(cache.into_placemarks().len(), 4);
+    }
 }
@@ -0,0 +1,151 @@
+use std::fs::File;
+use std::path::Path;
+
+use csv::{DeserializeRecordsIntoIter, Reader};
+use serde::Deserialize;
+use url::Url;
+
+use crate::cafe_source::{self, CafeSource, ECTCafeDetails, ECTCafeResult};
+use crate::model::{IOError, PipelineError};
+
+#[derive(Deserialize, Debug)]
+struct CafeListRecord {
+    url: String,
+    name: Option<String>,
+    address: Option<String>,
+}
+
+/// Curated CSV list of cafes with a `url,name,address` header.
+///
+/// Rows without both a name and an address are looked up by their URL, the
+/// same way as ECT pages whose details could not be parsed, and are rejected
+/// when the URL does not name the cafe either.
+pub struct CafeList {
+    description: String,
+    records: DeserializeRecordsIntoIter<File, CafeListRecord>,
+}
+
+impl CafeList {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, IOError> {
+        let description = format!("cafe list {}", path.as_ref().display());
+        let file = File::open(path).map_err(IOError::CafeListOpen)?;
+
+        Ok(CafeList {
+            description,
+            records: Reader::from_reader(file).into_deserialize(),
+        })
+    }
+}
+
+impl Iterator for CafeList {
+    type Item = Result<ECTCafeResult, PipelineError>;
+
+    fn next(&mut self) -> Option<Result<ECTCafeResult, PipelineError>> {
+        let record = self
+            .records
+            .next()?
+            .map_err(|err| PipelineError::CafeListParseError(err.to_string()));
+
+        Some(record.and_then(parse_record))
+    }
+}
+
+impl CafeSource for CafeList {
+    fn describe(&self) -> String {
+        self.description.clone()
+    }
+}
+
+fn parse_record(record: CafeListRecord) -> Result<ECTCafeResult, PipelineError> {
+    let endpoint = Url::parse(record.url.trim())
+        .map_err(|err| PipelineError::CafeListParseError(format!("{}: {}", record.url, err)))?;
+
+    let details = match (record.name, record.address) {
+        (Some(name), Some(address)) if !name.is_empty() && !address.is_empty() => {
+            Some(ECTCafeDetails::new(name, address))
+        }
+        _ => None,
+    };
+
+    if details.is_none() && cafe_source::url_fragment(&endpoint).is_none() {
+        return Err(PipelineError::CafeListParseError(format!(
+            "{}: no name and address, and no cafe name in the URL",
+            record.url
+        )));
+    }
+
+    Ok(ECTCafeResult { endpoint, details })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    /// The results of a cafe list file with `rows` under the header.
+    fn read(name: &str, rows: &str) -> Vec<Result<ECTCafeResult, PipelineError>> {
+        let path = std::env::temp_dir().join(format!(
+            "coffee_map_cafe_list_{}_{}.csv",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, format!("url,name,address\n{}", rows)).unwrap();
+        let results = CafeList::open(&path).unwrap().collect();
+        fs::remove_file(&path).unwrap();
+
+        results
+    }
+
+    #[test]
+    fn reads_the_name_and_address_of_each_cafe() {
+        let results = read(
+            "well_formed",
+            "https://europeancoffeetrip.com/cafe/kaffebar-nord/,Kaffebar Nord,\"Nørrebrogade 12, Copenhagen, Denmark\"\n",
+        );
+
+        let cafe = results.into_iter().next().unwrap().unwrap();
+        let details = cafe.details.unwrap();
+        assert_eq!(details.name, "Kaffebar Nord");
+        assert_eq!(details.address, "Nørrebrogade 12, Copenhagen, Denmark");
+    }
+
+    #[test]
+    fn looks_rows_without_a_name_up_by_their_url() {
+        let results = read(
+            "missing_name",
+            "https://europeancoffeetrip.com/cafe/utopia-geneva/,,Rue 1\nhttps://alpha.coffee/,,\n",
+        );
+
+        assert!(matches!(
+            &results[0],
+            Ok(ECTCafeResult { details: None, .. })
+        ));
+        assert!(matches!(
+            &results[1],
+            Err(PipelineError::CafeListParseError(err)) if err.contains("https://alpha.coffee/")
+        ));
+    }
+
+    #[test]
+    fn reports_bad_rows_and_carries_on() {
+        let results = read(
+            "bad_rows",
+            concat!(
+                "not a url,Cafe,Street 1\n",
+                "https://europeancoffeetrip.com/cafe/short-row/,Short Row\n",
+                "https://europeancoffeetrip.com/cafe/long-row/,Long Row,Street 1,extra\n",
+                "https://europeancoffeetrip.com/cafe/good-row/,Good Row,Street 1\n",
+            ),
+        );
+
+        assert_eq!(results.len(), 4);
+        assert!(results[..3]
+            .iter()
+            .all(|result| matches!(result, Err(PipelineError::CafeListParseError(_)))));
+        assert_eq!(
+            results[3].as_ref().unwrap().details.as_ref().unwrap().name,
+            "Good Row"
+        );
+    }
+}
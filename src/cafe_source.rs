@@ -0,0 +1,352 @@
+use std::collections::{HashMap, HashSet};
+
+use kml::types::{Element, Geometry, Placemark, Point};
+use url::Url;
+
+use crate::ect_page;
+use crate::model::{PipelineError, SearchTerm};
+use crate::write_kml;
+
+/// Placemark child element holding the cafe details from its ECT page.
+pub const EXTENDED_DATA_ELEMENT: &str = "ExtendedData";
+
+/// Bounding box around Europe, from the Canary Islands and the Azores to the
+/// Caucasus and the North Cape, as `(min, max)` degrees.
+const EUROPE_LATITUDES: (f64, f64) = (27.0, 72.0);
+const EUROPE_LONGITUDES: (f64, f64) = (-32.0, 50.0);
+
+/// Location of a cafe as given by the map widget on its ECT page.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ECTCoordinates {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl ECTCoordinates {
+    /// Whether the coordinates can be trusted without asking Google: finite,
+    /// not the `0,0` of an unset map and somewhere in Europe.
+    pub fn is_plausible(&self) -> bool {
+        let in_range = |value: f64, (min, max): (f64, f64)| (min..=max).contains(&value);
+
+        self.latitude.is_finite()
+            && self.longitude.is_finite()
+            && !(self.latitude == 0.0 && self.longitude == 0.0)
+            && in_range(self.latitude, EUROPE_LATITUDES)
+            && in_range(self.longitude, EUROPE_LONGITUDES)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ECTCafeDetails {
+    pub name: String,
+    pub address: String,
+    pub coordinates: Option<ECTCoordinates>,
+    pub opening_hours: Vec<String>,
+    pub website: Option<String>,
+    pub instagram: Option<String>,
+    pub social_links: Vec<String>,
+    pub roasters: Vec<String>,
+    pub brewing_methods: Vec<String>,
+    pub price_range: Option<String>,
+    pub description: Option<String>,
+    pub photo_urls: Vec<String>,
+    /// The page marks the cafe as permanently closed.
+    pub closed: bool,
+}
+
+impl ECTCafeDetails {
+    pub fn new(name: String, address: String) -> Self {
+        ECTCafeDetails {
+            name,
+            address,
+            ..Default::default()
+        }
+    }
+
+    /// Placemark located at the page's own map coordinates, or `None` when the
+    /// page has none or they fail [`ECTCoordinates::is_plausible`].
+    pub fn to_placemark(&self, search_term: &str) -> Option<Placemark> {
+        let coordinates = self.coordinates.filter(ECTCoordinates::is_plausible)?;
+
+        let attrs = HashMap::from([("search_term".to_string(), search_term.to_string())]);
+        let geometry = Geometry::Point(Point::new(
+            coordinates.longitude,
+            coordinates.latitude,
+            Some(0.0),
+        ));
+
+        Some(Placemark {
+            name: Some(self.name.clone()),
+            attrs,
+            children: vec![write_kml::cup_style_url()],
+            description: Some(self.address.clone()),
+            geometry: Some(geometry),
+        })
+    }
+
+    /// KML `<ExtendedData>` with one `<Data>` entry per detail found on the page,
+    /// or `None` when the page had nothing beyond the name and address.
+    pub fn to_extended_data(&self) -> Option<Element> {
+        let fields = [
+            ("opening_hours", self.opening_hours.join("\n")),
+            ("website", self.website.clone().unwrap_or_default()),
+            ("instagram", self.instagram.clone().unwrap_or_default()),
+            ("social_links", self.social_links.join("\n")),
+            ("roasters", self.roasters.join(", ")),
+            ("brewing_methods", self.brewing_methods.join(", ")),
+            ("price_range", self.price_range.clone().unwrap_or_default()),
+            (
+                "ect_description",
+                self.description.clone().unwrap_or_default(),
+            ),
+            ("photos", self.photo_urls.join("\n")),
+        ];
+
+        let data = fields
+            .into_iter()
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(name, value)| data_element(name, value))
+            .collect::<Vec<Element>>();
+
+        if data.is_empty() {
+            return None;
+        }
+
+        Some(extended_data(data))
+    }
+}
+
+pub fn extended_data(data: Vec<Element>) -> Element {
+    Element {
+        name: EXTENDED_DATA_ELEMENT.to_string(),
+        attrs: HashMap::new(),
+        content: None,
+        children: data,
+    }
+}
+
+pub fn data_element(name: &str, value: String) -> Element {
+    Element {
+        name: "Data".to_string(),
+        attrs: HashMap::from([("name".to_string(), name.to_string())]),
+        content: None,
+        children: vec![Element {
+            name: "value".to_string(),
+            attrs: HashMap::new(),
+            content: Some(value),
+            children: vec![],
+        }],
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ECTCafeResult {
+    pub endpoint: Url,
+    pub details: Option<ECTCafeDetails>,
+}
+
+impl ECTCafeResult {
+    /// What to ask the geocoder for: the cafe's name and address when the page
+    /// was read, otherwise the slug of its URL.
+    pub fn into_search_term(self) -> SearchTerm {
+        match self {
+            ECTCafeResult {
+                details: Some(cafe_details),
+                endpoint: _,
+            } => {
+                let search_string = if cafe_details.address.is_empty() {
+                    cafe_details.name.clone()
+                } else {
+                    format!("{} {}", &cafe_details.name, &cafe_details.address)
+                };
+                SearchTerm::CafeDetails(search_string)
+            }
+            ECTCafeResult {
+                details: _,
+                endpoint,
+            } => {
+                let search_string = url_fragment(&endpoint).unwrap_or_else(|| endpoint.to_string());
+                SearchTerm::UrlFragment(search_string)
+            }
+        }
+    }
+}
+
+/// The cafe's name as spelled in its URL, e.g. `utopia geneva` for
+/// `/cafe/utopia-geneva/`, if the URL has one.
+pub fn url_fragment(endpoint: &Url) -> Option<String> {
+    endpoint
+        .path_segments()?
+        .nth(1)
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| segment.replace('-', " "))
+}
+
+/// Anything that yields cafes for the geocoding pipeline: a live katana crawl,
+/// a katana recording, a curated cafe list, and so on.
+pub trait CafeSource: Iterator<Item = Result<ECTCafeResult, PipelineError>> + Send {
+    /// Human readable description of where the cafes come from, recorded in the run manifest.
+    fn describe(&self) -> String;
+}
+
+/// Wraps a [`CafeSource`] so every cafe comes out with its [`ect_page::canonical_url`],
+/// and pages already yielded under another spelling of their URL are reported
+/// as [`PipelineError::DuplicateEndpoint`] instead of being processed again.
+pub struct UniqueEndpoints {
+    source: Box<dyn CafeSource>,
+    seen_endpoints: HashSet<String>,
+}
+
+impl UniqueEndpoints {
+    pub fn new(source: Box<dyn CafeSource>) -> Self {
+        UniqueEndpoints {
+            source,
+            seen_endpoints: HashSet::new(),
+        }
+    }
+}
+
+impl Iterator for UniqueEndpoints {
+    type Item = Result<ECTCafeResult, PipelineError>;
+
+    fn next(&mut self) -> Option<Result<ECTCafeResult, PipelineError>> {
+        let cafe = match self.source.next()? {
+            Ok(cafe) => cafe,
+            Err(err) => return Some(Err(err)),
+        };
+
+        let endpoint = ect_page::canonical_url(&cafe.endpoint);
+        if !self
+            .seen_endpoints
+            .insert(ect_page::endpoint_key(&endpoint))
+        {
+            return Some(Err(PipelineError::DuplicateEndpoint(
+                cafe.endpoint.to_string(),
+            )));
+        }
+
+        Some(Ok(ECTCafeResult { endpoint, ..cafe }))
+    }
+}
+
+impl CafeSource for UniqueEndpoints {
+    fn describe(&self) -> String {
+        self.source.describe()
+    }
+}
+
+/// Wraps a [`CafeSource`] so the pages in `skipped_pages`, keyed by
+/// [`ect_page::endpoint_key`], are passed on without their details. The
+/// pipeline then takes them from the cache by their URL instead of processing
+/// them again.
+pub struct SkipPages {
+    source: Box<dyn CafeSource>,
+    skipped_pages: HashSet<String>,
+}
+
+impl SkipPages {
+    pub fn new(source: Box<dyn CafeSource>, skipped_pages: HashSet<String>) -> Self {
+        SkipPages {
+            source,
+            skipped_pages,
+        }
+    }
+}
+
+impl Iterator for SkipPages {
+    type Item = Result<ECTCafeResult, PipelineError>;
+
+    fn next(&mut self) -> Option<Result<ECTCafeResult, PipelineError>> {
+        let result = self.source.next()?;
+
+        Some(result.map(|cafe| {
+            match self
+                .skipped_pages
+                .contains(&ect_page::endpoint_key(&cafe.endpoint))
+            {
+                true => ECTCafeResult {
+                    details: None,
+                    ..cafe
+                },
+                false => cafe,
+            }
+        }))
+    }
+}
+
+impl CafeSource for SkipPages {
+    fn describe(&self) -> String {
+        self.source.describe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FixedSource(std::vec::IntoIter<&'static str>);
+
+    impl Iterator for FixedSource {
+        type Item = Result<ECTCafeResult, PipelineError>;
+
+        fn next(&mut self) -> Option<Result<ECTCafeResult, PipelineError>> {
+            self.0.next().map(|endpoint| {
+                Ok(ECTCafeResult {
+                    endpoint: Url::parse(endpoint).unwrap(),
+                    details: Some(ECTCafeDetails::new(endpoint.to_string(), String::new())),
+                })
+            })
+        }
+    }
+
+    impl CafeSource for FixedSource {
+        fn describe(&self) -> String {
+            "fixed endpoints".to_string()
+        }
+    }
+
+    #[test]
+    fn canonicalises_endpoints_and_reports_duplicates() {
+        let source = FixedSource(
+            vec![
+                "http://europeancoffeetrip.com/cafe/alpha",
+                "https://europeancoffeetrip.com/cafe/beta/",
+                "https://europeancoffeetrip.com/de/cafe/alpha/?ref=map#top",
+            ]
+            .into_iter(),
+        );
+
+        let results = UniqueEndpoints::new(Box::new(source)).collect::<Vec<_>>();
+
+        assert_eq!(
+            results[0].as_ref().unwrap().endpoint.as_str(),
+            "https://europeancoffeetrip.com/cafe/alpha/"
+        );
+        assert_eq!(
+            results[1].as_ref().unwrap().endpoint.as_str(),
+            "https://europeancoffeetrip.com/cafe/beta/"
+        );
+        assert!(matches!(
+            &results[2],
+            Err(PipelineError::DuplicateEndpoint(url)) if url.contains("/de/cafe/alpha/")
+        ));
+    }
+
+    #[test]
+    fn drops_the_details_of_skipped_pages() {
+        let source = FixedSource(
+            vec![
+                "https://europeancoffeetrip.com/cafe/alpha/",
+                "https://www.europeancoffeetrip.com/cafe/beta",
+            ]
+            .into_iter(),
+        );
+        let skipped_pages = HashSet::from(["europeancoffeetrip.com/cafe/beta".to_string()]);
+
+        let details = SkipPages::new(Box::new(source), skipped_pages)
+            .map(|result| result.unwrap().details.is_some())
+            .collect::<Vec<bool>>();
+
+        assert_eq!(details, vec![true, false]);
+    }
+}
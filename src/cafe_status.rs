@@ -0,0 +1,135 @@
+//! Whether a cafe is still in business, as last seen on its ECT page or on Google.
+//!
+//! The status is kept in placemark attributes, so it is stored in the cache
+//! together with the date it was last seen and where it was seen.
+
+use chrono::{Duration, NaiveDate, Utc};
+use kml::types::Placemark;
+
+use crate::geocoder;
+
+/// Placemark attribute holding Google's `businessStatus` vocabulary, e.g. `OPERATIONAL`.
+pub const STATUS_ATTR: &str = "business_status";
+/// Placemark attribute holding the date, `YYYY-MM-DD`, the status was last seen.
+pub const STATUS_SEEN_ATTR: &str = "business_status_seen_on";
+/// Placemark attribute holding where the status was seen: [`ECT_PAGE_SOURCE`] or a geocoder's name.
+pub const STATUS_SOURCE_ATTR: &str = "business_status_source";
+/// Placemark attribute holding the date, `YYYY-MM-DD`, the geocoder found the placemark.
+pub const GEOCODED_ON_ATTR: &str = "geocoded_on";
+
+pub const ECT_PAGE_SOURCE: &str = "ect_page";
+
+pub const OPERATIONAL: &str = "OPERATIONAL";
+pub const CLOSED_PERMANENTLY: &str = "CLOSED_PERMANENTLY";
+
+pub fn is_closed(placemark: &Placemark) -> bool {
+    placemark
+        .attrs
+        .get(STATUS_ATTR)
+        .is_some_and(|status| status == CLOSED_PERMANENTLY)
+}
+
+/// Records `status` as seen today by `source`.
+pub fn record_status(placemark: &mut Placemark, status: &str, source: &str) {
+    placemark
+        .attrs
+        .insert(STATUS_ATTR.to_string(), status.to_string());
+    placemark.attrs.insert(
+        STATUS_SEEN_ATTR.to_string(),
+        Utc::now().date_naive().to_string(),
+    );
+    placemark
+        .attrs
+        .insert(STATUS_SOURCE_ATTR.to_string(), source.to_string());
+}
+
+/// Records what a freshly processed ECT page says: closed when it has the
+/// closed marker, otherwise operational. A closure reported by the geocoder
+/// stands until the geocoder is asked again, as the page may not have caught up.
+pub fn record_page_status(placemark: &mut Placemark, closed: bool) {
+    let reported_by_geocoder = placemark
+        .attrs
+        .get(STATUS_SOURCE_ATTR)
+        .is_some_and(|source| source != ECT_PAGE_SOURCE);
+    let geocoder_reports_closure = reported_by_geocoder
+        && placemark
+            .attrs
+            .get(STATUS_ATTR)
+            .is_some_and(|status| status != OPERATIONAL);
+
+    if closed {
+        record_status(placemark, CLOSED_PERMANENTLY, ECT_PAGE_SOURCE);
+    } else if !geocoder_reports_closure {
+        record_status(placemark, OPERATIONAL, ECT_PAGE_SOURCE);
+    }
+}
+
+/// Whether the placemark came from a geocoder more than `days` ago, or on an
+/// unknown date as placemarks cached before the date was recorded did, so its
+/// status should be checked with the geocoder again.
+pub fn needs_recheck(placemark: &Placemark, days: u32, today: NaiveDate) -> bool {
+    if geocoder::found_by(placemark).is_none() {
+        return false;
+    }
+
+    placemark
+        .attrs
+        .get(GEOCODED_ON_ATTR)
+        .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok())
+        .is_none_or(|geocoded_on| today - geocoded_on > Duration::days(days.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geocoder::GEOCODER_ATTR;
+
+    #[test]
+    fn the_page_reopens_cafes_it_closed_but_not_those_the_geocoder_closed() {
+        let mut placemark = Placemark::default();
+
+        record_page_status(&mut placemark, true);
+        assert!(is_closed(&placemark));
+
+        record_page_status(&mut placemark, false);
+        assert_eq!(placemark.attrs[STATUS_ATTR], OPERATIONAL);
+        assert_eq!(placemark.attrs[STATUS_SOURCE_ATTR], ECT_PAGE_SOURCE);
+
+        record_status(&mut placemark, CLOSED_PERMANENTLY, "google");
+        record_page_status(&mut placemark, false);
+        assert!(is_closed(&placemark));
+    }
+
+    #[test]
+    fn rechecks_geocoded_placemarks_older_than_the_given_days() {
+        let today = NaiveDate::from_ymd_opt(2026, 10, 17).unwrap();
+        let geocoded_on = |date: Option<&str>| {
+            let mut placemark = Placemark::default();
+            placemark
+                .attrs
+                .insert(GEOCODER_ATTR.to_string(), "google".to_string());
+            if let Some(date) = date {
+                placemark
+                    .attrs
+                    .insert(GEOCODED_ON_ATTR.to_string(), date.to_string());
+            }
+            placemark
+        };
+
+        assert!(!needs_recheck(&geocoded_on(Some("2026-10-01")), 30, today));
+        assert!(needs_recheck(&geocoded_on(Some("2026-09-01")), 30, today));
+        assert!(needs_recheck(&geocoded_on(None), 30, today));
+        assert!(!needs_recheck(&Placemark::default(), 30, today));
+    }
+
+    #[test]
+    fn rechecks_placemarks_cached_before_the_geocoder_was_recorded() {
+        let today = NaiveDate::from_ymd_opt(2026, 10, 17).unwrap();
+        let mut legacy = Placemark::default();
+        legacy
+            .attrs
+            .insert("id".to_string(), "ChIJlegacy".to_string());
+
+        assert!(needs_recheck(&legacy, 30, today));
+    }
+}
@@ -0,0 +1,305 @@
+//! Choosing which of a geocoder's candidates is the cafe from its ECT page.
+//!
+//! Every candidate is scored on how alike its name is to the cafe's, how much
+//! of the cafe's address and postcode it shares, and whether it is a cafe.
+//! The best scoring candidate is taken, and marked for review when even that
+//! one is not much alike.
+
+use std::collections::HashSet;
+
+use kml::types::Placemark;
+
+use crate::cafe_source::ECTCafeDetails;
+use crate::geocoder::GeocodeCandidate;
+use crate::model::PipelineError;
+use crate::words::words;
+
+/// Placemark attribute holding the confidence, between 0 and 1, that the placemark is the cafe.
+pub const MATCH_CONFIDENCE_ATTR: &str = "match_confidence";
+/// Placemark attribute holding which of the geocoder's candidates was chosen, e.g. `2 of 5`.
+pub const MATCH_CANDIDATE_ATTR: &str = "match_candidate";
+/// Placemark attribute set to `true` when the match is below the review threshold.
+pub const NEEDS_REVIEW_ATTR: &str = "needs_review";
+
+const NAME_WEIGHT: f64 = 0.5;
+const ADDRESS_WEIGHT: f64 = 0.2;
+const POSTCODE_WEIGHT: f64 = 0.1;
+const CATEGORY_WEIGHT: f64 = 0.2;
+
+const CAFE_CATEGORIES: [&str; 2] = ["cafe", "coffee_shop"];
+/// Places that are not cafes but often serve coffee.
+const FOOD_CATEGORIES: [&str; 5] = ["bakery", "restaurant", "bar", "food", "meal_takeaway"];
+
+/// What is known about the cafe being looked for.
+pub struct MatchQuery {
+    pub name: String,
+    pub address: String,
+}
+
+/// The chosen candidate and how sure we are it is the cafe.
+#[derive(Debug)]
+pub struct CandidateMatch {
+    pub candidate: GeocodeCandidate,
+    pub confidence: f64,
+    /// Position of the candidate in the geocoder's answer, from 1.
+    pub rank: usize,
+    pub candidate_count: usize,
+}
+
+impl MatchQuery {
+    pub fn from_details(details: &ECTCafeDetails) -> Self {
+        MatchQuery {
+            name: details.name.clone(),
+            address: details.address.clone(),
+        }
+    }
+
+    /// For cafes known only by a search term, e.g. taken from their URL.
+    pub fn from_search_term(search_term: &str) -> Self {
+        MatchQuery {
+            name: search_term.to_string(),
+            address: String::new(),
+        }
+    }
+
+    /// Weighted mean of the scores that can be told for this query, between 0 and 1.
+    pub fn score(&self, candidate: &GeocodeCandidate) -> f64 {
+        let candidate_address = words(&candidate.address).collect::<HashSet<String>>();
+        let (postcodes, address): (HashSet<String>, HashSet<String>) =
+            words(&self.address).partition(|word| is_postcode(word));
+
+        let scores = [
+            Some((NAME_WEIGHT, name_similarity(&self.name, &candidate.name))),
+            shared_fraction(&address, &candidate_address).map(|score| (ADDRESS_WEIGHT, score)),
+            shared_fraction(&postcodes, &candidate_address).map(|score| (POSTCODE_WEIGHT, score)),
+            Some((CATEGORY_WEIGHT, category_score(&candidate.categories))),
+        ];
+
+        let (weighted, weights) = scores
+            .into_iter()
+            .flatten()
+            .fold((0.0, 0.0), |(weighted, weights), (weight, score)| {
+                (weighted + weight * score, weights + weight)
+            });
+
+        weighted / weights
+    }
+}
+
+/// The best scoring candidate, the earliest one of those scoring the same.
+pub fn choose_candidate(
+    candidates: Vec<GeocodeCandidate>,
+    query: &MatchQuery,
+    search_term: &str,
+) -> Result<CandidateMatch, PipelineError> {
+    let candidate_count = candidates.len();
+
+    candidates
+        .into_iter()
+        .enumerate()
+        .map(|(index, candidate)| CandidateMatch {
+            confidence: query.score(&candidate),
+            candidate,
+            rank: index + 1,
+            candidate_count,
+        })
+        .reduce(|best, next| {
+            if next.confidence > best.confidence {
+                next
+            } else {
+                best
+            }
+        })
+        .ok_or(PipelineError::PlaceNotFoundError(search_term.to_string()))
+}
+
+impl CandidateMatch {
+    pub fn needs_review(&self, review_threshold: f64) -> bool {
+        self.confidence < review_threshold
+    }
+
+    pub fn into_placemark(
+        self,
+        geocoder: &str,
+        search_term: String,
+        review_threshold: f64,
+    ) -> Placemark {
+        let needs_review = self.needs_review(review_threshold);
+        let mut placemark = self.candidate.into_placemark(geocoder, search_term);
+
+        placemark.attrs.insert(
+            MATCH_CONFIDENCE_ATTR.to_string(),
+            format!("{:.2}", self.confidence),
+        );
+        placemark.attrs.insert(
+            MATCH_CANDIDATE_ATTR.to_string(),
+            format!("{} of {}", self.rank, self.candidate_count),
+        );
+        if needs_review {
+            placemark
+                .attrs
+                .insert(NEEDS_REVIEW_ATTR.to_string(), "true".to_string());
+        }
+
+        placemark
+    }
+}
+
+pub fn needs_review(placemark: &Placemark) -> bool {
+    placemark
+        .attrs
+        .get(NEEDS_REVIEW_ATTR)
+        .is_some_and(|value| value == "true")
+}
+
+/// Likeness of two names between 0 and 1, by shared words or, for names
+/// spelled a little differently, by edit distance.
+fn name_similarity(left: &str, right: &str) -> f64 {
+    let left_words = words(left).collect::<HashSet<String>>();
+    let right_words = words(right).collect::<HashSet<String>>();
+    if left_words.is_empty() || right_words.is_empty() {
+        return 0.0;
+    }
+
+    let shared = left_words.intersection(&right_words).count();
+    let dice = 2.0 * shared as f64 / (left_words.len() + right_words.len()) as f64;
+
+    let left = words(left).collect::<Vec<String>>().join(" ");
+    let right = words(right).collect::<Vec<String>>().join(" ");
+    let longest = left.chars().count().max(right.chars().count());
+    let edit_similarity = 1.0 - levenshtein(&left, &right) as f64 / longest as f64;
+
+    dice.max(edit_similarity)
+}
+
+/// Fraction of `wanted` found in `found`, or `None` when nothing is wanted.
+fn shared_fraction(wanted: &HashSet<String>, found: &HashSet<String>) -> Option<f64> {
+    if wanted.is_empty() {
+        return None;
+    }
+
+    Some(wanted.intersection(found).count() as f64 / wanted.len() as f64)
+}
+
+fn category_score(categories: &[String]) -> f64 {
+    let has_any = |wanted: &[&str]| {
+        categories
+            .iter()
+            .any(|category| wanted.contains(&category.as_str()))
+    };
+
+    if has_any(&CAFE_CATEGORIES) {
+        1.0
+    } else if has_any(&FOOD_CATEGORIES) {
+        0.5
+    } else {
+        0.0
+    }
+}
+
+/// Postcodes have digits and, unlike most house numbers, at least four characters.
+fn is_postcode(word: &str) -> bool {
+    word.chars().count() >= 4 && word.chars().any(|c| c.is_ascii_digit())
+}
+
+fn levenshtein(left: &str, right: &str) -> usize {
+    let right = right.chars().collect::<Vec<char>>();
+    let mut previous = (0..=right.len()).collect::<Vec<usize>>();
+
+    for (i, left_char) in left.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, right_char) in right.iter().enumerate() {
+            let substitution = previous[j] + usize::from(left_char != *right_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[right.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geocoder::PlaceDetails;
+
+    fn candidate(name: &str, address: &str, categories: &[&str]) -> GeocodeCandidate {
+        GeocodeCandidate {
+            id: Some(name.to_string()),
+            name: name.to_string(),
+            address: address.to_string(),
+            latitude: 0.0,
+            longitude: 0.0,
+            country_code: None,
+            url: None,
+            categories: categories.iter().map(|c| c.to_string()).collect(),
+            business_status: None,
+            details: PlaceDetails::default(),
+        }
+    }
+
+    #[test]
+    fn prefers_the_candidate_most_like_the_cafe_over_the_first_cafe() {
+        let query = MatchQuery {
+            name: "Kaffebar Nord".to_string(),
+            address: "Nørrebrogade 12, 2200 Copenhagen, Denmark".to_string(),
+        };
+        let candidates = vec![
+            candidate(
+                "Nord Coffee",
+                "Vesterbrogade 1, 1620 København, Denmark",
+                &["cafe"],
+            ),
+            candidate(
+                "Kaffebar Nord",
+                "Nørrebrogade 12, 2200 København, Denmark",
+                &["cafe"],
+            ),
+            candidate(
+                "Kaffebar",
+                "Nørrebrogade 80, 2200 København, Denmark",
+                &["bar"],
+            ),
+        ];
+
+        let chosen = choose_candidate(candidates, &query, "term").unwrap();
+
+        assert_eq!(chosen.candidate.name, "Kaffebar Nord");
+        assert_eq!(chosen.rank, 2);
+        assert!(chosen.confidence > 0.8, "{}", chosen.confidence);
+        assert!(!chosen.needs_review(0.6));
+    }
+
+    #[test]
+    fn marks_unlike_matches_for_review() {
+        let query = MatchQuery::from_search_term("Deep");
+        let chosen = choose_candidate(
+            vec![candidate(
+                "Deep Sea Diving School",
+                "Port 1, Split, Croatia",
+                &["school"],
+            )],
+            &query,
+            "Deep",
+        )
+        .unwrap();
+
+        assert!(chosen.needs_review(0.6), "{}", chosen.confidence);
+
+        let placemark = chosen.into_placemark("google", "Deep".to_string(), 0.6);
+
+        assert!(needs_review(&placemark));
+        assert_eq!(placemark.attrs[MATCH_CANDIDATE_ATTR], "1 of 1");
+        assert!(matches!(
+            choose_candidate(vec![], &query, "Deep"),
+            Err(PipelineError::PlaceNotFoundError(_))
+        ));
+    }
+
+    #[test]
+    fn tolerates_small_spelling_differences_in_names() {
+        assert!(name_similarity("Kornél", "Kornel") > 0.8);
+        assert!(name_similarity("Café Korn", "Cafe Korn") > 0.8);
+        assert!(name_similarity("Utopia", "Starbucks") < 0.3);
+    }
+}
@@ -0,0 +1,281 @@
+//! Saving crawl progress while the crawl runs, so an interrupted crawl does not
+//! lose the places it already paid Google for.
+//!
+//! Every `checkpoint_every` newly geocoded placemarks, the placemarks added
+//! since the last save are written to a cache update file of their own, the
+//! record of processed pages is rewritten, and `checkpoint.json` notes which
+//! crawl they belong to. The finished crawl merges the update files into the
+//! cache and removes `checkpoint.json`, so one left behind marks a crawl that
+//! `crawl --resume` can continue.
+
+use std::fs;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::atomic_file;
+use crate::cache::{self, PlacemarkCache};
+use crate::model::{IOError, PlacemarkComputation};
+use crate::seen_endpoints::SeenEndpoints;
+
+const CHECKPOINT_FILENAME: &str = "checkpoint.json";
+
+/// Progress of a crawl that has not finished yet.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CheckpointState {
+    /// When the crawl started; pages processed since were processed by it.
+    pub started_at: DateTime<Utc>,
+    /// The ECT page of the last placemark saved.
+    pub last_endpoint: Option<String>,
+    /// Number of placemarks saved, including those of earlier resumed runs.
+    pub saved_placemarks: usize,
+}
+
+/// The checkpoint left in `cache_folder` by a crawl that did not finish.
+pub fn read(cache_folder: &str) -> Option<CheckpointState> {
+    let contents = fs::read_to_string(Path::new(cache_folder).join(CHECKPOINT_FILENAME)).ok()?;
+
+    serde_json::from_str(contents.as_str()).ok()
+}
+
+/// Saves the placemarks of a running crawl to the cache folder.
+pub struct Checkpoint<'a> {
+    cache_folder: String,
+    every: usize,
+    cache: &'a PlacemarkCache,
+    seen_endpoints: SeenEndpoints,
+    state: CheckpointState,
+    /// Placemarks of this run already recorded in `seen_endpoints`.
+    recorded: usize,
+    new_since_save: usize,
+}
+
+impl<'a> Checkpoint<'a> {
+    /// Continues the crawl described by `resumed`, or a new one started now when `None`.
+    pub fn new(
+        cache_folder: String,
+        every: usize,
+        cache: &'a PlacemarkCache,
+        seen_endpoints: SeenEndpoints,
+        resumed: Option<CheckpointState>,
+    ) -> Self {
+        let state = resumed.unwrap_or_else(|| CheckpointState {
+            started_at: Utc::now(),
+            last_endpoint: None,
+            saved_placemarks: 0,
+        });
+
+        Checkpoint {
+            cache_folder,
+            every,
+            cache,
+            seen_endpoints,
+            recorded: 0,
+            new_since_save: 0,
+            state,
+        }
+    }
+
+    /// Called with every placemark computed so far each time one is added;
+    /// saves them once `every` of them were not already in the cache.
+    pub fn placemark_added(&mut self, placemarks: &[PlacemarkComputation]) -> Result<(), IOError> {
+        if let Some(
+            PlacemarkComputation::FromGeocoder(..) | PlacemarkComputation::FromECTCoordinates(..),
+        ) = placemarks.last()
+        {
+            self.new_since_save += 1;
+        }
+
+        if self.new_since_save < self.every {
+            return Ok(());
+        }
+
+        self.save(placemarks)?;
+        self.write_state()
+    }
+
+    /// Saves the placemarks of the finished crawl into the cache and removes the checkpoint.
+    pub fn finish(mut self, placemarks: &[PlacemarkComputation]) -> Result<(), IOError> {
+        self.record(placemarks);
+        self.seen_endpoints.save(&self.cache_folder)?;
+        cache::update(self.cache_folder.clone(), self.cache, placemarks)?;
+
+        match fs::remove_file(Path::new(&self.cache_folder).join(CHECKPOINT_FILENAME)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                Err(IOError::CheckpointWrite(err))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Saves the placemarks of an interrupted crawl and keeps the checkpoint
+    /// so `crawl --resume` can continue it.
+    pub fn suspend(mut self, placemarks: &[PlacemarkComputation]) -> Result<(), IOError> {
+        self.save(placemarks)?;
+        self.write_state()
+    }
+
+    /// Writes the placemarks added since the last save next to the cache.
+    fn save(&mut self, placemarks: &[PlacemarkComputation]) -> Result<(), IOError> {
+        let new_placemarks = &placemarks[self.recorded..];
+        cache::append(&self.cache_folder, new_placemarks)?;
+
+        self.record(placemarks);
+        self.seen_endpoints.save(&self.cache_folder)
+    }
+
+    /// Records the pages of the placemarks added since the last save as processed.
+    fn record(&mut self, placemarks: &[PlacemarkComputation]) {
+        let processed_at = Utc::now();
+        for computation in &placemarks[self.recorded..] {
+            if let Some(endpoint) = computation.processed_endpoint() {
+                self.seen_endpoints.record(&endpoint, processed_at);
+                self.state.last_endpoint = Some(endpoint.to_string());
+            }
+        }
+        self.state.saved_placemarks += placemarks.len() - self.recorded;
+        self.recorded = placemarks.len();
+        self.new_since_save = 0;
+    }
+
+    fn write_state(&self) -> Result<(), IOError> {
+        let contents = serde_json::to_string_pretty(&self.state)
+            .map_err(|err| IOError::CheckpointWrite(err.into()))?;
+
+        atomic_file::write(
+            &Path::new(&self.cache_folder).join(CHECKPOINT_FILENAME),
+            contents.as_bytes(),
+        )
+        .map_err(IOError::CheckpointWrite)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use kml::types::Placemark;
+    use url::Url;
+
+    use super::*;
+    use crate::model::SearchTerm;
+
+    fn geocoded(slug: &str) -> PlacemarkComputation {
+        let placemark = Placemark {
+            name: Some(slug.to_string()),
+            attrs: HashMap::from([("search_term".to_string(), slug.to_string())]),
+            ..Default::default()
+        };
+        let endpoint =
+            Url::parse(&format!("https://europeancoffeetrip.com/cafe/{}/", slug)).unwrap();
+
+        PlacemarkComputation::FromGeocoder(SearchTerm::CafeDetails(slug.to_string()), placemark)
+            .with_ect_url(&endpoint)
+    }
+
+    fn kml_files(folder: &str) -> Vec<String> {
+        let mut files = fs::read_dir(folder)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| name.ends_with(".kml"))
+            .collect::<Vec<String>>();
+        files.sort();
+
+        files
+    }
+
+    #[test]
+    fn saves_every_n_new_placemarks_and_clears_the_checkpoint_when_finished() {
+        let folder = std::env::temp_dir()
+            .join(format!("coffee_map_checkpoint_{}", std::process::id()))
+            .display()
+            .to_string();
+        let cache = PlacemarkCache::default();
+        let mut checkpoint =
+            Checkpoint::new(folder.clone(), 2, &cache, SeenEndpoints::default(), None);
+
+        let mut placemarks = vec![geocoded("alpha")];
+        checkpoint.placemark_added(&placemarks).unwrap();
+        assert_eq!(read(&folder), None);
+
+        placemarks.push(geocoded("beta"));
+        checkpoint.placemark_added(&placemarks).unwrap();
+        let state = read(&folder).unwrap();
+        assert_eq!(state.saved_placemarks, 2);
+        assert_eq!(
+            state.last_endpoint.as_deref(),
+            Some("https://europeancoffeetrip.com/cafe/beta/")
+        );
+        assert_eq!(cache::load(folder.clone()).unwrap().search_term_count(), 2);
+        let saved_files = kml_files(&folder);
+        assert_eq!(saved_files.len(), 1);
+        assert!(saved_files[0].starts_with("cache_update_"));
+        assert_eq!(
+            SeenEndpoints::load(&folder)
+                .processed_after(Some(state.started_at))
+                .len(),
+            2
+        );
+
+        placemarks.push(geocoded("gamma"));
+        checkpoint.placemark_added(&placemarks).unwrap();
+        checkpoint.finish(&placemarks).unwrap();
+        let cached = cache::load(folder.clone()).unwrap().search_term_count();
+        let finished_files = kml_files(&folder);
+        fs::remove_dir_all(&folder).unwrap();
+
+        assert_eq!(finished_files, vec!["cache.kml".to_string()]);
+
+        assert_eq!(read(&folder), None);
+        assert_eq!(cached, 3);
+    }
+
+    #[test]
+    fn resumes_past_a_truncated_update_file() {
+        let folder = std::env::temp_dir()
+            .join(format!("coffee_map_truncated_{}", std::process::id()))
+            .display()
+            .to_string();
+        let cache = PlacemarkCache::default();
+        let mut checkpoint =
+            Checkpoint::new(folder.clone(), 1, &cache, SeenEndpoints::default(), None);
+        checkpoint.placemark_added(&[geocoded("alpha")]).unwrap();
+
+        // The crawl is killed half way through writing its next update file.
+        let saved = Path::new(&folder).join(&kml_files(&folder)[0]);
+        let contents = fs::read_to_string(saved).unwrap();
+        fs::write(
+            Path::new(&folder).join("cache_update_99991231T235959.000000000.kml"),
+            &contents[..contents.len() / 2],
+        )
+        .unwrap();
+        drop(checkpoint);
+
+        let resumed = read(&folder);
+        assert!(resumed.is_some());
+        let cache = cache::load(folder.clone()).unwrap();
+        assert_eq!(cache.search_term_count(), 1);
+
+        let placemarks = vec![geocoded("beta")];
+        let mut checkpoint = Checkpoint::new(
+            folder.clone(),
+            1,
+            &cache,
+            SeenEndpoints::load(&folder),
+            resumed,
+        );
+        checkpoint.placemark_added(&placemarks).unwrap();
+        checkpoint.finish(&placemarks).unwrap();
+        let cached = cache::load(folder.clone()).unwrap().search_term_count();
+        let finished_files = fs::read_dir(&folder)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| name.contains(".kml"))
+            .collect::<Vec<String>>();
+        fs::remove_dir_all(&folder).unwrap();
+
+        assert_eq!(finished_files, vec!["cache.kml".to_string()]);
+        assert_eq!(cached, 2);
+    }
+}
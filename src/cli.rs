@@ -0,0 +1,216 @@
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::config::PartialConfig;
+use crate::model::{ClosedCafes, CrawlerKind, GeocoderKind};
+
+/// Generate a KML map of the specialty coffee shops listed on europeancoffeetrip.com.
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about,
+    after_help = "Every setting flag can also be given as a COFFEE_MAP_<FLAG> environment variable, e.g. COFFEE_MAP_KML_BATCH_SIZE=500; the flag wins when both are given."
+)]
+pub struct Cli {
+    #[command(flatten)]
+    pub config: ConfigArgs,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Crawl europeancoffeetrip.com, geocode every cafe and write the KML output.
+    Crawl {
+        #[command(flatten)]
+        google: GoogleArgs,
+
+        #[command(flatten)]
+        source: SourceArgs,
+
+        #[command(flatten)]
+        incremental: IncrementalArgs,
+
+        /// Continue the last crawl that did not finish, without processing the pages it already did.
+        #[arg(long)]
+        resume: bool,
+    },
+    /// Look up a single search term with the configured geocoder.
+    Geocode {
+        #[command(flatten)]
+        google: GoogleArgs,
+
+        /// Text to search for, e.g. "<cafe name> <cafe address>".
+        search_term: String,
+    },
+    /// Write the KML output from the cache without crawling.
+    Export,
+    /// List the search terms and placemark names stored in the cache.
+    Cache,
+    /// Summarise the contents of the cache.
+    Stats,
+}
+
+/// Where `crawl` reads cafes from; a live crawl with the configured crawler when no flag is given.
+#[derive(Args, Debug)]
+#[group(multiple = false)]
+pub struct SourceArgs {
+    /// Replay a recorded `katana -jsonl` file, optionally gzipped, instead of crawling.
+    #[arg(long)]
+    pub replay: Option<PathBuf>,
+
+    /// Read cafes from a CSV file with `url,name,address` columns instead of crawling.
+    #[arg(long)]
+    pub cafe_list: Option<PathBuf>,
+}
+
+/// Which ECT pages `crawl` processes again; every page when no flag is given.
+///
+/// Skipped pages keep their cached placemark and are not geocoded again.
+#[derive(Args, Debug)]
+#[group(multiple = false)]
+pub struct IncrementalArgs {
+    /// Only process ECT pages that no earlier crawl has processed; needs a sitemap crawl, a replay or a cafe list.
+    #[arg(long)]
+    pub only_new: bool,
+
+    /// Only process ECT pages that were new or last processed more than N days ago; needs a sitemap crawl, a replay or a cafe list.
+    #[arg(long, value_name = "N")]
+    pub stale_after_days: Option<u32>,
+}
+
+#[derive(Args, Debug)]
+pub struct GoogleArgs {
+    /// Google places API key, needed by the google geocoder unless `google_places_url` points at a mock server.
+    #[arg(long, env = "GOOGLE_PLACES_API_KEY", hide_env_values = true)]
+    pub google_api_key: Option<String>,
+}
+
+/// Settings flags; [`crate::config`] also reads each from a `COFFEE_MAP_<FLAG>` environment variable.
+#[derive(Args, Debug)]
+pub struct ConfigArgs {
+    /// TOML config file; defaults to ./coffee_map.toml when it exists.
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Crawler used to find cafes on europeancoffeetrip.com [default: katana].
+    #[arg(long, global = true)]
+    pub crawler: Option<CrawlerKind>,
+
+    /// Maximum number of placemarks written to each KML file [default: 1000].
+    #[arg(long, global = true)]
+    pub kml_batch_size: Option<usize>,
+
+    /// Link depth the crawler follows from the europeancoffeetrip.com cafe index [default: 14].
+    #[arg(long, global = true)]
+    pub katana_search_depth: Option<u8>,
+
+    /// Maximum number of requests per second the crawler sends [default: 40].
+    #[arg(long, global = true)]
+    pub katana_requests_per_second: Option<u8>,
+
+    /// Folder holding the placemark cache [default: ./cache/].
+    #[arg(long, global = true)]
+    pub cache_folder: Option<String>,
+
+    /// Neither read nor write the placemark cache.
+    #[arg(long, global = true)]
+    pub no_cache: bool,
+
+    /// Folder holding one timestamped directory per run [default: ./output/].
+    #[arg(long, global = true)]
+    pub output_folder: Option<String>,
+
+    /// Filename prefix of the KML output chunks [default: placemarks].
+    #[arg(long, global = true)]
+    pub output_prefix: Option<String>,
+
+    /// Whether closed cafes go in a "Closed" folder of the KML output or are left out [default: folder].
+    #[arg(long, global = true)]
+    pub closed_cafes: Option<ClosedCafes>,
+
+    /// Number of newly geocoded placemarks after which a crawl saves its progress to the cache [default: 100].
+    #[arg(long, global = true)]
+    pub checkpoint_every: Option<usize>,
+
+    /// Maximum number of geocoder requests in flight at once [default: 8].
+    #[arg(long, global = true)]
+    pub geocoder_concurrency: Option<usize>,
+
+    /// Times a google places request is sent again after a 429, 5xx or network error [default: 5].
+    #[arg(long, global = true)]
+    pub google_max_retries: Option<u32>,
+
+    /// Maximum number of google places requests sent per second, retries included [default: 10].
+    #[arg(long, global = true)]
+    pub google_queries_per_second: Option<u32>,
+
+    /// Base URL of the google places API, e.g. of `mock_places` [default: https://places.googleapis.com].
+    #[arg(long, global = true)]
+    pub google_places_url: Option<String>,
+
+    /// Comma separated place fields to ask google for beyond the required ones, e.g. `rating,websiteUri`; all but `businessStatus` and `primaryType` need the pricier enterprise SKU [default: businessStatus].
+    #[arg(long, global = true, value_delimiter = ',')]
+    pub google_place_fields: Option<Vec<String>>,
+
+    /// Backend used to find where cafes are [default: google].
+    #[arg(long, global = true)]
+    pub geocoder: Option<GeocoderKind>,
+
+    /// Endpoint of the nominatim or photon geocoder [default: the public instance].
+    #[arg(long, global = true)]
+    pub geocoder_url: Option<String>,
+
+    /// Maximum number of nominatim or photon requests sent per second [default: 1].
+    #[arg(long, global = true)]
+    pub geocoder_queries_per_second: Option<u32>,
+
+    /// Times a nominatim or photon request is sent again after a 429, 5xx or network error [default: 3].
+    #[arg(long, global = true)]
+    pub geocoder_max_retries: Option<u32>,
+
+    /// CSV file with `name,address,latitude,longitude` columns used by the gazetteer geocoder.
+    #[arg(long, global = true)]
+    pub gazetteer_file: Option<String>,
+
+    /// Geocoded cafes matched with less confidence than this, between 0 and 1, are marked for review [default: 0.6].
+    #[arg(long, global = true)]
+    pub match_review_threshold: Option<f64>,
+}
+
+impl ConfigArgs {
+    pub fn to_partial_config(&self) -> PartialConfig {
+        PartialConfig {
+            crawler: self.crawler,
+            kml_batch_size: self.kml_batch_size,
+            katana_search_depth: self.katana_search_depth,
+            katana_requests_per_second: self.katana_requests_per_second,
+            cache_folder: self.cache_folder.clone(),
+            no_cache: self.no_cache.then_some(true),
+            output_folder: self.output_folder.clone(),
+            output_prefix: self.output_prefix.clone(),
+            closed_cafes: self.closed_cafes,
+            checkpoint_every: self.checkpoint_every,
+            geocoder_concurrency: self.geocoder_concurrency,
+            google_max_retries: self.google_max_retries,
+            google_queries_per_second: self.google_queries_per_second,
+            google_places_url: self.google_places_url.clone(),
+            google_place_fields: self.google_place_fields.as_ref().map(|fields| {
+                fields
+                    .iter()
+                    .filter(|field| !field.is_empty())
+                    .cloned()
+                    .collect()
+            }),
+            geocoder: self.geocoder,
+            geocoder_url: self.geocoder_url.clone(),
+            geocoder_queries_per_second: self.geocoder_queries_per_second,
+            geocoder_max_retries: self.geocoder_max_retries,
+            gazetteer_file: self.gazetteer_file.clone(),
+            match_review_threshold: self.match_review_threshold,
+            selectors: None,
+        }
+    }
+}
@@ -0,0 +1,484 @@
+//! Layered loading of [`CoffeeMapConfig`].
+//!
+//! Values are resolved from, in increasing priority: the built-in defaults, a
+//! TOML config file, `COFFEE_MAP_*` environment variables and command-line
+//! flags. Each environment variable is parsed as if its flag had been given.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Component, Path};
+
+use clap::builder::{FalseyValueParser, TypedValueParser};
+use clap::{Args, FromArgMatches};
+use serde::Deserialize;
+use url::Url;
+
+use crate::cli::ConfigArgs;
+use crate::google_places;
+use crate::model::{ClosedCafes, CoffeeMapConfig, ConfigError, CrawlerKind, GeocoderKind};
+use crate::selector_profile::SelectorProfile;
+
+/// Config file read from the working directory when no `--config` is given.
+pub const DEFAULT_CONFIG_FILE: &str = "coffee_map.toml";
+
+/// One layer of configuration; unset fields fall through to the layer below.
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PartialConfig {
+    pub crawler: Option<CrawlerKind>,
+    pub kml_batch_size: Option<usize>,
+    pub katana_search_depth: Option<u8>,
+    pub katana_requests_per_second: Option<u8>,
+    pub cache_folder: Option<String>,
+    pub no_cache: Option<bool>,
+    pub output_folder: Option<String>,
+    pub output_prefix: Option<String>,
+    pub closed_cafes: Option<ClosedCafes>,
+    pub checkpoint_every: Option<usize>,
+    pub geocoder_concurrency: Option<usize>,
+    pub google_max_retries: Option<u32>,
+    pub google_queries_per_second: Option<u32>,
+    pub google_places_url: Option<String>,
+    pub google_place_fields: Option<Vec<String>>,
+    pub geocoder: Option<GeocoderKind>,
+    pub geocoder_url: Option<String>,
+    pub geocoder_queries_per_second: Option<u32>,
+    pub geocoder_max_retries: Option<u32>,
+    pub gazetteer_file: Option<String>,
+    pub match_review_threshold: Option<f64>,
+    pub selectors: Option<SelectorProfile>,
+}
+
+impl PartialConfig {
+    fn apply(self, config: CoffeeMapConfig) -> CoffeeMapConfig {
+        let cache_folder = match (self.no_cache, self.cache_folder) {
+            (Some(true), _) => None,
+            (_, Some(folder)) => Some(folder),
+            (Some(false), None) => config
+                .cache_folder
+                .or(CoffeeMapConfig::default().cache_folder),
+            (None, None) => config.cache_folder,
+        };
+
+        CoffeeMapConfig {
+            crawler: self.crawler.unwrap_or(config.crawler),
+            kml_batch_size: self.kml_batch_size.unwrap_or(config.kml_batch_size),
+            katana_search_depth: self
+                .katana_search_depth
+                .unwrap_or(config.katana_search_depth),
+            katana_requests_per_second: self
+                .katana_requests_per_second
+                .unwrap_or(config.katana_requests_per_second),
+            cache_folder,
+            output_folder: self.output_folder.unwrap_or(config.output_folder),
+            output_prefix: self.output_prefix.unwrap_or(config.output_prefix),
+            closed_cafes: self.closed_cafes.unwrap_or(config.closed_cafes),
+            checkpoint_every: self.checkpoint_every.unwrap_or(config.checkpoint_every),
+            geocoder_concurrency: self
+                .geocoder_concurrency
+                .unwrap_or(config.geocoder_concurrency),
+            google_max_retries: self.google_max_retries.unwrap_or(config.google_max_retries),
+            google_queries_per_second: self
+                .google_queries_per_second
+                .unwrap_or(config.google_queries_per_second),
+            google_places_url: self.google_places_url.unwrap_or(config.google_places_url),
+            google_place_fields: self
+                .google_place_fields
+                .unwrap_or(config.google_place_fields),
+            geocoder: self.geocoder.unwrap_or(config.geocoder),
+            geocoder_url: self.geocoder_url.or(config.geocoder_url),
+            geocoder_queries_per_second: self
+                .geocoder_queries_per_second
+                .unwrap_or(config.geocoder_queries_per_second),
+            geocoder_max_retries: self
+                .geocoder_max_retries
+                .unwrap_or(config.geocoder_max_retries),
+            gazetteer_file: self.gazetteer_file.or(config.gazetteer_file),
+            match_review_threshold: self
+                .match_review_threshold
+                .unwrap_or(config.match_review_threshold),
+            selectors: self.selectors.unwrap_or(config.selectors),
+        }
+    }
+}
+
+pub fn load(args: &ConfigArgs) -> Result<CoffeeMapConfig, ConfigError> {
+    load_with(args, |name| std::env::var(name).ok())
+}
+
+/// [`load`] with environment variables looked up through `env`.
+pub fn load_with(
+    args: &ConfigArgs,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<CoffeeMapConfig, ConfigError> {
+    let env_args = environment_args(env)?;
+
+    let file_layer = match args.config.as_ref().or(env_args.config.as_ref()) {
+        Some(path) => read_config_file(path)?,
+        None if Path::new(DEFAULT_CONFIG_FILE).exists() => read_config_file(DEFAULT_CONFIG_FILE)?,
+        None => PartialConfig::default(),
+    };
+
+    let config = args.to_partial_config().apply(
+        env_args
+            .to_partial_config()
+            .apply(file_layer.apply(CoffeeMapConfig::default())),
+    );
+
+    validate(config)
+}
+
+/// Parses the `COFFEE_MAP_<FLAG>` environment variables as the flags they stand for.
+fn environment_args(env: impl Fn(&str) -> Option<String>) -> Result<ConfigArgs, ConfigError> {
+    let command = ConfigArgs::augment_args(clap::Command::new("environment")).no_binary_name(true);
+
+    let mut flags = Vec::new();
+    for arg in command.get_arguments() {
+        let Some(long) = arg.get_long() else {
+            continue;
+        };
+        let name = format!("COFFEE_MAP_{}", long.to_uppercase().replace('-', "_"));
+        let Some(value) = env(&name) else {
+            continue;
+        };
+
+        let flag = if arg.get_action().takes_values() {
+            format!("--{}={}", long, value)
+        } else if FalseyValueParser::new()
+            .parse_ref(&command, Some(arg), OsStr::new(&value))
+            .unwrap_or(true)
+        {
+            format!("--{}", long)
+        } else {
+            continue;
+        };
+
+        // Parsing each variable on its own lets an error name the variable.
+        command
+            .clone()
+            .try_get_matches_from([flag.as_str()])
+            .map_err(|err| ConfigError::Environment(name, clap_reason(&err)))?;
+        flags.push(flag);
+    }
+
+    command
+        .try_get_matches_from(flags)
+        .and_then(|matches| ConfigArgs::from_arg_matches(&matches))
+        .map_err(|err| {
+            ConfigError::Environment("COFFEE_MAP_ variables".to_string(), clap_reason(&err))
+        })
+}
+
+/// First line of a clap error without its `error: ` prefix.
+fn clap_reason(err: &clap::Error) -> String {
+    let rendered = err.to_string();
+    let first_line = rendered.lines().next().unwrap_or_default();
+    first_line
+        .strip_prefix("error: ")
+        .unwrap_or(first_line)
+        .to_string()
+}
+
+fn read_config_file<P: AsRef<Path>>(path: P) -> Result<PartialConfig, ConfigError> {
+    let path_string = path.as_ref().display().to_string();
+
+    let contents =
+        fs::read_to_string(&path).map_err(|err| ConfigError::ReadFile(path_string.clone(), err))?;
+
+    toml::from_str(contents.as_str()).map_err(|err| ConfigError::ParseFile(path_string, err))
+}
+
+fn validate(config: CoffeeMapConfig) -> Result<CoffeeMapConfig, ConfigError> {
+    if config.kml_batch_size == 0 {
+        return Err(ConfigError::InvalidValue(
+            "kml_batch_size",
+            "must be at least 1".to_string(),
+        ));
+    }
+
+    // Katana's `-depth` has no upper limit, but a depth of 0 crawls nothing.
+    if config.katana_search_depth == 0 {
+        return Err(ConfigError::InvalidValue(
+            "katana_search_depth",
+            "must be at least 1".to_string(),
+        ));
+    }
+
+    if config.katana_requests_per_second == 0 {
+        return Err(ConfigError::InvalidValue(
+            "katana_requests_per_second",
+            "must be at least 1".to_string(),
+        ));
+    }
+
+    if config.output_folder.is_empty() {
+        return Err(ConfigError::InvalidValue(
+            "output_folder",
+            "must not be empty".to_string(),
+        ));
+    }
+
+    if config.output_prefix.is_empty() || config.output_prefix.contains('/') {
+        return Err(ConfigError::InvalidValue(
+            "output_prefix",
+            "must be a non-empty filename without '/'".to_string(),
+        ));
+    }
+
+    if config.checkpoint_every == 0 {
+        return Err(ConfigError::InvalidValue(
+            "checkpoint_every",
+            "must be at least 1".to_string(),
+        ));
+    }
+
+    if config.geocoder_concurrency == 0 {
+        return Err(ConfigError::InvalidValue(
+            "geocoder_concurrency",
+            "must be at least 1".to_string(),
+        ));
+    }
+
+    if config.google_queries_per_second == 0 {
+        return Err(ConfigError::InvalidValue(
+            "google_queries_per_second",
+            "must be at least 1".to_string(),
+        ));
+    }
+
+    if let Err(err) = Url::parse(&config.google_places_url) {
+        return Err(ConfigError::InvalidValue(
+            "google_places_url",
+            err.to_string(),
+        ));
+    }
+
+    // Placemarks from a stand-in are recorded as google's, so they must not
+    // end up in the cache real google runs use.
+    let default_cache_folder = CoffeeMapConfig::default().cache_folder;
+    if config.geocoder == GeocoderKind::Google
+        && !google_places::is_google_url(&config.google_places_url)
+        && config.cache_folder.as_deref().map(folder_components)
+            == default_cache_folder.as_deref().map(folder_components)
+    {
+        return Err(ConfigError::InvalidValue(
+            "cache_folder",
+            "must be set to a folder of its own, or --no-cache given, when google_places_url is not google's"
+                .to_string(),
+        ));
+    }
+
+    if let Some(field) = config
+        .google_place_fields
+        .iter()
+        .find(|field| !google_places::OPTIONAL_FIELDS.contains(&field.as_str()))
+    {
+        return Err(ConfigError::InvalidValue(
+            "google_place_fields",
+            format!(
+                "unknown field {}, expected any of {}",
+                field,
+                google_places::OPTIONAL_FIELDS.join(", ")
+            ),
+        ));
+    }
+
+    if !config
+        .google_place_fields
+        .iter()
+        .any(|field| field == google_places::BUSINESS_STATUS_FIELD)
+    {
+        return Err(ConfigError::InvalidValue(
+            "google_place_fields",
+            format!(
+                "must include {}, which tells closed cafes apart",
+                google_places::BUSINESS_STATUS_FIELD
+            ),
+        ));
+    }
+
+    if config.geocoder_queries_per_second == 0 {
+        return Err(ConfigError::InvalidValue(
+            "geocoder_queries_per_second",
+            "must be at least 1".to_string(),
+        ));
+    }
+
+    if config.geocoder == GeocoderKind::Gazetteer && config.gazetteer_file.is_none() {
+        return Err(ConfigError::InvalidValue(
+            "gazetteer_file",
+            "must be set to use the gazetteer geocoder".to_string(),
+        ));
+    }
+
+    if !(0.0..=1.0).contains(&config.match_review_threshold) {
+        return Err(ConfigError::InvalidValue(
+            "match_review_threshold",
+            format!("{} is not between 0 and 1", config.match_review_threshold),
+        ));
+    }
+
+    config.selectors.validate()?;
+
+    Ok(config)
+}
+
+/// Components of `folder` without `.`, so that `./cache/` and `cache` compare equal.
+fn folder_components(folder: &str) -> Vec<Component<'_>> {
+    Path::new(folder)
+        .components()
+        .filter(|component| *component != Component::CurDir)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::cli::Cli;
+
+    fn environment(variables: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let variables = variables
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<(String, String)>>();
+
+        move |name| {
+            variables
+                .iter()
+                .find(|(variable, _)| variable == name)
+                .map(|(_, value)| value.clone())
+        }
+    }
+
+    fn parse_args(args: &[&str]) -> ConfigArgs {
+        Cli::try_parse_from(["coffee_map"].iter().chain(args).chain(&["stats"]))
+            .unwrap()
+            .config
+    }
+
+    #[test]
+    fn flags_override_environment_variables_which_override_the_file() {
+        let path =
+            std::env::temp_dir().join(format!("coffee_map_config_{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "kml_batch_size = 5\noutput_prefix = \"file\"\nkatana_requests_per_second = 7\n",
+        )
+        .unwrap();
+
+        let config = load_with(
+            &parse_args(&["--kml-batch-size", "9"]),
+            environment(&[
+                ("COFFEE_MAP_CONFIG", path.to_str().unwrap()),
+                ("COFFEE_MAP_KML_BATCH_SIZE", "6"),
+                ("COFFEE_MAP_OUTPUT_PREFIX", "env"),
+            ]),
+        );
+        fs::remove_file(&path).unwrap();
+
+        let config = config.unwrap();
+        assert_eq!(config.kml_batch_size, 9);
+        assert_eq!(config.output_prefix, "env");
+        assert_eq!(config.katana_requests_per_second, 7);
+        assert_eq!(
+            config.katana_search_depth,
+            CoffeeMapConfig::default().katana_search_depth
+        );
+    }
+
+    #[test]
+    fn reads_environment_flags_and_names_a_bad_variable() {
+        let config = load_with(
+            &parse_args(&[]),
+            environment(&[
+                ("COFFEE_MAP_NO_CACHE", "1"),
+                ("COFFEE_MAP_GOOGLE_PLACE_FIELDS", "businessStatus,rating"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.cache_folder, None);
+        assert_eq!(config.google_place_fields, vec!["businessStatus", "rating"]);
+
+        let config = load_with(
+            &parse_args(&[]),
+            environment(&[("COFFEE_MAP_NO_CACHE", "false")]),
+        )
+        .unwrap();
+        assert_eq!(config.cache_folder, CoffeeMapConfig::default().cache_folder);
+
+        match load_with(
+            &parse_args(&[]),
+            environment(&[("COFFEE_MAP_KML_BATCH_SIZE", "many")]),
+        ) {
+            Err(ConfigError::Environment(name, _)) => {
+                assert_eq!(name, "COFFEE_MAP_KML_BATCH_SIZE")
+            }
+            other => panic!("expected an environment error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn rejects_an_empty_batch_size_and_a_zero_depth() {
+        let invalid_field = |config: CoffeeMapConfig| match validate(config) {
+            Err(ConfigError::InvalidValue(field, _)) => field,
+            other => panic!("expected an invalid value, got {:?}", other.map(|_| ())),
+        };
+
+        assert_eq!(
+            invalid_field(CoffeeMapConfig {
+                kml_batch_size: 0,
+                ..CoffeeMapConfig::default()
+            }),
+            "kml_batch_size"
+        );
+        assert_eq!(
+            invalid_field(CoffeeMapConfig {
+                katana_search_depth: 0,
+                ..CoffeeMapConfig::default()
+            }),
+            "katana_search_depth"
+        );
+        assert!(validate(CoffeeMapConfig {
+            katana_search_depth: 40,
+            ..CoffeeMapConfig::default()
+        })
+        .is_ok());
+    }
+
+    #[test]
+    fn asks_only_for_the_business_status_by_default_and_always_for_it() {
+        assert_eq!(
+            validate(CoffeeMapConfig::default())
+                .unwrap()
+                .google_place_fields,
+            vec!["businessStatus"]
+        );
+        assert!(matches!(
+            validate(CoffeeMapConfig {
+                google_place_fields: vec!["rating".to_string()],
+                ..CoffeeMapConfig::default()
+            }),
+            Err(ConfigError::InvalidValue("google_place_fields", _))
+        ));
+    }
+
+    #[test]
+    fn keeps_mock_google_placemarks_out_of_the_default_cache() {
+        let with_url = |google_places_url: &str, cache_folder: Option<&str>| {
+            validate(CoffeeMapConfig {
+                google_places_url: google_places_url.to_string(),
+                cache_folder: cache_folder.map(str::to_string),
+                ..CoffeeMapConfig::default()
+            })
+            .is_ok()
+        };
+
+        assert!(!with_url("http://127.0.0.1:8765", Some("./cache/")));
+        assert!(!with_url("http://127.0.0.1:8765", Some("cache")));
+        assert!(with_url("http://127.0.0.1:8765", Some("/tmp/mock_cache")));
+        assert!(with_url("http://127.0.0.1:8765", None));
+        assert!(with_url("https://places.googleapis.com/", Some("./cache/")));
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::{HashSet, VecDeque};
+
+use scraper::{Html, Selector};
+use url::Url;
+
+use crate::cafe_source::{CafeSource, ECTCafeResult};
+use crate::ect_page::{self, ECT_CAFE_INDEX_URL};
+use crate::model::{CoffeeMapConfig, IOError, PipelineError};
+use crate::polite_client::PoliteClient;
+use crate::selector_profile::CafePageSelectors;
+use crate::shutdown;
+
+/// Built-in breadth-first crawler for europeancoffeetrip.com, an alternative to katana.
+///
+/// Starting from the cafe index it follows every same-site link whose path
+/// contains `/cafe/`, up to `max_depth` links away, and yields each cafe page
+/// it downloads. Requests are spaced to stay under `requests_per_second` and
+/// the site's robots.txt is obeyed.
+pub struct ECTCrawler {
+    client: PoliteClient,
+    start_url: Url,
+    max_depth: u8,
+    frontier: VecDeque<(Url, u8)>,
+    seen: HashSet<Url>,
+    page_selectors: CafePageSelectors,
+    pending_errors: VecDeque<PipelineError>,
+}
+
+impl ECTCrawler {
+    pub fn new(
+        start_url: Url,
+        max_depth: u8,
+        requests_per_second: u8,
+        page_selectors: CafePageSelectors,
+    ) -> Result<Self, IOError> {
+        let client = PoliteClient::new(&start_url, requests_per_second)?;
+        let start_url = without_fragment(start_url);
+
+        Ok(ECTCrawler {
+            client,
+            max_depth,
+            frontier: VecDeque::from([(start_url.clone(), 0)]),
+            seen: HashSet::from([start_url.clone()]),
+            start_url,
+            page_selectors,
+            pending_errors: VecDeque::new(),
+        })
+    }
+
+    pub fn from_config(config: &CoffeeMapConfig) -> Result<Self, IOError> {
+        let start_url = Url::parse(ECT_CAFE_INDEX_URL).expect("ECT cafe index url is valid");
+
+        Self::new(
+            start_url,
+            config.katana_search_depth,
+            config.katana_requests_per_second,
+            config.selectors.compile(),
+        )
+    }
+
+    fn enqueue_links(&mut self, page_url: &Url, html: &Html, depth: u8) {
+        if depth >= self.max_depth {
+            return;
+        }
+
+        let link_selector = Selector::parse("a[href]").expect("link selector is valid");
+
+        let links = html
+            .select(&link_selector)
+            .filter_map(|link| link.value().attr("href"))
+            .filter_map(|href| page_url.join(href).ok())
+            .map(without_fragment)
+            .filter(|url| self.is_followable(url))
+            .collect::<Vec<Url>>();
+
+        for link in links {
+            if self.seen.insert(link.clone()) {
+                self.frontier.push_back((link, depth + 1));
+            }
+        }
+    }
+
+    fn is_followable(&self, url: &Url) -> bool {
+        url.host_str() == self.start_url.host_str()
+            && url.path().contains("/cafe/")
+            && self.client.is_allowed(url)
+    }
+}
+
+impl Iterator for ECTCrawler {
+    type Item = Result<ECTCafeResult, PipelineError>;
+
+    fn next(&mut self) -> Option<Result<ECTCafeResult, PipelineError>> {
+        if let Some(err) = self.pending_errors.pop_front() {
+            return Some(Err(err));
+        }
+
+        while let Some((url, depth)) = self.frontier.pop_front() {
+            // Index pages yield no cafe, so many can be fetched between two cafes.
+            if shutdown::is_interrupted() {
+                return None;
+            }
+
+            if !self.client.is_allowed(&url) {
+                continue;
+            }
+
+            let html_body = match self.client.fetch_text(&url) {
+                Ok(html_body) => html_body,
+                Err(err) => return Some(Err(err)),
+            };
+
+            let html = Html::parse_document(html_body.as_str());
+            self.enqueue_links(&url, &html, depth);
+
+            if ect_page::is_cafe_page(&url) {
+                let details = ect_page::read_cafe_page(
+                    &url,
+                    html_body.as_str(),
+                    &self.page_selectors,
+                    &mut self.pending_errors,
+                );
+
+                return Some(Ok(ECTCafeResult {
+                    endpoint: url,
+                    details,
+                }));
+            }
+        }
+
+        None
+    }
+}
+
+impl CafeSource for ECTCrawler {
+    fn describe(&self) -> String {
+        format!("native crawl of {}", self.start_url)
+    }
+}
+
+pub fn without_fragment(mut url: Url) -> Url {
+    url.set_fragment(None);
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::TestServer;
+
+    fn cafe_page(name: &str, links: &[&str]) -> String {
+        let anchors = links
+            .iter()
+            .map(|link| format!(r#"<a href="{}">link</a>"#, link))
+            .collect::<String>();
+
+        format!(
+            r#"<html><body><h1 class="cafe-name">{}</h1><div class="cafe-address">Street 1, 1000 City, Country</div>{}</body></html>"#,
+            name, anchors
+        )
+    }
+
+    fn fixture_server() -> TestServer {
+        TestServer::start(vec![
+            (
+                "/robots.txt",
+                "User-agent: *\nDisallow: /cafe/private/\n".to_string(),
+            ),
+            (
+                "/cafe",
+                cafe_page(
+                    "index",
+                    &[
+                        "/cafe/alpha/",
+                        "cafe/beta/",
+                        "/about/",
+                        "/cafe/private/secret/",
+                    ],
+                ),
+            ),
+            (
+                "/cafe/alpha/",
+                cafe_page("Alpha", &["/cafe/beta/#reviews", "/cafe/gamma/"]),
+            ),
+            ("/cafe/beta/", cafe_page("Beta", &["/cafe/alpha/"])),
+            ("/cafe/gamma/", cafe_page("Gamma", &[])),
+            ("/cafe/private/secret/", cafe_page("Secret", &[])),
+        ])
+    }
+
+    fn crawled_names(server: &TestServer, max_depth: u8) -> Vec<String> {
+        ECTCrawler::new(
+            server.url("/cafe"),
+            max_depth,
+            255,
+            CafePageSelectors::default(),
+        )
+        .unwrap()
+        .map(|result| result.unwrap().details.unwrap().name)
+        .collect()
+    }
+
+    #[test]
+    fn crawls_cafe_pages_up_to_max_depth() {
+        let server = fixture_server();
+
+        assert_eq!(crawled_names(&server, 1), vec!["Alpha", "Beta"]);
+        assert_eq!(crawled_names(&server, 2), vec!["Alpha", "Beta", "Gamma"]);
+    }
+
+    #[test]
+    fn fetches_each_page_once_and_obeys_robots_txt() {
+        let server = fixture_server();
+
+        crawled_names(&server, 5);
+
+        assert_eq!(server.hits("/cafe/beta/"), 1);
+        assert_eq!(server.hits("/cafe/private/secret/"), 0);
+        assert_eq!(server.hits("/about/"), 0);
+    }
+
+    #[test]
+    fn reports_unknown_layouts_and_still_yields_the_cafe() {
+        let server = TestServer::start(vec![
+            ("/cafe", cafe_page("index", &["/cafe/alpha/"])),
+            (
+                "/cafe/alpha/",
+                r#"<html><body><h1 class="venue-title">Alpha</h1></body></html>"#.to_string(),
+            ),
+        ]);
+
+        let results = ECTCrawler::new(server.url("/cafe"), 1, 255, CafePageSelectors::default())
+            .unwrap()
+            .collect::<Vec<Result<ECTCafeResult, PipelineError>>>();
+
+        assert!(matches!(
+            results.as_slice(),
+            [Ok(cafe), Err(PipelineError::ECTPageLayoutUnknown(_))]
+                if cafe.endpoint.path() == "/cafe/alpha/" && cafe.details.is_none()
+        ));
+    }
+}
@@ -0,0 +1,446 @@
+use std::collections::VecDeque;
+
+use itertools::Itertools;
+use scraper::{Html, Selector};
+use url::Url;
+
+use crate::cafe_source::{ECTCafeDetails, ECTCoordinates};
+use crate::model::PipelineError;
+use crate::selector_profile::CafePageSelectors;
+
+/// Page listing every cafe on europeancoffeetrip.com, where crawls start.
+pub const ECT_CAFE_INDEX_URL: &str = "https://europeancoffeetrip.com/cafe";
+
+/// Whether `url` is an individual cafe page, i.e. `/cafe/<slug>/`.
+pub fn is_cafe_page(url: &Url) -> bool {
+    let mut segments = match url.path_segments() {
+        Some(segments) => segments,
+        None => return false,
+    };
+
+    segments.any(|segment| segment == "cafe")
+        && segments.next().is_some_and(|slug| !slug.is_empty())
+}
+
+/// The one URL standing for all the ways a page can be linked: https, no
+/// `www.`, no query or fragment, no language prefix such as `/de/` before
+/// `/cafe/`, and a trailing slash.
+pub fn canonical_url(url: &Url) -> Url {
+    let mut canonical = url.clone();
+    canonical.set_query(None);
+    canonical.set_fragment(None);
+
+    if canonical.scheme() == "http" {
+        let _ = canonical.set_scheme("https");
+        let _ = canonical.set_port(None);
+    }
+
+    if let Some(host) = url.host_str().and_then(|host| host.strip_prefix("www.")) {
+        let _ = canonical.set_host(Some(host));
+    }
+
+    let mut segments = url
+        .path_segments()
+        .map(|segments| {
+            segments
+                .filter(|segment| !segment.is_empty())
+                .collect::<Vec<&str>>()
+        })
+        .unwrap_or_default();
+
+    if segments.len() > 1 && is_language_prefix(segments[0]) && segments[1] == "cafe" {
+        segments.remove(0);
+    }
+
+    let path = match segments.is_empty() {
+        true => "/".to_string(),
+        false => format!("/{}/", segments.join("/")),
+    };
+    canonical.set_path(&path);
+
+    canonical
+}
+
+/// Language codes like `de` or `pt-br` that prefix translated pages.
+fn is_language_prefix(segment: &str) -> bool {
+    let is_code = |part: &str| part.len() == 2 && part.chars().all(|c| c.is_ascii_lowercase());
+
+    match segment.split_once('-') {
+        Some((language, region)) => is_code(language) && is_code(region),
+        None => is_code(segment),
+    }
+}
+
+/// Key identifying the same ECT page across crawls, based on its [`canonical_url`].
+pub fn endpoint_key(url: &Url) -> String {
+    let canonical = canonical_url(url);
+
+    format!(
+        "{}{}",
+        canonical.host_str().unwrap_or_default(),
+        canonical.path().trim_end_matches('/')
+    )
+}
+
+/// Details of the cafe page at `endpoint`, or `None` when it matches no known
+/// layout. The layout error is then queued in `errors` to be reported, and the
+/// cafe can still be searched for by its URL.
+pub fn read_cafe_page(
+    endpoint: &Url,
+    html_body: &str,
+    selectors: &CafePageSelectors,
+    errors: &mut VecDeque<PipelineError>,
+) -> Option<ECTCafeDetails> {
+    parse_cafe_page(endpoint, html_body, selectors)
+        .map_err(|err| errors.push_back(err))
+        .ok()
+}
+
+/// Parses the cafe page at `endpoint` with the given selectors. Only the
+/// name is required; every other detail is left empty when the page does not
+/// have it.
+pub fn parse_cafe_page(
+    endpoint: &Url,
+    html_body: &str,
+    selectors: &CafePageSelectors,
+) -> Result<ECTCafeDetails, PipelineError> {
+    let html = Html::parse_document(html_body);
+
+    let name = first_text_node(&html, &selectors.name)
+        .ok_or_else(|| PipelineError::ECTPageLayoutUnknown(endpoint.to_string()))?;
+    let address = parse_address(&html, &selectors.address).unwrap_or_default();
+
+    let (instagram, social_links): (Vec<String>, Vec<String>) =
+        select_attrs(&html, &selectors.social_links, "href")
+            .into_iter()
+            .partition(|link| link.contains("instagram.com"));
+
+    Ok(ECTCafeDetails {
+        name,
+        address,
+        coordinates: parse_coordinates(&html),
+        opening_hours: select_texts(&html, &selectors.opening_hours),
+        website: select_attrs(&html, &selectors.website, "href")
+            .into_iter()
+            .next(),
+        instagram: instagram.into_iter().next(),
+        social_links,
+        roasters: select_texts(&html, &selectors.roasters),
+        brewing_methods: select_texts(&html, &selectors.brewing_methods),
+        price_range: select_texts(&html, &selectors.price_range)
+            .into_iter()
+            .next(),
+        description: select_texts(&html, &selectors.description)
+            .into_iter()
+            .next(),
+        photo_urls: select_attrs(&html, &selectors.photos, "src"),
+        closed: selectors
+            .closed
+            .iter()
+            .any(|selector| html.select(selector).next().is_some()),
+    })
+}
+
+/// Address lines, i.e. the text directly inside the address element, joined
+/// with commas. Text in nested elements such as a "show on map" link is
+/// skipped unless the address has no text of its own.
+fn parse_address(html: &Html, selectors: &[Selector]) -> Option<String> {
+    let own_text = selectors.iter().find_map(|selector| {
+        let lines = html
+            .select(selector)
+            .next()?
+            .children()
+            .filter_map(|child| child.value().as_text())
+            .map(|line| line.trim().trim_end_matches(',').trim_end())
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<&str>>();
+
+        (!lines.is_empty()).then(|| lines.join(", "))
+    });
+
+    own_text.or_else(|| first_text_node(html, selectors).map(|text| text.trim().to_string()))
+}
+
+/// First text node of the first element matched by the first matching selector.
+fn first_text_node(html: &Html, selectors: &[Selector]) -> Option<String> {
+    selectors.iter().find_map(|selector| {
+        html.select(selector)
+            .next()?
+            .text()
+            .next()
+            .map(String::from)
+    })
+}
+
+/// Cafe location from the page's map widget, either from `data-lat`/`data-lng`
+/// style attributes or from the `q`, `ll` or `center` parameter of an embedded
+/// Google map.
+///
+/// Both forms are guesses at ECT's markup that have not been checked against a
+/// live cafe page. When neither matches, the cafe is geocoded as usual.
+fn parse_coordinates(html: &Html) -> Option<ECTCoordinates> {
+    let attribute_pairs = [
+        ("data-lat", "data-lng"),
+        ("data-latitude", "data-longitude"),
+    ];
+
+    let from_attributes = attribute_pairs.iter().find_map(|(lat_attr, lng_attr)| {
+        let selector = Selector::parse(&format!("[{}][{}]", lat_attr, lng_attr))
+            .expect("map selector is valid");
+        let map = html.select(&selector).next()?;
+
+        Some(ECTCoordinates {
+            latitude: map.value().attr(lat_attr)?.trim().parse().ok()?,
+            longitude: map.value().attr(lng_attr)?.trim().parse().ok()?,
+        })
+    });
+
+    from_attributes.or_else(|| {
+        let map_embed = Selector::parse(r#"iframe[src*="google."][src*="maps"]"#)
+            .expect("map selector is valid");
+
+        select_attrs(html, &[map_embed], "src")
+            .iter()
+            .filter_map(|src| Url::parse(src).ok())
+            .find_map(|map_url| {
+                map_url
+                    .query_pairs()
+                    .filter(|(key, _)| matches!(key.as_ref(), "q" | "ll" | "center"))
+                    .find_map(|(_, value)| parse_lat_lng(&value))
+            })
+    })
+}
+
+fn parse_lat_lng(value: &str) -> Option<ECTCoordinates> {
+    let (latitude, longitude) = value.split_once(',')?;
+
+    Some(ECTCoordinates {
+        latitude: latitude.trim().parse().ok()?,
+        longitude: longitude.trim().parse().ok()?,
+    })
+}
+
+/// Whitespace-normalised text of every element matching the first selector
+/// that matches anything, skipping empty ones.
+fn select_texts(html: &Html, selectors: &[Selector]) -> Vec<String> {
+    first_non_empty(selectors, |selector| {
+        html.select(selector)
+            .map(|element| {
+                element
+                    .text()
+                    .flat_map(str::split_whitespace)
+                    .collect::<Vec<&str>>()
+                    .join(" ")
+            })
+            .filter(|text| !text.is_empty())
+            .collect()
+    })
+}
+
+/// Distinct values of `attribute` on the elements matching the first selector
+/// that matches anything, in page order.
+fn select_attrs(html: &Html, selectors: &[Selector], attribute: &str) -> Vec<String> {
+    first_non_empty(selectors, |selector| {
+        html.select(selector)
+            .filter_map(|element| element.value().attr(attribute))
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(String::from)
+            .unique()
+            .collect()
+    })
+}
+
+fn first_non_empty<F>(selectors: &[Selector], select: F) -> Vec<String>
+where
+    F: Fn(&Selector) -> Vec<String>,
+{
+    selectors
+        .iter()
+        .map(select)
+        .find(|values| !values.is_empty())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selector_profile::SelectorProfile;
+
+    const CAFE_PAGE: &str = r#"<html><body>
+        <h1 class="cafe-name">Alpha Coffee</h1>
+        <div class="cafe-address">Street 1, 1000 City, Country<span>Show on map</span></div>
+        <div class="cafe-opening-hours"><ul><li>Mon - Fri  8:00 - 17:00</li><li>Sat 9:00 - 15:00</li></ul></div>
+        <a class="cafe-website" href="https://alpha.coffee/">alpha.coffee</a>
+        <div class="cafe-social">
+            <a href="https://www.instagram.com/alphacoffee/">Instagram</a>
+            <a href="https://www.facebook.com/alphacoffee">Facebook</a>
+        </div>
+        <div class="cafe-roasters"><ul><li>Alpha Roastery</li><li>Guest roasters</li></ul></div>
+        <div class="cafe-brewing-methods"><ul><li>Espresso</li><li>V60</li></ul></div>
+        <div class="cafe-price-range">€€</div>
+        <div class="cafe-description"><p>Specialty coffee
+            by the river.</p></div>
+        <div class="cafe-map" data-lat="46.2032" data-lng="6.1574"></div>
+        <div class="cafe-gallery"><img src="https://img.example/1.jpg"><img src="https://img.example/2.jpg"></div>
+    </body></html>"#;
+
+    fn parse(html_body: &str) -> Result<ECTCafeDetails, PipelineError> {
+        let endpoint = Url::parse("https://europeancoffeetrip.com/cafe/alpha/").unwrap();
+
+        parse_cafe_page(&endpoint, html_body, &CafePageSelectors::default())
+    }
+
+    #[test]
+    fn parses_rich_cafe_details() {
+        let details = parse(CAFE_PAGE).unwrap();
+
+        assert_eq!(details.name, "Alpha Coffee");
+        assert_eq!(details.address, "Street 1, 1000 City, Country");
+        assert_eq!(
+            details.coordinates,
+            Some(ECTCoordinates {
+                latitude: 46.2032,
+                longitude: 6.1574
+            })
+        );
+        assert_eq!(
+            details.opening_hours,
+            vec!["Mon - Fri 8:00 - 17:00", "Sat 9:00 - 15:00"]
+        );
+        assert_eq!(details.website.as_deref(), Some("https://alpha.coffee/"));
+        assert_eq!(
+            details.instagram.as_deref(),
+            Some("https://www.instagram.com/alphacoffee/")
+        );
+        assert_eq!(
+            details.social_links,
+            vec!["https://www.facebook.com/alphacoffee"]
+        );
+        assert_eq!(details.roasters, vec!["Alpha Roastery", "Guest roasters"]);
+        assert_eq!(details.brewing_methods, vec!["Espresso", "V60"]);
+        assert_eq!(details.price_range.as_deref(), Some("€€"));
+        assert_eq!(
+            details.description.as_deref(),
+            Some("Specialty coffee by the river.")
+        );
+        assert_eq!(
+            details.photo_urls,
+            vec!["https://img.example/1.jpg", "https://img.example/2.jpg"]
+        );
+    }
+
+    #[test]
+    fn leaves_missing_details_empty() {
+        let details =
+            parse(r#"<h1 class="cafe-name">Beta</h1><div class="cafe-address">Road 2, City</div>"#)
+                .unwrap();
+
+        assert!(details.opening_hours.is_empty());
+        assert_eq!(details.coordinates, None);
+        assert_eq!(details.website, None);
+        assert!(details.to_extended_data().is_none());
+    }
+
+    #[test]
+    fn reads_coordinates_from_embedded_google_map() {
+        let details = parse(
+            r#"<h1 class="cafe-name">Gamma</h1><div class="cafe-address">Way 3, City</div>
+            <iframe src="https://www.google.com/maps/embed/v1/place?key=k&amp;q=52.52,13.405"></iframe>"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            details.coordinates,
+            Some(ECTCoordinates {
+                latitude: 52.52,
+                longitude: 13.405
+            })
+        );
+    }
+
+    #[test]
+    fn matches_elements_with_extra_classes() {
+        let details = parse(
+            r#"<h1 class="cafe-name title">Delta</h1><div class="cafe-address wide">Lane 4, City</div>"#,
+        )
+        .unwrap();
+
+        assert_eq!(details.name, "Delta");
+        assert_eq!(details.address, "Lane 4, City");
+    }
+
+    #[test]
+    fn falls_back_to_later_selectors() {
+        let profile = SelectorProfile {
+            name: vec!["h1.cafe-title".to_string(), "h2.name".to_string()],
+            ..Default::default()
+        };
+        let endpoint = Url::parse("https://europeancoffeetrip.com/cafe/epsilon/").unwrap();
+
+        let details = parse_cafe_page(
+            &endpoint,
+            r#"<h2 class="name">Epsilon</h2><div class="cafe-address">Square 5, City</div>"#,
+            &profile.compile(),
+        )
+        .unwrap();
+
+        assert_eq!(details.name, "Epsilon");
+    }
+
+    #[test]
+    fn reports_pages_matching_no_known_layout() {
+        let result = parse(r#"<h1 class="venue-title">Zeta</h1><p>Street 6, City</p>"#);
+
+        assert!(matches!(
+            result,
+            Err(PipelineError::ECTPageLayoutUnknown(url)) if url.ends_with("/cafe/alpha/")
+        ));
+    }
+
+    #[test]
+    fn canonicalises_variants_of_the_same_page() {
+        let canonical = Url::parse("https://europeancoffeetrip.com/cafe/utopia-geneva/").unwrap();
+
+        for variant in [
+            "https://europeancoffeetrip.com/cafe/utopia-geneva",
+            "http://europeancoffeetrip.com/cafe/utopia-geneva/",
+            "https://www.europeancoffeetrip.com/cafe/utopia-geneva/",
+            "https://europeancoffeetrip.com/cafe/utopia-geneva/?utm_source=feed#reviews",
+            "https://europeancoffeetrip.com/de/cafe/utopia-geneva/",
+            "https://europeancoffeetrip.com/pt-br/cafe/utopia-geneva",
+        ] {
+            let variant = Url::parse(variant).unwrap();
+
+            assert_eq!(canonical_url(&variant), canonical, "{}", variant);
+            assert_eq!(endpoint_key(&variant), endpoint_key(&canonical));
+        }
+    }
+
+    #[test]
+    fn keeps_paths_that_are_not_language_prefixes() {
+        let url = Url::parse("https://europeancoffeetrip.com/guide/cafe/alpha").unwrap();
+
+        assert_eq!(
+            canonical_url(&url).as_str(),
+            "https://europeancoffeetrip.com/guide/cafe/alpha/"
+        );
+    }
+
+    #[test]
+    fn rejects_implausible_coordinates() {
+        let plausible = |latitude, longitude| {
+            ECTCoordinates {
+                latitude,
+                longitude,
+            }
+            .is_plausible()
+        };
+
+        assert!(plausible(46.2032, 6.1574));
+        assert!(plausible(28.1, -15.4));
+        assert!(!plausible(0.0, 0.0));
+        assert!(!plausible(f64::NAN, 6.1574));
+        assert!(!plausible(40.7128, -74.006));
+    }
+}
@@ -0,0 +1,105 @@
+//! Geocoder that makes places up, for running the pipeline without a network.
+
+use futures::future::{self, BoxFuture, FutureExt};
+
+use crate::geocoder::{GeocodeCandidate, GeocodeQuery, Geocoder, PlaceDetails};
+use crate::model::PipelineError;
+use crate::region::Region;
+
+/// Answers every search term with one cafe, always the same one for the same
+/// search term: near the query's city, otherwise in its country, otherwise
+/// somewhere in Europe.
+pub struct FakeGeocoder;
+
+impl Geocoder for FakeGeocoder {
+    fn name(&self) -> &'static str {
+        "fake"
+    }
+
+    fn geocode<'a>(
+        &'a self,
+        query: &'a GeocodeQuery,
+    ) -> BoxFuture<'a, Result<Vec<GeocodeCandidate>, PipelineError>> {
+        let hash = fnv1a(&query.search_term);
+        let (latitude, longitude) = match &query.region {
+            Some(region) => place_in(region, hash),
+            None => (
+                (3_600 + (hash % 3_400) as i64) as f64 / 100.0,
+                (-1_000 + ((hash >> 16) % 4_000) as i64) as f64 / 100.0,
+            ),
+        };
+
+        let candidate = GeocodeCandidate {
+            id: Some(format!("fake:{:016x}", hash)),
+            name: query.search_term.clone(),
+            address: String::new(),
+            latitude,
+            longitude,
+            country_code: query.region.map(|region| region.country.code.to_string()),
+            url: None,
+            categories: vec!["cafe".to_string()],
+            business_status: None,
+            details: PlaceDetails::default(),
+        };
+
+        future::ready(Ok(vec![candidate])).boxed()
+    }
+}
+
+/// Within a few kilometres of the region's city, or otherwise within its
+/// country's bounding box.
+fn place_in(region: &Region, hash: u64) -> (f64, f64) {
+    let fraction = |bits: u64| ((hash >> bits) % 1_000) as f64 / 1_000.0;
+
+    match region.city {
+        Some(city) => (
+            city.latitude + (fraction(0) - 0.5) / 20.0,
+            city.longitude + (fraction(16) - 0.5) / 20.0,
+        ),
+        None => {
+            let bounds = region.country.bounds;
+
+            (
+                bounds.south + fraction(0) * (bounds.north - bounds.south),
+                bounds.west + fraction(16) * (bounds.east - bounds.west),
+            )
+        }
+    }
+}
+
+/// FNV-1a, which unlike the standard library's hasher is the same in every build.
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn answers_the_same_search_term_with_the_same_place_in_its_region() {
+        let query = |search_term: &str, address: &str| {
+            GeocodeQuery::new(search_term.to_string(), Region::from_address(address))
+        };
+        let first = FakeGeocoder.geocode(&query("Test Cafe", "")).await.unwrap();
+        let again = FakeGeocoder.geocode(&query("Test Cafe", "")).await.unwrap();
+        let other = FakeGeocoder
+            .geocode(&query("Other Cafe", ""))
+            .await
+            .unwrap();
+        let in_portugal = FakeGeocoder
+            .geocode(&query("Test Cafe", "Rua 1, Braga, Portugal"))
+            .await
+            .unwrap();
+
+        assert_eq!(first, again);
+        assert_ne!(first[0].id, other[0].id);
+        assert!((36.0..70.0).contains(&first[0].latitude));
+        assert!((-10.0..30.0).contains(&first[0].longitude));
+        assert!(Region::from_address("Portugal")
+            .unwrap()
+            .contains(in_portugal[0].latitude, in_portugal[0].longitude));
+    }
+}
@@ -0,0 +1,126 @@
+//! Offline geocoding from a CSV list of known places.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::path::Path;
+
+use csv::Reader;
+use futures::future::{self, BoxFuture, FutureExt};
+use serde::Deserialize;
+
+use crate::geocoder::{GeocodeCandidate, GeocodeQuery, Geocoder, PlaceDetails};
+use crate::model::{IOError, PipelineError};
+use crate::region;
+use crate::words::words;
+
+#[derive(Deserialize, Debug)]
+struct GazetteerRecord {
+    name: String,
+    address: String,
+    latitude: f64,
+    longitude: f64,
+    id: Option<String>,
+    category: Option<String>,
+}
+
+/// Places read from a CSV file with a `name,address,latitude,longitude` header
+/// and optional `id` and `category` columns.
+///
+/// A place matches a search term containing every word of its name; places
+/// sharing more words with the search term's address come first.
+pub struct Gazetteer {
+    places: Vec<GazetteerRecord>,
+}
+
+impl Gazetteer {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, IOError> {
+        let file = File::open(&path).map_err(|err| {
+            IOError::GazetteerRead(format!("{}: {}", path.as_ref().display(), err))
+        })?;
+
+        let places = Reader::from_reader(file)
+            .into_deserialize()
+            .collect::<Result<Vec<GazetteerRecord>, csv::Error>>()
+            .map_err(|err| IOError::GazetteerRead(err.to_string()))?;
+
+        Ok(Gazetteer { places })
+    }
+
+    fn search(&self, search_term: &str) -> Vec<GeocodeCandidate> {
+        let search_words = words(search_term).collect::<HashSet<String>>();
+
+        let mut matches = self
+            .places
+            .iter()
+            .filter(|place| words(&place.name).all(|word| search_words.contains(&word)))
+            .map(|place| {
+                let shared_address_words = words(&place.address)
+                    .collect::<HashSet<String>>()
+                    .intersection(&search_words)
+                    .count();
+
+                (shared_address_words, place)
+            })
+            .collect::<Vec<_>>();
+        matches.sort_by(|(left, _), (right, _)| right.cmp(left));
+
+        matches
+            .into_iter()
+            .map(|(_, place)| GeocodeCandidate {
+                id: place.id.as_ref().map(|id| format!("gazetteer:{}", id)),
+                name: place.name.clone(),
+                address: place.address.clone(),
+                latitude: place.latitude,
+                longitude: place.longitude,
+                country_code: region::country_code_in(&place.address),
+                url: None,
+                categories: place.category.iter().cloned().collect(),
+                business_status: None,
+                details: PlaceDetails::default(),
+            })
+            .collect()
+    }
+}
+
+impl Geocoder for Gazetteer {
+    fn name(&self) -> &'static str {
+        "gazetteer"
+    }
+
+    fn geocode<'a>(
+        &'a self,
+        query: &'a GeocodeQuery,
+    ) -> BoxFuture<'a, Result<Vec<GeocodeCandidate>, PipelineError>> {
+        future::ready(Ok(self.search(&query.search_term))).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn matches_every_word_of_the_name_and_ranks_by_address() {
+        let path =
+            std::env::temp_dir().join(format!("coffee_map_gazetteer_{}.csv", std::process::id()));
+        fs::write(
+            &path,
+            "name,address,latitude,longitude,id,category\n\
+             Korn,\"Hauptstr. 1, Wien, Austria\",48.2,16.37,k1,cafe\n\
+             Korn,\"Main St 5, Dublin, Ireland\",53.35,-6.26,k2,cafe\n\
+             Korn Bakery,\"Hauptstr. 2, Wien, Austria\",48.21,16.38,,bakery\n",
+        )
+        .unwrap();
+        let gazetteer = Gazetteer::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let candidates = gazetteer.search("Korn Main St 5, Dublin, Ireland");
+
+        assert_eq!(candidates.len(), 2);
+        assert_eq!(candidates[0].id.as_deref(), Some("gazetteer:k2"));
+        assert_eq!(candidates[1].id.as_deref(), Some("gazetteer:k1"));
+        assert!(gazetteer.search("Deep Dublin").is_empty());
+    }
+}
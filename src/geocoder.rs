@@ -0,0 +1,236 @@
+//! Finding where a cafe is from its search term.
+//!
+//! Every [`Geocoder`] backend answers with [`GeocodeCandidate`]s, so the rest
+//! of the pipeline does not depend on which one the `geocoder` config key picks.
+
+use std::collections::HashMap;
+
+use chrono::Utc;
+use futures::future::BoxFuture;
+use kml::types::{Element, Geometry, Placemark, Point};
+
+use crate::cafe_source;
+use crate::cafe_status;
+use crate::fake_geocoder::FakeGeocoder;
+use crate::gazetteer::Gazetteer;
+use crate::google_places::{self, PlacesClient};
+use crate::model::{CoffeeMapConfig, GeocoderKind, IOError, PipelineError};
+use crate::osm_geocoder::{OsmFlavour, OsmGeocoder};
+use crate::region::Region;
+use crate::write_kml;
+
+/// Placemark attribute naming the geocoder that found the placemark.
+pub const GEOCODER_ATTR: &str = "geocoder";
+/// Geocoder of the placemarks cached before [`GEOCODER_ATTR`] was recorded,
+/// which all came from google places.
+pub const LEGACY_GEOCODER: &str = "google";
+
+/// Name of the geocoder that found the placemark, or `None` for one placed at
+/// its ECT page's coordinates. A placemark with an `id` but no
+/// [`GEOCODER_ATTR`] was cached before the attribute existed and is google's.
+pub fn found_by(placemark: &Placemark) -> Option<&str> {
+    match placemark.attrs.get(GEOCODER_ATTR) {
+        Some(geocoder) => Some(geocoder),
+        None if placemark.attrs.contains_key("id") => Some(LEGACY_GEOCODER),
+        None => None,
+    }
+}
+
+/// Start of the names of the `ExtendedData` entries holding [`PlaceDetails`].
+pub const GOOGLE_DATA_PREFIX: &str = "google_";
+
+/// A place a geocoder found for a search term.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeocodeCandidate {
+    /// Identifies the place, unique across geocoders; placemarks with the same id are deduplicated.
+    pub id: Option<String>,
+    pub name: String,
+    pub address: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    /// ISO 3166-1 alpha-2 code of the country the place is in, when the geocoder tells.
+    pub country_code: Option<String>,
+    /// Link to the place on the geocoder's own map.
+    pub url: Option<String>,
+    /// Kinds of place, e.g. `cafe`.
+    pub categories: Vec<String>,
+    /// In google's `businessStatus` vocabulary, e.g. `OPERATIONAL`.
+    pub business_status: Option<String>,
+    pub details: PlaceDetails,
+}
+
+/// What a geocoder knows about a place beyond where it is; only google
+/// places fills these in, as far as `google_place_fields` asks for them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PlaceDetails {
+    pub rating: Option<f64>,
+    pub rating_count: Option<u32>,
+    pub website: Option<String>,
+    /// One line per weekday, e.g. `Monday: 8:00 AM – 5:00 PM`.
+    pub opening_hours: Vec<String>,
+    /// e.g. `moderate`.
+    pub price_level: Option<String>,
+    pub phone: Option<String>,
+    /// The kind of place above all others, e.g. `coffee_shop`.
+    pub primary_type: Option<String>,
+}
+
+/// What a geocoder is asked to find.
+#[derive(Debug, Clone)]
+pub struct GeocodeQuery {
+    pub search_term: String,
+    /// Where the cafe is, when its address says; geocoders that can prefer places there.
+    pub region: Option<Region>,
+}
+
+impl GeocodeQuery {
+    pub fn new(search_term: String, region: Option<Region>) -> Self {
+        GeocodeQuery {
+            search_term,
+            region,
+        }
+    }
+}
+
+pub trait Geocoder: Send + Sync {
+    /// Name recorded in the [`GEOCODER_ATTR`] of the placemarks it finds.
+    fn name(&self) -> &'static str;
+
+    /// Places matching the query's search term, best match first as far as the backend can tell.
+    fn geocode<'a>(
+        &'a self,
+        query: &'a GeocodeQuery,
+    ) -> BoxFuture<'a, Result<Vec<GeocodeCandidate>, PipelineError>>;
+
+    /// Number of requests sent again after failing.
+    fn retries(&self) -> usize {
+        0
+    }
+}
+
+/// The geocoder the config asks for.
+pub fn from_config(
+    config: &CoffeeMapConfig,
+    google_api_key: Option<String>,
+) -> Result<Box<dyn Geocoder>, IOError> {
+    match config.geocoder {
+        GeocoderKind::Google => {
+            // A mock server, unlike google, does not need a key.
+            let api_key = match google_api_key {
+                Some(api_key) => api_key,
+                None if !google_places::is_google_url(&config.google_places_url) => String::new(),
+                None => return Err(IOError::GoogleApiKeyMissing),
+            };
+
+            Ok(Box::new(PlacesClient::new(config, api_key)?))
+        }
+        GeocoderKind::Nominatim => Ok(Box::new(OsmGeocoder::new(config, OsmFlavour::Nominatim)?)),
+        GeocoderKind::Photon => Ok(Box::new(OsmGeocoder::new(config, OsmFlavour::Photon)?)),
+        GeocoderKind::Gazetteer => {
+            let path = config
+                .gazetteer_file
+                .as_ref()
+                .ok_or_else(|| IOError::GazetteerRead("no gazetteer_file set".to_string()))?;
+
+            Ok(Box::new(Gazetteer::open(path)?))
+        }
+        GeocoderKind::Fake => Ok(Box::new(FakeGeocoder)),
+    }
+}
+
+impl GeocodeCandidate {
+    pub fn into_placemark(self, geocoder: &str, search_term: String) -> Placemark {
+        let mut attrs = HashMap::<String, String>::new();
+        attrs.insert(String::from("search_term"), search_term);
+        attrs.insert(GEOCODER_ATTR.to_string(), geocoder.to_string());
+        attrs.insert(
+            cafe_status::GEOCODED_ON_ATTR.to_string(),
+            Utc::now().date_naive().to_string(),
+        );
+        if let Some(id) = self.id {
+            attrs.insert("id".to_string(), id);
+        }
+
+        let geometry = Geometry::Point(Point::new(self.longitude, self.latitude, Some(0.0)));
+
+        let description = match &self.url {
+            Some(url) => format!(
+                r#"{}
+
+            {}"#,
+                url, self.address
+            ),
+            None => self.address.clone(),
+        };
+        let description = match self.details.summary() {
+            Some(summary) => format!("{}\n\n{}", description, summary),
+            None => description,
+        };
+
+        let mut children = vec![write_kml::cup_style_url()];
+        children.extend(self.details.to_extended_data());
+
+        let mut placemark = Placemark {
+            name: Some(self.name),
+            attrs,
+            children,
+            description: Some(description),
+            geometry: Some(geometry),
+        };
+
+        if let Some(business_status) = &self.business_status {
+            cafe_status::record_status(&mut placemark, business_status, geocoder);
+        }
+
+        placemark
+    }
+}
+
+impl PlaceDetails {
+    /// Rating and phone number, the details worth seeing at a glance.
+    fn summary(&self) -> Option<String> {
+        let rating = self.rating.map(|rating| match self.rating_count {
+            Some(count) => format!("Rated {:.1} by {} people", rating, count),
+            None => format!("Rated {:.1}", rating),
+        });
+
+        let lines = rating
+            .into_iter()
+            .chain(self.phone.clone())
+            .collect::<Vec<String>>();
+
+        (!lines.is_empty()).then(|| lines.join("\n"))
+    }
+
+    /// KML `<ExtendedData>` with a `<Data>` entry named [`GOOGLE_DATA_PREFIX`]
+    /// and the detail per detail known, or `None` when none is.
+    fn to_extended_data(&self) -> Option<Element> {
+        let fields = [
+            ("rating", self.rating.map(|rating| rating.to_string())),
+            (
+                "rating_count",
+                self.rating_count.map(|count| count.to_string()),
+            ),
+            ("website", self.website.clone()),
+            (
+                "opening_hours",
+                (!self.opening_hours.is_empty()).then(|| self.opening_hours.join("\n")),
+            ),
+            ("price_level", self.price_level.clone()),
+            ("phone", self.phone.clone()),
+            ("primary_type", self.primary_type.clone()),
+        ];
+
+        let data = fields
+            .into_iter()
+            .filter_map(|(name, value)| {
+                Some(cafe_source::data_element(
+                    &format!("{}{}", GOOGLE_DATA_PREFIX, name),
+                    value?,
+                ))
+            })
+            .collect::<Vec<Element>>();
+
+        (!data.is_empty()).then(|| cafe_source::extended_data(data))
+    }
+}
@@ -1,12 +1,43 @@
-use std::collections::HashMap;
-
-use kml::types::{Element, Geometry, Placemark, Point};
-use reqwest::blocking;
+use futures::future::{BoxFuture, FutureExt};
 use serde::Deserialize;
 use serde_json::{json, Value};
+use url::Url;
+
+use crate::backoff::RetryPolicy;
+use crate::geocoder::{GeocodeCandidate, GeocodeQuery, Geocoder, PlaceDetails};
+use crate::model::{CoffeeMapConfig, IOError, PipelineError};
+use crate::region::{self, CITY_RADIUS_METERS};
+use crate::retrying_client::RetryingClient;
 
-use crate::model::PipelineError;
-use crate::write_kml::CUP_STYLE_ID;
+/// Base URL of the real places API, the default `google_places_url`.
+pub const GOOGLE_PLACES_URL: &str = "https://places.googleapis.com";
+/// Host of every google API; requests to it always need an API key.
+const GOOGLE_APIS_HOST: &str = "googleapis.com";
+const SEARCH_TEXT_PATH: &str = "/v1/places:searchText";
+/// Fields every response needs, all in the text search pro SKU.
+const REQUIRED_FIELDS: [&str; 7] = [
+    "displayName",
+    "id",
+    "formattedAddress",
+    "addressComponents",
+    "location",
+    "googleMapsUri",
+    "types",
+];
+/// Field telling closed cafes apart, which `google_place_fields` must keep.
+pub const BUSINESS_STATUS_FIELD: &str = "businessStatus";
+/// Fields `google_place_fields` can add; all but `businessStatus` and
+/// `primaryType` move requests up to the pricier enterprise SKU.
+pub const OPTIONAL_FIELDS: [&str; 8] = [
+    "businessStatus",
+    "primaryType",
+    "rating",
+    "userRatingCount",
+    "websiteUri",
+    "regularOpeningHours",
+    "priceLevel",
+    "nationalPhoneNumber",
+];
 
 #[derive(Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -18,7 +49,22 @@ pub struct Location {
 #[serde(rename_all = "camelCase")]
 pub struct DisplayName {
     pub text: String,
-    pub language_code: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AddressComponent {
+    /// e.g. `DE` for the country.
+    pub short_text: Option<String>,
+    #[serde(default)]
+    pub types: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OpeningHours {
+    #[serde(default)]
+    pub weekday_descriptions: Vec<String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -27,115 +73,422 @@ pub struct GooglePlace {
     pub id: String,
     pub display_name: DisplayName,
     pub formatted_address: String,
+    #[serde(default)]
+    pub address_components: Vec<AddressComponent>,
     pub google_maps_uri: String,
     pub location: Location,
     pub types: Vec<String>,
+    pub business_status: Option<String>,
+    pub primary_type: Option<String>,
+    pub rating: Option<f64>,
+    pub user_rating_count: Option<u32>,
+    pub website_uri: Option<String>,
+    pub regular_opening_hours: Option<OpeningHours>,
+    /// e.g. `PRICE_LEVEL_MODERATE`.
+    pub price_level: Option<String>,
+    pub national_phone_number: Option<String>,
 }
 
-pub struct GooglePlaceResult {
-    pub place: GooglePlace,
-    pub searchterm: String,
-}
+impl From<GooglePlace> for GeocodeCandidate {
+    fn from(place: GooglePlace) -> Self {
+        let country_code = place
+            .address_components
+            .into_iter()
+            .find(|component| component.types.iter().any(|kind| kind == "country"))
+            .and_then(|component| component.short_text)
+            .or_else(|| region::country_code_in(&place.formatted_address));
 
-impl GooglePlaceResult {
-    pub fn to_placemark(self) -> Placemark {
-        let searchterm = self.searchterm;
-        let place = self.place;
-
-        let mut attrs = HashMap::<String, String>::new();
-        attrs.insert(String::from("search_term"), searchterm);
-        attrs.insert("id".to_string(), place.id);
-
-        let geometry = Geometry::Point(Point::new(
-            place.location.longitude,
-            place.location.latitude,
-            Some(0.0),
-        ));
-
-        Placemark {
-            name: Some(place.display_name.text),
-            attrs,
-            children: vec![Element {
-                name: "styleUrl".to_string(),
-                attrs: HashMap::<String, String>::new(),
-                content: Some(format!("#{}", CUP_STYLE_ID)),
-                children: vec![],
-            }],
-            description: Some(format!(
-                r#"{}
-            
-            {}"#,
-                place.google_maps_uri, place.formatted_address
-            )),
-            geometry: Some(geometry),
+        GeocodeCandidate {
+            id: Some(place.id),
+            name: place.display_name.text,
+            country_code,
+            address: place.formatted_address,
+            latitude: place.location.latitude,
+            longitude: place.location.longitude,
+            url: Some(place.google_maps_uri),
+            categories: place.types,
+            business_status: place.business_status,
+            details: PlaceDetails {
+                rating: place.rating,
+                rating_count: place.user_rating_count,
+                website: place.website_uri,
+                opening_hours: place
+                    .regular_opening_hours
+                    .map(|hours| hours.weekday_descriptions)
+                    .unwrap_or_default(),
+                price_level: place.price_level.map(|level| {
+                    level
+                        .trim_start_matches("PRICE_LEVEL_")
+                        .to_lowercase()
+                        .replace('_', " ")
+                }),
+                phone: place.national_phone_number,
+                primary_type: place.primary_type,
+            },
         }
     }
 }
 
-pub fn query(
-    client: &blocking::Client,
-    searchterm: String,
+/// Geocoder backed by the google places text search.
+pub struct PlacesClient {
+    client: RetryingClient,
     api_key: String,
-) -> Result<GooglePlaceResult, PipelineError> {
-    let endpoint = "https://places.googleapis.com/v1/places:searchText";
-    let request_body = json!({
-        "textQuery": searchterm.clone()
-    });
+    search_text_url: String,
+    field_mask: String,
+}
 
-    let req = client
-        .post(endpoint)
-        .header("Content-Type", "application/json")
-        .header("X-Goog-Api-Key", api_key.as_str())
-        .header(
-            "X-Goog-FieldMask",
-            "places.displayName,places.id,places.formattedAddress,places.location,places.googleMapsUri,places.types",
+impl PlacesClient {
+    pub fn new(config: &CoffeeMapConfig, api_key: String) -> Result<Self, IOError> {
+        Self::with_url(
+            format!(
+                "{}{}",
+                config.google_places_url.trim_end_matches('/'),
+                SEARCH_TEXT_PATH
+            ),
+            api_key,
+            RetryPolicy::new(config.google_max_retries),
+            config.google_queries_per_second,
+            &config.google_place_fields,
         )
-        .body(request_body.to_string());
+    }
 
-    let response = req
-        .send()
-        .map_err(|err| PipelineError::GoogleHTTPError(format!("{:#?}", err)))?;
+    fn with_url(
+        search_text_url: String,
+        api_key: String,
+        retry_policy: RetryPolicy,
+        queries_per_second: u32,
+        place_fields: &[String],
+    ) -> Result<Self, IOError> {
+        Ok(PlacesClient {
+            client: RetryingClient::new(retry_policy, queries_per_second)?,
+            api_key,
+            search_text_url,
+            field_mask: field_mask(place_fields),
+        })
+    }
 
-    if response.status() != reqwest::StatusCode::OK {
-        let error_str = format!(
-            "search_term: {}, response code: {:#?}",
-            &searchterm,
-            response.status()
-        );
-        return Err(PipelineError::GoogleHTTPError(error_str));
+    pub async fn search_text(
+        &self,
+        query: &GeocodeQuery,
+    ) -> Result<Vec<GooglePlace>, PipelineError> {
+        let searchterm = query.search_term.as_str();
+        let request_body = search_text_body(query);
+
+        let body = self
+            .client
+            .send(|client| {
+                let request = client
+                    .post(self.search_text_url.as_str())
+                    .header("Content-Type", "application/json")
+                    .header("X-Goog-FieldMask", self.field_mask.as_str())
+                    .body(request_body.to_string());
+
+                if self.api_key.is_empty() {
+                    request
+                } else {
+                    request.header("X-Goog-Api-Key", self.api_key.as_str())
+                }
+            })
+            .await
+            .map_err(|err| {
+                PipelineError::GoogleHTTPError(format!("search_term: {}, {}", searchterm, err))
+            })?;
+
+        parse_search_response(body.as_str())
     }
+}
 
-    let body = response
-        .text()
-        .map_err(|err| PipelineError::GoogleHTTPError(format!("{:#?}", err)))?;
+impl Geocoder for PlacesClient {
+    fn name(&self) -> &'static str {
+        "google"
+    }
 
-    let response: Value = serde_json::from_str(body.as_str())
+    fn geocode<'a>(
+        &'a self,
+        query: &'a GeocodeQuery,
+    ) -> BoxFuture<'a, Result<Vec<GeocodeCandidate>, PipelineError>> {
+        async move {
+            let places = self.search_text(query).await?;
+
+            Ok(places.into_iter().map(GeocodeCandidate::from).collect())
+        }
+        .boxed()
+    }
+
+    fn retries(&self) -> usize {
+        self.client.retries()
+    }
+}
+
+/// Whether `url` points at google rather than at a stand-in such as
+/// `mock_places`. URLs that do not parse are taken to be google's.
+pub fn is_google_url(url: &str) -> bool {
+    match Url::parse(url).ok().as_ref().and_then(Url::host_str) {
+        Some(host) => host == GOOGLE_APIS_HOST || host.ends_with(&format!(".{}", GOOGLE_APIS_HOST)),
+        None => true,
+    }
+}
+
+/// The required fields and `place_fields`, each as a field of `places`.
+fn field_mask(place_fields: &[String]) -> String {
+    REQUIRED_FIELDS
+        .into_iter()
+        .chain(place_fields.iter().map(String::as_str))
+        .map(|field| format!("places.{}", field))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// Asks for places in the query's region first: within a circle around its
+/// city, or otherwise within its country's bounding box.
+fn search_text_body(query: &GeocodeQuery) -> Value {
+    let mut body = json!({
+        "textQuery": query.search_term
+    });
+
+    if let Some(region) = &query.region {
+        body["regionCode"] = json!(region.country.code);
+        body["locationBias"] = match region.city {
+            Some(city) => json!({
+                "circle": {
+                    "center": {"latitude": city.latitude, "longitude": city.longitude},
+                    "radius": CITY_RADIUS_METERS
+                }
+            }),
+            None => {
+                let bounds = region.country.bounds;
+
+                json!({
+                    "rectangle": {
+                        "low": {"latitude": bounds.south, "longitude": bounds.west},
+                        "high": {"latitude": bounds.north, "longitude": bounds.east}
+                    }
+                })
+            }
+        };
+    }
+
+    body
+}
+
+/// The places in a `searchText` response; google leaves `places` out when nothing matched.
+fn parse_search_response(body: &str) -> Result<Vec<GooglePlace>, PipelineError> {
+    let response: Value = serde_json::from_str(body)
         .map_err(|err| PipelineError::GoogleHTTPError(format!("{:#?}", err)))?;
 
-    let places_json = response
-        .get("places")
-        .ok_or(PipelineError::GooglePlaceNotFoundError(searchterm.clone()))?;
+    let Some(places_json) = response.get("places") else {
+        return Ok(vec![]);
+    };
+
+    serde_json::from_value::<Vec<GooglePlace>>(places_json.clone())
+        .map_err(|err| PipelineError::GoogleJsonParseError(format!("{:#?}", err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::cafe_source::{ECTCafeDetails, EXTENDED_DATA_ELEMENT};
+    use crate::model::{PlacemarkComputation, SearchTerm};
+    use crate::region::Region;
+    use crate::test_server::{TestResponse, TestServer};
 
-    let mut places = serde_json::from_value::<Vec<GooglePlace>>(places_json.clone())
-        .map_err(|err| PipelineError::GoogleJsonParseError(format!("{:#?}", err)))?
-        .into_iter();
+    const PLACES_BODY: &str = r#"{"places": [{"id": "abc", "displayName": {"text": "Test Cafe"}, "formattedAddress": "Street 1, Berlin", "googleMapsUri": "https://maps.google.com/?cid=1", "location": {"latitude": 52.5, "longitude": 13.4}, "types": ["cafe"]}]}"#;
 
-    let cafe_keyword = "cafe".to_string();
-    let coffee_shop_keyword = "coffee_shop".to_string();
+    /// Answers every request after the first `failures` with a place.
+    fn flaky_server(failures: usize, failure: fn() -> TestResponse) -> TestServer {
+        let requests = AtomicUsize::new(0);
 
-    let place = if let Some(coffee_shop) = places
-        .clone()
-        .filter(|place| {
-            place.types.contains(&cafe_keyword) | place.types.contains(&coffee_shop_keyword)
+        TestServer::with_handler(move |_| {
+            if requests.fetch_add(1, Ordering::SeqCst) < failures {
+                failure()
+            } else {
+                TestResponse::ok(PLACES_BODY.to_string())
+            }
         })
-        .next()
-    {
-        coffee_shop
-    } else {
-        places
-            .next()
-            .ok_or(PipelineError::GooglePlaceNotFoundError(searchterm.clone()))?
-    };
+    }
+
+    fn client(server: &TestServer, max_retries: u32) -> PlacesClient {
+        let retry_policy = RetryPolicy {
+            max_retries,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+        };
+
+        PlacesClient::with_url(
+            server.url("/v1/places:searchText").to_string(),
+            "key".to_string(),
+            retry_policy,
+            1000,
+            &[],
+        )
+        .unwrap()
+    }
+
+    fn test_query() -> GeocodeQuery {
+        GeocodeQuery::new("Test Cafe".to_string(), None)
+    }
+
+    fn too_many_requests() -> TestResponse {
+        TestResponse {
+            headers: vec![("Retry-After".to_string(), "0".to_string())],
+            ..TestResponse::status(429)
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_rate_limited_and_failing_requests() {
+        let server = flaky_server(2, too_many_requests);
+        let places_client = client(&server, 3);
+
+        let candidates = places_client.geocode(&test_query()).await.unwrap();
+
+        assert_eq!(candidates[0].id.as_deref(), Some("abc"));
+        assert_eq!(places_client.retries(), 2);
+        assert_eq!(server.hits("/v1/places:searchText"), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_retries() {
+        let server = flaky_server(10, || TestResponse::status(503));
+        let places_client = client(&server, 2);
+
+        let result = places_client.geocode(&test_query()).await;
+
+        assert!(matches!(result, Err(PipelineError::GoogleHTTPError(_))));
+        assert_eq!(server.hits("/v1/places:searchText"), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_when_asked_to_wait_longer_than_the_max_delay() {
+        let server = flaky_server(1, || TestResponse {
+            headers: vec![("Retry-After".to_string(), "3600".to_string())],
+            ..TestResponse::status(429)
+        });
+        let places_client = client(&server, 3);
+
+        let result = places_client.geocode(&test_query()).await;
+
+        assert!(
+            matches!(result, Err(PipelineError::GoogleHTTPError(err)) if err.contains("3600s"))
+        );
+        assert_eq!(server.hits("/v1/places:searchText"), 1);
+    }
 
-    Ok(GooglePlaceResult { place, searchterm })
+    #[tokio::test]
+    async fn does_not_retry_client_errors() {
+        let server = flaky_server(1, || TestResponse::status(403));
+        let places_client = client(&server, 3);
+
+        let result = places_client.geocode(&test_query()).await;
+
+        assert!(matches!(result, Err(PipelineError::GoogleHTTPError(_))));
+        assert_eq!(places_client.retries(), 0);
+    }
+
+    #[test]
+    fn biases_the_search_to_the_city_or_country_of_the_address() {
+        let in_city = GeocodeQuery::new(
+            "Kaffebar Nord".to_string(),
+            Region::from_address("Nørrebrogade 12, 2200 Copenhagen, Denmark"),
+        );
+        let in_country = GeocodeQuery::new(
+            "Deep".to_string(),
+            Region::from_address("Main St 1, Kilkenny, Ireland"),
+        );
+
+        let city_body = search_text_body(&in_city);
+        let country_body = search_text_body(&in_country);
+
+        assert_eq!(city_body["regionCode"], "DK");
+        assert_eq!(
+            city_body["locationBias"]["circle"]["center"]["latitude"],
+            55.6761
+        );
+        assert_eq!(country_body["regionCode"], "IE");
+        assert_eq!(
+            country_body["locationBias"]["rectangle"]["high"]["longitude"],
+            -5.99
+        );
+        assert_eq!(
+            search_text_body(&test_query()),
+            json!({"textQuery": "Test Cafe"})
+        );
+    }
+
+    #[test]
+    fn reads_the_country_from_the_address_components_or_the_address() {
+        let body = r#"{"places": [
+            {"id": "a", "displayName": {"text": "Cafe"}, "formattedAddress": "Street 1, 5020 Salzburg, Austria", "addressComponents": [{"shortText": "Salzburg", "types": ["locality"]}, {"shortText": "AT", "types": ["country", "political"]}], "googleMapsUri": "https://maps.google.com/?cid=1", "location": {"latitude": 47.8, "longitude": 13.0}, "types": ["cafe"]},
+            {"id": "b", "displayName": {"text": "Cafe"}, "formattedAddress": "Street 1, 83395 Freilassing, Germany", "googleMapsUri": "https://maps.google.com/?cid=2", "location": {"latitude": 47.8, "longitude": 12.9}, "types": ["cafe"]},
+            {"id": "c", "displayName": {"text": "Cafe"}, "formattedAddress": "Street 1, Atlantis", "googleMapsUri": "https://maps.google.com/?cid=3", "location": {"latitude": 47.8, "longitude": 12.9}, "types": ["cafe"]}
+        ]}"#;
+        let country_codes = parse_search_response(body)
+            .unwrap()
+            .into_iter()
+            .map(|place| GeocodeCandidate::from(place).country_code)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            country_codes,
+            vec![Some("AT".to_string()), Some("DE".to_string()), None]
+        );
+    }
+
+    #[test]
+    fn keeps_extended_place_fields_next_to_the_ect_details() {
+        let body = r#"{"places": [{"id": "abc", "displayName": {"text": "Test Cafe"}, "formattedAddress": "Street 1, Berlin", "googleMapsUri": "https://maps.google.com/?cid=1", "location": {"latitude": 52.5, "longitude": 13.4}, "types": ["cafe"], "rating": 4.6, "userRatingCount": 321, "priceLevel": "PRICE_LEVEL_MODERATE", "nationalPhoneNumber": "030 123456", "regularOpeningHours": {"weekdayDescriptions": ["Monday: 8:00 AM – 5:00 PM"]}}]}"#;
+        let candidate = GeocodeCandidate::from(parse_search_response(body).unwrap().remove(0));
+        let ect_details = ECTCafeDetails {
+            website: Some("https://testcafe.example".to_string()),
+            ..ECTCafeDetails::new("Test Cafe".to_string(), "Street 1, Berlin".to_string())
+        };
+
+        let computation = PlacemarkComputation::FromGeocoder(
+            SearchTerm::CafeDetails("Test Cafe Street 1, Berlin".to_string()),
+            candidate.into_placemark("google", "Test Cafe Street 1, Berlin".to_string()),
+        )
+        .with_ect_details(&ect_details);
+        let placemark = computation.get_placemark();
+        let data = placemark
+            .children
+            .iter()
+            .filter(|child| child.name == EXTENDED_DATA_ELEMENT)
+            .flat_map(|child| child.children.iter())
+            .map(|data| {
+                (
+                    data.attrs["name"].as_str(),
+                    data.children[0].content.clone().unwrap_or_default(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert!(data.contains(&("website", "https://testcafe.example".to_string())));
+        assert!(data.contains(&("google_rating", "4.6".to_string())));
+        assert!(data.contains(&("google_price_level", "moderate".to_string())));
+        assert!(data.contains(&(
+            "google_opening_hours",
+            "Monday: 8:00 AM – 5:00 PM".to_string()
+        )));
+        assert!(placemark
+            .description
+            .as_ref()
+            .unwrap()
+            .ends_with("Rated 4.6 by 321 people\n030 123456"));
+        assert_eq!(
+            field_mask(&["rating".to_string()]),
+            "places.displayName,places.id,places.formattedAddress,places.addressComponents,places.location,places.googleMapsUri,places.types,places.rating"
+        );
+    }
+
+    #[test]
+    fn tells_google_from_a_stand_in() {
+        assert!(is_google_url(GOOGLE_PLACES_URL));
+        assert!(is_google_url("https://places.googleapis.com/"));
+        assert!(is_google_url("HTTPS://Places.GoogleAPIs.com:443"));
+        assert!(!is_google_url("http://127.0.0.1:8765"));
+        assert!(!is_google_url("https://notgoogleapis.com"));
+    }
 }
@@ -1,39 +1,57 @@
 use std::{
+    collections::VecDeque,
+    fs::File,
     io::Lines,
-    io::{BufRead, BufReader},
-    process::{ChildStdout, Command, Stdio},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    process::{Child, ChildStdout, Command, Stdio},
+    sync::mpsc::{self, Receiver},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
 };
 
+use crate::cafe_source::{CafeSource, ECTCafeDetails, ECTCafeResult};
+use crate::ect_page::{self, ECT_CAFE_INDEX_URL};
 use crate::model::CoffeeMapConfig;
-use crate::model::PipelineError;
-use scraper::{Html, Selector};
+use crate::model::{IOError, PipelineError};
+use crate::selector_profile::CafePageSelectors;
+use crate::shutdown;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use serde_json::Value;
 use url::Url;
 
-#[derive(Debug, Clone)]
-pub struct ECTCafeDetails {
-    pub name: String,
-    pub address: String,
-}
+/// Filename of the gzip-compressed copy of katana's output kept in each run directory.
+pub const KATANA_ARCHIVE_FILENAME: &str = "katana.jsonl.gz";
 
-#[derive(Debug, Clone)]
-pub struct ECTCafeResult {
-    pub endpoint: Url,
-    pub details: Option<ECTCafeDetails>,
+pub struct KatanaStream {
+    description: String,
+    reader_lines: Lines<Box<dyn BufRead + Send>>,
+    archive: Option<GzEncoder<BufWriter<File>>>,
+    katana: Option<KatanaProcess>,
+    pending_errors: VecDeque<PipelineError>,
+    page_selectors: CafePageSelectors,
 }
 
-pub struct KatanaStream {
-    reader_lines: Lines<BufReader<ChildStdout>>,
+/// A running katana child process whose stderr is read on a background thread.
+///
+/// The process is killed when this is dropped before katana has exited.
+struct KatanaProcess {
+    child: Arc<Mutex<Child>>,
+    stderr_lines: Receiver<String>,
+    stderr_reader: Option<JoinHandle<()>>,
 }
 
 impl KatanaStream {
-    pub fn new(config: &CoffeeMapConfig) -> Self {
+    /// Spawns a live katana crawl, copying every raw output line into `archive_path` if given.
+    pub fn new(config: &CoffeeMapConfig, archive_path: Option<&Path>) -> Result<Self, IOError> {
         let search_depth = config.katana_search_depth.clone().to_string();
         let max_requests_per_second = config.katana_requests_per_second.clone().to_string();
 
         let katana_args = vec![
             "-u",
-            "https://europeancoffeetrip.com/cafe",
+            ECT_CAFE_INDEX_URL,
             "-mr",
             ".*/cafe/.*",
             "-d",
@@ -44,49 +62,237 @@ impl KatanaStream {
             "-jsonl", //"-c 20",
                       //"-p 20",
         ];
-        let stdout = spawn_katana_process(katana_args).unwrap();
-        let reader = BufReader::new(stdout);
+        let (katana, stdout) = KatanaProcess::spawn("katana", katana_args)?;
+        let reader: Box<dyn BufRead + Send> = Box::new(BufReader::new(stdout));
 
-        Self {
+        let archive = match archive_path {
+            Some(path) => {
+                let file = File::create(path).map_err(IOError::KatanaArchiveCreate)?;
+                Some(GzEncoder::new(BufWriter::new(file), Compression::default()))
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            description: format!("katana crawl of {}", ECT_CAFE_INDEX_URL),
             reader_lines: reader.lines(),
+            archive,
+            katana: Some(katana),
+            pending_errors: VecDeque::new(),
+            page_selectors: config.selectors.compile(),
+        })
+    }
+
+    /// Replays the output of an earlier `katana -jsonl` crawl instead of crawling live.
+    /// Recordings ending in `.gz`, such as a run's katana archive, are decompressed on the fly.
+    pub fn from_recording<P: AsRef<Path>>(
+        config: &CoffeeMapConfig,
+        path: P,
+    ) -> Result<Self, IOError> {
+        let is_gzipped = path
+            .as_ref()
+            .extension()
+            .is_some_and(|extension| extension == "gz");
+        let description = format!("katana recording {}", path.as_ref().display());
+
+        let file = File::open(path).map_err(IOError::ReplayFileOpen)?;
+        let reader: Box<dyn BufRead + Send> = if is_gzipped {
+            Box::new(BufReader::new(GzDecoder::new(file)))
+        } else {
+            Box::new(BufReader::new(file))
+        };
+
+        Ok(Self {
+            description,
+            reader_lines: reader.lines(),
+            archive: None,
+            katana: None,
+            pending_errors: VecDeque::new(),
+            page_selectors: config.selectors.compile(),
+        })
+    }
+
+    fn archive_line(&mut self, line: &str) {
+        let written = self
+            .archive
+            .as_mut()
+            .map(|archive| writeln!(archive, "{}", line));
+
+        // A broken archive must not interrupt the crawl, so stop archiving instead.
+        if let Some(Err(_)) = written {
+            self.archive = None;
+        }
+    }
+
+    fn finish_archive(&mut self) {
+        if let Some(archive) = self.archive.take() {
+            let _ = archive.finish().and_then(|mut writer| writer.flush());
+        }
+    }
+
+    /// Collects katana's remaining stderr output and a failing exit status once stdout is closed.
+    fn finish_katana(&mut self) {
+        if let Some(katana) = self.katana.take() {
+            self.pending_errors.extend(katana.finish());
         }
     }
 }
 
-fn spawn_katana_process(args: Vec<&str>) -> Option<ChildStdout> {
-    Command::new("katana")
-        .args(args)
-        .stdout(Stdio::piped())
-        .spawn()
-        .map_err(|err| {
-            println!("{:#?}", err);
-            err
-        })
-        .ok()?
-        .stdout
+impl KatanaProcess {
+    fn spawn(program: &str, args: Vec<&str>) -> Result<(Self, ChildStdout), IOError> {
+        let mut child = Command::new(program)
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::NotFound => IOError::KatanaMissing,
+                _ => IOError::KatanaSpawn(err),
+            })?;
+
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or(IOError::KatanaSpawn(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "katana stdout is not piped",
+            )))?;
+        let stderr = child.stderr.take();
+
+        let (sender, stderr_lines) = mpsc::channel();
+        let stderr_reader = stderr.map(|stderr| {
+            thread::spawn(move || {
+                for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                    if !line.trim().is_empty() && sender.send(line).is_err() {
+                        break;
+                    }
+                }
+            })
+        });
+
+        let child = Arc::new(Mutex::new(child));
+        shutdown::register_child(&child);
+
+        Ok((
+            KatanaProcess {
+                child,
+                stderr_lines,
+                stderr_reader,
+            },
+            stdout,
+        ))
+    }
+
+    fn next_stderr_line(&self) -> Option<String> {
+        self.stderr_lines.try_recv().ok()
+    }
+
+    fn finish(mut self) -> Vec<PipelineError> {
+        let status = self.child.lock().map(|mut child| child.wait());
+
+        if let Some(stderr_reader) = self.stderr_reader.take() {
+            let _ = stderr_reader.join();
+        }
+
+        let mut errors = self
+            .stderr_lines
+            .try_iter()
+            .map(PipelineError::KatanaStderr)
+            .collect::<Vec<PipelineError>>();
+
+        match status {
+            Ok(Ok(status)) if !status.success() => {
+                errors.push(PipelineError::KatanaExitStatus(status))
+            }
+            Ok(Err(err)) => errors.push(PipelineError::KatanaIOError(err)),
+            _ => {}
+        }
+
+        errors
+    }
+}
+
+impl Drop for KatanaProcess {
+    fn drop(&mut self) {
+        if let Ok(mut child) = self.child.lock() {
+            if let Ok(None) = child.try_wait() {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+    }
+}
+
+/// Version reported by the katana binary on PATH, if it can be run.
+pub fn katana_version() -> Option<String> {
+    let output = Command::new("katana").arg("-version").output().ok()?;
+
+    let combined_output = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    combined_output
+        .lines()
+        .find(|line| line.to_lowercase().contains("version"))
+        .and_then(|line| line.split_whitespace().last())
+        .map(String::from)
 }
 
 impl Iterator for KatanaStream {
     type Item = Result<ECTCafeResult, PipelineError>;
 
     fn next(&mut self) -> Option<Result<ECTCafeResult, PipelineError>> {
-        let ect_cafe_details = self
-            .reader_lines
-            .next()?
-            .map_err(PipelineError::KatanaIOError)
-            .and_then(parse_katana_output);
+        if let Some(err) = self.pending_errors.pop_front() {
+            return Some(Err(err));
+        }
+
+        if let Some(stderr_line) = self
+            .katana
+            .as_ref()
+            .and_then(KatanaProcess::next_stderr_line)
+        {
+            return Some(Err(PipelineError::KatanaStderr(stderr_line)));
+        }
+
+        let Some(line) = self.reader_lines.next() else {
+            self.finish_archive();
+            self.finish_katana();
+            return self.pending_errors.pop_front().map(Err);
+        };
+
+        if let Ok(line) = &line {
+            self.archive_line(line);
+        }
+
+        let ect_cafe_details = line.map_err(PipelineError::KatanaIOError).and_then(|line| {
+            parse_katana_output(line, &self.page_selectors, &mut self.pending_errors)
+        });
 
         Some(ect_cafe_details)
     }
 }
 
-fn parse_katana_output(json_string: String) -> Result<ECTCafeResult, PipelineError> {
-    let katana_json: Value = serde_json::from_str(json_string.as_str())
-        .map_err(|err| PipelineError::KatanaJsonParseError(err))?;
+impl CafeSource for KatanaStream {
+    fn describe(&self) -> String {
+        self.description.clone()
+    }
+}
+
+/// Parses one line of katana output, queueing the layout error of a cafe page
+/// that matches no known layout in `errors`.
+fn parse_katana_output(
+    json_string: String,
+    page_selectors: &CafePageSelectors,
+    errors: &mut VecDeque<PipelineError>,
+) -> Result<ECTCafeResult, PipelineError> {
+    let katana_json: Value =
+        serde_json::from_str(json_string.as_str()).map_err(PipelineError::KatanaJsonParseError)?;
 
     let endpoint = parse_katana_endpoint(&katana_json)
         .ok_or(PipelineError::KatanaEndpointParseError(katana_json.clone()))?;
-    let details = parse_cafe_details(&katana_json);
+    let details = parse_cafe_details(&katana_json, &endpoint, page_selectors, errors);
 
     Ok(ECTCafeResult { endpoint, details })
 }
@@ -97,33 +303,309 @@ fn parse_katana_endpoint(katana_json: &Value) -> Option<Url> {
     Url::parse(endpoint_string.as_str()).ok()
 }
 
-fn parse_cafe_details(katana_json: &Value) -> Option<ECTCafeDetails> {
-    let html_body_json = katana_json.get("response")?.get("body")?.clone();
+/// Details of the downloaded page, if katana recorded its body. Pages that are
+/// not individual cafe pages may fail to parse without it being an error.
+fn parse_cafe_details(
+    katana_json: &Value,
+    endpoint: &Url,
+    page_selectors: &CafePageSelectors,
+    errors: &mut VecDeque<PipelineError>,
+) -> Option<ECTCafeDetails> {
+    let html_body = katana_json
+        .get("response")
+        .and_then(|response| response.get("body"))
+        .and_then(|body| serde_json::from_value::<String>(body.clone()).ok())?;
 
-    let html_body = serde_json::from_value::<String>(html_body_json).ok()?;
-    let html = Html::parse_document(html_body.as_str());
+    if ect_page::is_cafe_page(endpoint) {
+        ect_page::read_cafe_page(endpoint, html_body.as_str(), page_selectors, errors)
+    } else {
+        ect_page::parse_cafe_page(endpoint, html_body.as_str(), page_selectors).ok()
+    }
+}
 
-    let name = parse_name(&html)?;
-    let address = parse_address(&html)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::SearchTerm;
+    use serde_json::json;
+    use std::{env, fs, path::PathBuf};
 
-    Some(ECTCafeDetails { name, address })
-}
+    /// Stand-ins for ECT cafe pages, each next to a `.golden` file with the
+    /// expected parse. They were written by hand because the live site could
+    /// not be fetched, so they follow the default selectors rather than prove
+    /// them. Run the tests with `UPDATE_GOLDEN=1` to rewrite the golden files
+    /// after an intended change, and review the diff.
+    const FIXTURE_DIR: &str = "tests/fixtures/ect";
+    /// Cafe pages saved from europeancoffeetrip.com, golden files alongside;
+    /// see the README in the folder for how to add one.
+    const LIVE_FIXTURE_DIR: &str = "tests/fixtures/ect/live";
+    /// `katana -jsonl` output for the fixture pages above.
+    const SAMPLE_RECORDING: &str = "tests/fixtures/katana/sample.jsonl";
 
-fn parse_address(html: &Html) -> Option<String> {
-    let address_selector = Selector::parse(r#"div[class="cafe-address"]"#).ok()?;
-    html.select(&address_selector)
-        .next()?
-        .text()
-        .next()
-        .map(str::trim)
-        .map(String::from)
-}
+    fn fixture_pages(fixture_dir: &str) -> Vec<PathBuf> {
+        let fixture_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join(fixture_dir);
 
-fn parse_name(html: &Html) -> Option<String> {
-    let address_selector = Selector::parse(r#"h1[class="cafe-name"]"#).ok()?;
-    html.select(&address_selector)
-        .next()?
-        .text()
-        .next()
-        .map(String::from)
+        let mut pages = fs::read_dir(fixture_dir)
+            .expect("fixture directory exists")
+            .map(|entry| entry.expect("readable fixture directory").path())
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "html")
+            })
+            .collect::<Vec<PathBuf>>();
+        pages.sort();
+
+        pages
+    }
+
+    fn katana_line(endpoint: &str, html_body: Option<&str>) -> String {
+        let mut katana_json = json!({ "request": { "endpoint": endpoint } });
+        if let Some(html_body) = html_body {
+            katana_json["response"] = json!({ "body": html_body });
+        }
+
+        katana_json.to_string()
+    }
+
+    fn parse_line(line: String) -> Result<ECTCafeResult, PipelineError> {
+        parse_katana_output(line, &CafePageSelectors::default(), &mut VecDeque::new())
+    }
+
+    /// One `field: value` line per detail, and one `  - item` line per list
+    /// entry, so a golden diff shows exactly which details changed.
+    fn describe_parse(result: Result<ECTCafeResult, PipelineError>) -> String {
+        let cafe = match result {
+            Ok(cafe) => cafe,
+            Err(err) => return format!("error: {}\n", err),
+        };
+
+        let mut lines = vec![format!("endpoint: {}", cafe.endpoint)];
+        let mut field = |name: &str, value: Option<String>| {
+            lines.push(format!("{}: {}", name, value.unwrap_or("-".to_string())))
+        };
+
+        match &cafe.details {
+            Some(details) => {
+                field("name", Some(details.name.clone()));
+                field("address", Some(details.address.clone()));
+                field(
+                    "coordinates",
+                    details.coordinates.map(|coordinates| {
+                        format!("{}, {}", coordinates.latitude, coordinates.longitude)
+                    }),
+                );
+                field("website", details.website.clone());
+                field("instagram", details.instagram.clone());
+                field("price_range", details.price_range.clone());
+                field("description", details.description.clone());
+                field("closed", Some(details.closed.to_string()));
+
+                for (name, items) in [
+                    ("opening_hours", &details.opening_hours),
+                    ("social_links", &details.social_links),
+                    ("roasters", &details.roasters),
+                    ("brewing_methods", &details.brewing_methods),
+                    ("photo_urls", &details.photo_urls),
+                ] {
+                    lines.push(format!("{}:", name));
+                    lines.extend(items.iter().map(|item| format!("  - {}", item)));
+                }
+            }
+            None => field("details", None),
+        }
+
+        let search_term = match cafe.into_search_term() {
+            SearchTerm::CafeDetails(term) => format!("cafe details: {}", term),
+            SearchTerm::UrlFragment(term) => format!("url fragment: {}", term),
+            SearchTerm::KnownEndpoint(term) => format!("known endpoint: {}", term),
+        };
+        lines.push(format!("search_term: {}", search_term));
+
+        lines.join("\n") + "\n"
+    }
+
+    #[test]
+    fn fixture_pages_match_golden_files() {
+        let pages = fixture_pages(FIXTURE_DIR);
+        assert!(!pages.is_empty(), "no fixture pages in {}", FIXTURE_DIR);
+
+        let mut mismatches = vec![];
+        for page in pages.into_iter().chain(fixture_pages(LIVE_FIXTURE_DIR)) {
+            let slug = page.file_stem().unwrap().to_string_lossy().to_string();
+            let endpoint = format!("https://europeancoffeetrip.com/cafe/{}/", slug);
+            let html_body = fs::read_to_string(&page).unwrap();
+
+            let mut layout_errors = VecDeque::new();
+            let result = parse_katana_output(
+                katana_line(&endpoint, Some(&html_body)),
+                &CafePageSelectors::default(),
+                &mut layout_errors,
+            );
+            let actual = layout_errors
+                .into_iter()
+                .map(Err)
+                .fold(describe_parse(result), |actual, err| {
+                    actual + &describe_parse(err)
+                });
+
+            let golden_path = page.with_extension("golden");
+            if env::var_os("UPDATE_GOLDEN").is_some() {
+                fs::write(&golden_path, &actual).unwrap();
+                continue;
+            }
+
+            let expected = fs::read_to_string(&golden_path).unwrap_or_default();
+            if actual != expected {
+                mismatches.push(format!(
+                    "{}\n--- expected\n{}--- actual\n{}",
+                    slug, expected, actual
+                ));
+            }
+        }
+
+        assert!(mismatches.is_empty(), "{}", mismatches.join("\n"));
+    }
+
+    /// Unlike the golden files, which record whatever the parse gives, this
+    /// fails when the default selectors cannot read a saved page at all.
+    #[test]
+    fn default_selectors_read_the_name_and_address_of_live_pages() {
+        for page in fixture_pages(LIVE_FIXTURE_DIR) {
+            let slug = page.file_stem().unwrap().to_string_lossy().to_string();
+            let endpoint = format!("https://europeancoffeetrip.com/cafe/{}/", slug);
+            let html_body = fs::read_to_string(&page).unwrap();
+
+            let mut layout_errors = VecDeque::new();
+            let details = parse_katana_output(
+                katana_line(&endpoint, Some(&html_body)),
+                &CafePageSelectors::default(),
+                &mut layout_errors,
+            )
+            .unwrap()
+            .details;
+
+            assert!(layout_errors.is_empty(), "{}: unknown layout", slug);
+            let details = details.unwrap_or_else(|| panic!("{}: no cafe details", slug));
+            assert!(!details.name.is_empty(), "{}: no name", slug);
+            assert!(!details.address.is_empty(), "{}: no address", slug);
+        }
+    }
+
+    #[test]
+    fn pages_without_a_body_are_searched_by_url() {
+        let cafe = parse_line(katana_line(
+            "https://europeancoffeetrip.com/cafe/utopia-geneva/",
+            None,
+        ))
+        .unwrap();
+
+        assert!(cafe.details.is_none());
+        assert!(matches!(
+            cafe.into_search_term(),
+            SearchTerm::UrlFragment(search_term) if search_term == "utopia geneva"
+        ));
+    }
+
+    #[test]
+    fn non_cafe_pages_with_unknown_layout_are_not_errors() {
+        let cafe = parse_line(katana_line(
+            "https://europeancoffeetrip.com/cafe",
+            Some("<h2>All cafes</h2>"),
+        ))
+        .unwrap();
+
+        assert!(cafe.details.is_none());
+    }
+
+    #[test]
+    fn replays_a_recorded_katana_crawl() {
+        let recording = Path::new(env!("CARGO_MANIFEST_DIR")).join(SAMPLE_RECORDING);
+        let stream = KatanaStream::from_recording(&CoffeeMapConfig::default(), &recording).unwrap();
+
+        assert!(stream.describe().ends_with(SAMPLE_RECORDING));
+
+        let cafes = stream
+            .map(|result| result.unwrap().details.unwrap())
+            .map(|details| (details.name, details.closed))
+            .collect::<Vec<(String, bool)>>();
+
+        assert_eq!(
+            cafes,
+            vec![
+                ("Utopia coffee shop".to_string(), false),
+                ("Kaffebar Nord".to_string(), false),
+                ("Mobile Espresso Cart".to_string(), false),
+                ("Old Town Roasters".to_string(), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn replays_the_archive_written_while_streaming() {
+        let config = CoffeeMapConfig::default();
+        let recording = Path::new(env!("CARGO_MANIFEST_DIR")).join(SAMPLE_RECORDING);
+        let archive_path = env::temp_dir().join(format!(
+            "coffee_map_archive_{}_{}",
+            std::process::id(),
+            KATANA_ARCHIVE_FILENAME
+        ));
+
+        let mut stream = KatanaStream::from_recording(&config, &recording).unwrap();
+        let archive_file = File::create(&archive_path).unwrap();
+        stream.archive = Some(GzEncoder::new(
+            BufWriter::new(archive_file),
+            Compression::default(),
+        ));
+        let streamed = stream.map(describe_parse).collect::<Vec<String>>();
+
+        let replayed = KatanaStream::from_recording(&config, &archive_path)
+            .unwrap()
+            .map(describe_parse)
+            .collect::<Vec<String>>();
+        fs::remove_file(&archive_path).unwrap();
+
+        assert_eq!(streamed.len(), 4);
+        assert_eq!(replayed, streamed);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn yields_katana_stderr_and_a_failing_exit_status() {
+        let line = katana_line("https://europeancoffeetrip.com/cafe/utopia-geneva/", None);
+        let script = format!("echo '{}'; echo 'rate limited' >&2; exit 3", line);
+        let (katana, stdout) = KatanaProcess::spawn("sh", vec!["-c", &script]).unwrap();
+        let reader: Box<dyn BufRead + Send> = Box::new(BufReader::new(stdout));
+
+        let stream = KatanaStream {
+            description: "stub katana".to_string(),
+            reader_lines: reader.lines(),
+            archive: None,
+            katana: Some(katana),
+            pending_errors: VecDeque::new(),
+            page_selectors: CafePageSelectors::default(),
+        };
+        let results = stream.collect::<Vec<Result<ECTCafeResult, PipelineError>>>();
+
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        assert!(results.iter().any(|result| matches!(
+            result,
+            Err(PipelineError::KatanaStderr(line)) if line == "rate limited"
+        )));
+        assert!(matches!(
+            results.last(),
+            Some(Err(PipelineError::KatanaExitStatus(status))) if status.code() == Some(3)
+        ));
+    }
+
+    #[test]
+    fn reports_malformed_katana_lines() {
+        assert!(matches!(
+            parse_line("garbage".to_string()),
+            Err(PipelineError::KatanaJsonParseError(_))
+        ));
+        assert!(matches!(
+            parse_line(json!({ "request": {} }).to_string()),
+            Err(PipelineError::KatanaEndpointParseError(_))
+        ));
+    }
 }
@@ -1,138 +1,734 @@
-use katana_stream::ECTCafeResult;
+use cache::PlacemarkCache;
+use cafe_list::CafeList;
+use cafe_source::{CafeSource, ECTCafeDetails, ECTCafeResult, SkipPages, UniqueEndpoints};
+use candidate_match::MatchQuery;
+use checkpoint::{Checkpoint, CheckpointState};
+use chrono::Utc;
+use clap::Parser;
+use cli::{Cli, Command, IncrementalArgs, SourceArgs};
+use ect_crawler::ECTCrawler;
+use geocoder::{GeocodeQuery, Geocoder};
 use kml::types::Placemark;
-use model::{CoffeeMapConfig, IOError};
-use terminal_gui::LogCounts;
+use model::{CoffeeMapConfig, CrawlerKind, IOError};
+use region::Region;
+use seen_endpoints::SeenEndpoints;
+use sitemap::SitemapSource;
+use terminal_gui::{CrawlDisplay, LogCounts};
 
-use reqwest::blocking;
+use futures::{stream, StreamExt};
+use tokio::sync::mpsc;
+use url::Url;
 
-use std::collections::{HashMap, HashSet};
-use std::env;
+use std::collections::HashSet;
+use std::process;
+use std::thread;
 
 use crate::katana_stream::KatanaStream;
 use crate::model::{PipelineError, PlacemarkComputation, SearchTerm};
+use crate::run_directory::{CrawlSummary, RunDirectory};
 
-use superconsole::SuperConsole;
-
+mod atomic_file;
+mod backoff;
 mod cache;
+mod cafe_list;
+mod cafe_source;
+mod cafe_status;
+mod candidate_match;
+mod checkpoint;
+mod cli;
+mod config;
+mod ect_crawler;
+mod ect_page;
+mod fake_geocoder;
+mod gazetteer;
+mod geocoder;
 mod google_places;
 mod katana_stream;
 mod model;
+mod osm_geocoder;
+mod polite_client;
+mod region;
+mod retrying_client;
+mod run_directory;
+mod seen_endpoints;
+mod selector_profile;
+mod shutdown;
+mod sitemap;
 mod terminal_gui;
+#[cfg(test)]
+mod test_server;
+mod token_bucket;
+mod words;
 mod write_kml;
 
-fn main() -> Result<(), IOError> {
-    let args: Vec<String> = env::args().collect();
-
-    let google_api_key = &args[1];
+fn main() {
+    let cli = Cli::parse();
+    let config = match config::load(&cli.config) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(2);
+        }
+    };
 
-    let config = CoffeeMapConfig {
-        kml_batch_size: 1000,
-        katana_search_depth: 14,
-        katana_requests_per_second: 40,
-        cache_folder: Some("./cache/".to_string()),
-        output_folder: "./kml/output/".to_string(),
-        output_prefix: "placemarks".to_string(),
+    let result = match cli.command {
+        Command::Crawl {
+            google,
+            source,
+            incremental,
+            resume,
+        } => run_crawl(
+            &config,
+            google.google_api_key,
+            &source,
+            &incremental,
+            resume,
+        ),
+        Command::Geocode {
+            google,
+            search_term,
+        } => run_geocode(&config, search_term, google.google_api_key),
+        Command::Export => run_export(&config),
+        Command::Cache => print_cache(&config),
+        Command::Stats => print_stats(&config),
     };
 
-    let cache = match &config.cache_folder {
-        Some(folder) => cache::make_existing_placemarks_hashmap(folder.clone()),
-        None => HashMap::<String, Placemark>::new(),
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+
+    shutdown::exit_if_interrupted();
+}
+
+fn run_crawl(
+    config: &CoffeeMapConfig,
+    google_api_key: Option<String>,
+    source_args: &SourceArgs,
+    incremental_args: &IncrementalArgs,
+    resume: bool,
+) -> Result<(), IOError> {
+    shutdown::install_handler()?;
+
+    let is_live_crawl = source_args.replay.is_none() && source_args.cafe_list.is_none();
+    let is_incremental = incremental_args.only_new || incremental_args.stale_after_days.is_some();
+    if is_incremental && is_live_crawl && config.crawler != CrawlerKind::Sitemap {
+        return Err(IOError::IncrementalCrawlUnsupported(config.crawler));
+    }
+
+    let geocoder = geocoder::from_config(config, google_api_key)?;
+    let run_directory = RunDirectory::create(config)?;
+    let cache = load_cache(config)?;
+    let seen_endpoints = match &config.cache_folder {
+        Some(folder) => SeenEndpoints::load(folder),
+        None => SeenEndpoints::default(),
+    };
+    let resumed = resume
+        .then(|| config.cache_folder.as_deref().and_then(checkpoint::read))
+        .flatten();
+    let initial_counts = match resume {
+        true => LogCounts::new().with_note(resume_note(resumed.as_ref())),
+        false => LogCounts::new(),
     };
+    let skipped_pages = skipped_pages(incremental_args, resumed.as_ref(), &seen_endpoints, &cache);
 
-    let placemarks = crawl_cafes(&config, google_api_key, &cache)?;
+    let is_live_katana_crawl = is_live_crawl && config.crawler == CrawlerKind::Katana;
+    let cafe_source = open_cafe_source(
+        config,
+        source_args,
+        &run_directory,
+        skipped_pages
+            .clone()
+            .unwrap_or_else(|| cache.ect_url_keys()),
+    )?;
+    let cafe_source = Box::new(SkipPages::new(
+        Box::new(UniqueEndpoints::new(cafe_source)),
+        skipped_pages.unwrap_or_default(),
+    ));
+    let source_description = cafe_source.describe();
 
-    config
-        .cache_folder
-        .as_ref()
-        .map(|folder| cache::update(folder.clone(), cache, &placemarks));
+    let checkpoint = config.cache_folder.as_ref().map(|folder| {
+        Checkpoint::new(
+            folder.clone(),
+            config.checkpoint_every,
+            &cache,
+            seen_endpoints,
+            resumed,
+        )
+    });
+
+    let display = CrawlDisplay::on_terminal(initial_counts)?;
+    let (placemarks, computation_log) = crawl_cafes(
+        cafe_source,
+        config,
+        geocoder.as_ref(),
+        &cache,
+        checkpoint,
+        incremental_args.stale_after_days,
+        display,
+    )?;
 
     let deduplicated_placemarks_based_on_google_id = placemarks
         .into_iter()
         .collect::<HashSet<PlacemarkComputation>>()
         .into_iter()
-        .map(|computation| computation.to_placemark())
+        .map(|computation| computation.into_placemark())
         .collect::<Vec<Placemark>>();
 
-    write_kml::generate_kml_documents(&config, placemarks)
+    let chunk_files = write_kml::generate_kml_documents(
+        config,
+        run_directory.path_string(),
+        deduplicated_placemarks_based_on_google_id,
+    )?;
+
+    let crawl_summary = CrawlSummary {
+        source: source_description,
+        katana_version: is_live_katana_crawl
+            .then(katana_stream::katana_version)
+            .flatten(),
+        katana_archive: is_live_katana_crawl
+            .then(|| katana_stream::KATANA_ARCHIVE_FILENAME.to_string()),
+        counts: computation_log,
+        interrupted: shutdown::is_interrupted(),
+    };
+
+    if shutdown::is_interrupted() {
+        println!(
+            "crawl interrupted, wrote the cafes found so far; continue it with `crawl --resume`"
+        );
+    }
+
+    run_directory.finish("crawl", config, chunk_files, Some(crawl_summary))
+}
+
+/// What `--resume` found to continue, shown with the crawl's counts.
+fn resume_note(resumed: Option<&CheckpointState>) -> String {
+    match resumed {
+        Some(state) => format!(
+            "resumed the crawl started at {} after {} placemarks, last from {}",
+            state.started_at,
+            state.saved_placemarks,
+            state.last_endpoint.as_deref().unwrap_or("no ECT page")
+        ),
+        None => "no unfinished crawl to resume, started a new one".to_string(),
+    }
+}
+
+/// Cached pages the incremental flags, or the resumed crawl, say not to
+/// process again, or `None` when every page is to be processed.
+fn skipped_pages(
+    incremental_args: &IncrementalArgs,
+    resumed: Option<&CheckpointState>,
+    seen_endpoints: &SeenEndpoints,
+    cache: &PlacemarkCache,
+) -> Option<HashSet<String>> {
+    let recently_processed = match incremental_args {
+        IncrementalArgs { only_new: true, .. } => {
+            Some(seen_endpoints.processed_since(None, Utc::now()))
+        }
+        IncrementalArgs {
+            stale_after_days: Some(days),
+            ..
+        } => Some(seen_endpoints.processed_since(Some(*days), Utc::now())),
+        _ => None,
+    };
+    let processed_by_resumed_crawl =
+        resumed.map(|state| seen_endpoints.processed_after(Some(state.started_at)));
+
+    let skipped = recently_processed
+        .into_iter()
+        .chain(processed_by_resumed_crawl)
+        .reduce(|left, right| left.union(&right).cloned().collect())?;
+
+    Some(
+        skipped
+            .intersection(&cache.ect_url_keys())
+            .cloned()
+            .collect(),
+    )
+}
+
+/// Opens the cafe source; a sitemap crawl does not download `cached_pages`.
+fn open_cafe_source(
+    config: &CoffeeMapConfig,
+    source_args: &SourceArgs,
+    run_directory: &RunDirectory,
+    cached_pages: HashSet<String>,
+) -> Result<Box<dyn CafeSource>, IOError> {
+    if let Some(recording) = &source_args.replay {
+        return Ok(Box::new(KatanaStream::from_recording(config, recording)?));
+    }
+
+    if let Some(cafe_list) = &source_args.cafe_list {
+        return Ok(Box::new(CafeList::open(cafe_list)?));
+    }
+
+    match config.crawler {
+        CrawlerKind::Katana => {
+            let archive_path = run_directory
+                .path
+                .join(katana_stream::KATANA_ARCHIVE_FILENAME);
+
+            Ok(Box::new(KatanaStream::new(config, Some(&archive_path))?))
+        }
+        CrawlerKind::Native => Ok(Box::new(ECTCrawler::from_config(config)?)),
+        CrawlerKind::Sitemap => Ok(Box::new(SitemapSource::from_config(config, cached_pages)?)),
+    }
+}
+
+fn run_geocode(
+    config: &CoffeeMapConfig,
+    search_term: String,
+    google_api_key: Option<String>,
+) -> Result<(), IOError> {
+    let runtime = tokio::runtime::Runtime::new().map_err(IOError::AsyncRuntime)?;
+    let geocoder = geocoder::from_config(config, google_api_key)?;
+
+    let query = MatchQuery::from_search_term(&search_term);
+    let candidate_match = runtime
+        .block_on(geocoder.geocode(&GeocodeQuery::new(search_term.clone(), None)))
+        .and_then(|candidates| candidate_match::choose_candidate(candidates, &query, &search_term));
+
+    match candidate_match {
+        Ok(candidate_match) => {
+            let needs_review = candidate_match.needs_review(config.match_review_threshold);
+            let candidate = candidate_match.candidate;
+            println!("{}", candidate.name);
+            if !candidate.address.is_empty() {
+                println!("{}", candidate.address);
+            }
+            if let Some(url) = candidate.url {
+                println!("{}", url);
+            }
+            println!("{}, {}", candidate.latitude, candidate.longitude);
+            if let Some(business_status) = candidate.business_status {
+                println!("{}", business_status);
+            }
+            println!(
+                "match confidence {:.2}, candidate {} of {}{}",
+                candidate_match.confidence,
+                candidate_match.rank,
+                candidate_match.candidate_count,
+                if needs_review { ", needs review" } else { "" }
+            );
+
+            Ok(())
+        }
+        Err(err) => Err(IOError::GeocodeFailed(err)),
+    }
+}
+
+fn run_export(config: &CoffeeMapConfig) -> Result<(), IOError> {
+    let run_directory = RunDirectory::create(config)?;
+    let placemarks = load_cache(config)?.placemarks_for(config.geocoder.name());
+
+    let chunk_files = write_kml::generate_kml_documents(
+        config,
+        run_directory.path_string(),
+        deduplicate_placemarks(placemarks),
+    )?;
+
+    run_directory.finish("export", config, chunk_files, None)
+}
+
+fn print_cache(config: &CoffeeMapConfig) -> Result<(), IOError> {
+    let cache = load_cache(config)?;
+    let mut entries = cache.entries().collect::<Vec<_>>();
+    entries.sort_by_key(|(search_term, _)| *search_term);
+
+    for (search_term, placemark) in entries {
+        let review = if candidate_match::needs_review(placemark) {
+            " (needs review)"
+        } else {
+            ""
+        };
+        println!(
+            "{} -> {}{}",
+            search_term,
+            placemark.name.as_deref().unwrap_or_default(),
+            review
+        );
+    }
+
+    Ok(())
+}
+
+fn print_stats(config: &CoffeeMapConfig) -> Result<(), IOError> {
+    let cache = load_cache(config)?;
+    let search_term_count = cache.search_term_count();
+    let placemarks = cache.into_placemarks();
+    let without_id = placemarks
+        .iter()
+        .filter(|placemark| !placemark.attrs.contains_key("id"))
+        .count();
+    let needing_review = placemarks
+        .iter()
+        .filter(|placemark| candidate_match::needs_review(placemark))
+        .count();
+
+    println!("cached search terms: {}", search_term_count);
+    println!(
+        "unique places: {}",
+        deduplicate_placemarks(placemarks).len()
+    );
+    println!("placemarks without a google id: {}", without_id);
+    println!("placemarks needing review: {}", needing_review);
+
+    if let Some(manifest) = run_directory::read_latest_manifest(&config.output_folder) {
+        println!(
+            "latest run: {} started at {}, took {:.0}s, wrote {} kml files",
+            manifest.command,
+            manifest.started_at,
+            manifest.duration_seconds,
+            manifest.chunk_files.len()
+        );
+    }
+
+    Ok(())
+}
+
+fn load_cache(config: &CoffeeMapConfig) -> Result<PlacemarkCache, IOError> {
+    match &config.cache_folder {
+        Some(folder) => cache::load(folder.clone()),
+        None => Ok(PlacemarkCache::default()),
+    }
+}
+
+fn deduplicate_placemarks(placemarks: Vec<Placemark>) -> Vec<Placemark> {
+    let mut seen_ids = HashSet::<String>::new();
+
+    placemarks
+        .into_iter()
+        .filter(|placemark| match placemark.attrs.get("id") {
+            Some(id) => seen_ids.insert(id.clone()),
+            None => true,
+        })
+        .collect()
+}
+
+/// A crawled cafe, with its placemark when it could be found without asking google.
+enum CrawledCafe {
+    Resolved(Result<PlacemarkComputation, PipelineError>),
+    NeedsGeocoding(GeocodeRequest),
 }
 
+/// A cafe to look up with google places once a request slot is free.
+struct GeocodeRequest {
+    search_term: SearchTerm,
+    endpoint: Url,
+    details: Option<ECTCafeDetails>,
+}
+
+/// Crawls `cafe_source` on its own thread while up to `geocoder_concurrency`
+/// geocoder requests run on async tasks, so crawling and geocoding overlap.
 fn crawl_cafes(
+    cafe_source: Box<dyn CafeSource>,
     config: &CoffeeMapConfig,
-    google_api_key: &String,
-    cached_search_terms: &HashMap<String, Placemark>,
-) -> Result<Vec<PlacemarkComputation>, IOError> {
-    let client = blocking::Client::new();
+    geocoder: &dyn Geocoder,
+    cache: &PlacemarkCache,
+    mut checkpoint: Option<Checkpoint>,
+    recheck_after_days: Option<u32>,
+    mut display: CrawlDisplay,
+) -> Result<(Vec<PlacemarkComputation>, LogCounts), IOError> {
+    let runtime = tokio::runtime::Runtime::new().map_err(IOError::AsyncRuntime)?;
+
+    let mut placemarks = Vec::<PlacemarkComputation>::new();
+
+    let (sender, receiver) = mpsc::channel::<CrawledCafe>(config.geocoder_concurrency);
+
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            send_crawled_cafes(
+                cafe_source,
+                cache,
+                geocoder.name(),
+                recheck_after_days,
+                sender,
+            )
+        });
 
-    let mut computation_log = LogCounts::new();
-    let mut superconsole = SuperConsole::new().ok_or_else(|| IOError::SuperConsoleNotTTY)?;
+        runtime.block_on(async {
+            // Owned by this block so the crawl thread stops once it returns early.
+            let mut receiver = receiver;
+            let mut placemark_results = stream::poll_fn(|cx| receiver.poll_recv(cx))
+                .map(|crawled_cafe| geocode(crawled_cafe, geocoder, config.match_review_threshold))
+                .buffer_unordered(config.geocoder_concurrency);
 
-    let placemarks = KatanaStream::new(&config)
-        .filter_map(|katana_result| {
-            let placemark_result =
-                process_katana_result(katana_result, google_api_key, &client, cached_search_terms);
+            while let Some(placemark_result) = placemark_results.next().await {
+                display.update(&placemark_result, geocoder.retries());
 
-            computation_log = computation_log.update(&placemark_result);
-            let _ = superconsole.render(&computation_log.make_component());
+                if let Ok(computation) = placemark_result {
+                    placemarks.push(computation);
 
-            placemark_result.ok()
+                    if let Some(checkpoint) = &mut checkpoint {
+                        checkpoint.placemark_added(&placemarks)?;
+                    }
+                }
+            }
+
+            Ok(())
         })
-        .collect::<Vec<PlacemarkComputation>>();
+    })?;
 
-    let _ = superconsole.finalize(&computation_log.make_component());
+    let computation_log = display.finish();
+
+    match checkpoint {
+        Some(checkpoint) if shutdown::is_interrupted() => checkpoint.suspend(&placemarks)?,
+        Some(checkpoint) => checkpoint.finish(&placemarks)?,
+        None => {}
+    }
 
-    Ok(placemarks)
+    Ok((placemarks, computation_log))
 }
 
-fn process_katana_result(
+/// Runs the crawl, stopping when interrupted or when nobody is receiving any more.
+fn send_crawled_cafes(
+    cafe_source: Box<dyn CafeSource>,
+    cache: &PlacemarkCache,
+    geocoder_name: &str,
+    recheck_after_days: Option<u32>,
+    sender: mpsc::Sender<CrawledCafe>,
+) {
+    for katana_result in cafe_source {
+        if shutdown::is_interrupted() {
+            break;
+        }
+
+        if sender
+            .blocking_send(resolve_crawled_cafe(
+                katana_result,
+                cache,
+                geocoder_name,
+                recheck_after_days,
+            ))
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+/// Resolves a crawled cafe from the placemarks cached for `geocoder_name` or
+/// its page's coordinates where possible. Cached placemarks geocoded more than
+/// `recheck_after_days` ago are geocoded again, so their business status is
+/// checked again.
+fn resolve_crawled_cafe(
     katana_result: Result<ECTCafeResult, PipelineError>,
-    google_api_key: &String,
-    client: &blocking::Client,
-    searchterm_to_placemark: &HashMap<String, Placemark>,
-) -> Result<PlacemarkComputation, PipelineError> {
-    let katana_cafe = katana_result?;
+    cache: &PlacemarkCache,
+    geocoder_name: &str,
+    recheck_after_days: Option<u32>,
+) -> CrawledCafe {
+    let katana_cafe = match katana_result {
+        Ok(katana_cafe) => katana_cafe,
+        Err(err) => return CrawledCafe::Resolved(Err(err)),
+    };
+    let endpoint = katana_cafe.endpoint.clone();
+    let details = katana_cafe.details.clone();
 
-    let search_term = make_searchterm(katana_cafe);
+    if katana_cafe.details.is_none() {
+        if let Some((search_term, placemark)) = cache.get_by_ect_url(&endpoint, geocoder_name) {
+            return CrawledCafe::Resolved(Ok(PlacemarkComputation::FromCache(
+                SearchTerm::KnownEndpoint(search_term.clone()),
+                placemark.clone(),
+            )));
+        }
+    }
+
+    let search_term = katana_cafe.into_search_term();
     let search_term_str = search_term.extract_str();
 
-    if let Some(existing_placemark) = searchterm_to_placemark.get(search_term_str) {
+    let today = Utc::now().date_naive();
+    let cached_placemark = cache
+        .get(search_term_str, geocoder_name)
+        .filter(|placemark| {
+            !recheck_after_days
+                .is_some_and(|days| cafe_status::needs_recheck(placemark, days, today))
+        });
+
+    let computation = if let Some(existing_placemark) = cached_placemark {
         let cloned_placemark = existing_placemark.clone();
 
-        Ok(PlacemarkComputation::FromCache(
-            search_term,
-            cloned_placemark,
-        ))
+        PlacemarkComputation::FromCache(search_term, cloned_placemark)
+    } else if let Some(placemark) = details
+        .as_ref()
+        .and_then(|details| details.to_placemark(search_term_str))
+    {
+        PlacemarkComputation::FromECTCoordinates(search_term, placemark)
     } else {
-        google_places::query(&client, search_term_str.clone(), google_api_key.clone()).map({
-            |google_place| {
-                let placemark = google_place.to_placemark();
+        return CrawledCafe::NeedsGeocoding(GeocodeRequest {
+            search_term,
+            endpoint,
+            details,
+        });
+    };
 
-                PlacemarkComputation::FromGoogleQuery(search_term, placemark)
+    CrawledCafe::Resolved(Ok(with_ect_page(computation, &endpoint, details.as_ref())))
+}
+
+async fn geocode(
+    crawled_cafe: CrawledCafe,
+    geocoder: &dyn Geocoder,
+    review_threshold: f64,
+) -> Result<PlacemarkComputation, PipelineError> {
+    let request = match crawled_cafe {
+        CrawledCafe::Resolved(placemark_result) => return placemark_result,
+        CrawledCafe::NeedsGeocoding(request) => request,
+    };
+
+    let search_term_str = request.search_term.extract_str().clone();
+    let query = match &request.details {
+        Some(details) => MatchQuery::from_details(details),
+        None => MatchQuery::from_search_term(&search_term_str),
+    };
+    let region = request
+        .details
+        .as_ref()
+        .and_then(|details| Region::from_address(&details.address));
+    let candidates = geocoder
+        .geocode(&GeocodeQuery::new(search_term_str.clone(), region))
+        .await?;
+    let candidates = match region {
+        Some(region) if !candidates.is_empty() => match region.within(candidates) {
+            candidates if candidates.is_empty() => {
+                return Err(PipelineError::PlaceOutsideCountryError(format!(
+                    "{}, {}",
+                    region.country.name(),
+                    search_term_str
+                )))
             }
-        })
+            candidates => candidates,
+        },
+        _ => candidates,
+    };
+    let candidate_match = candidate_match::choose_candidate(candidates, &query, &search_term_str)?;
+    let computation = PlacemarkComputation::FromGeocoder(
+        request.search_term,
+        candidate_match.into_placemark(geocoder.name(), search_term_str, review_threshold),
+    );
+
+    Ok(with_ect_page(
+        computation,
+        &request.endpoint,
+        request.details.as_ref(),
+    ))
+}
+
+/// Records the ECT page a placemark was found from, and what it says about the cafe.
+fn with_ect_page(
+    computation: PlacemarkComputation,
+    endpoint: &Url,
+    details: Option<&ECTCafeDetails>,
+) -> PlacemarkComputation {
+    let computation = computation.with_ect_url(endpoint);
+
+    match details {
+        Some(details) => computation.with_ect_details(details),
+        None => computation,
     }
 }
 
-fn make_searchterm(katana_cafe: ECTCafeResult) -> SearchTerm {
-    match katana_cafe {
-        ECTCafeResult {
-            details: Some(cafe_details),
-            endpoint: _,
-        } => {
-            let search_string = format!("{} {}", &cafe_details.name, &cafe_details.address);
-            SearchTerm::CafeDetails(search_string)
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::fake_geocoder::FakeGeocoder;
+
+    struct StubSource(Box<dyn Iterator<Item = Result<ECTCafeResult, PipelineError>> + Send>);
+
+    impl Iterator for StubSource {
+        type Item = Result<ECTCafeResult, PipelineError>;
+
+        fn next(&mut self) -> Option<Result<ECTCafeResult, PipelineError>> {
+            self.0.next()
         }
-        ECTCafeResult {
-            details: _,
-            endpoint,
-        } => {
-            let search_string = endpoint
-                .path_segments()
-                .unwrap()
-                .nth(1)
-                .unwrap()
-                .to_string()
-                .replace("-", " ");
-            SearchTerm::UrlFragment(search_string)
+    }
+
+    impl CafeSource for StubSource {
+        fn describe(&self) -> String {
+            "stub".to_string()
         }
     }
+
+    fn cafe(slug: &str) -> Result<ECTCafeResult, PipelineError> {
+        Ok(ECTCafeResult {
+            endpoint: Url::parse(&format!("https://europeancoffeetrip.com/cafe/{}/", slug))
+                .unwrap(),
+            details: None,
+        })
+    }
+
+    fn count(counts: &LogCounts, name: &str) -> i64 {
+        serde_json::to_value(counts).unwrap()[name]
+            .as_i64()
+            .unwrap()
+    }
+
+    #[test]
+    fn geocodes_cafes_in_order_and_counts_the_source_errors() {
+        let source = StubSource(Box::new(
+            vec![
+                cafe("alpha"),
+                Err(PipelineError::CrawlerHTTPError("timed out".to_string())),
+                cafe("beta-bar"),
+                cafe("gamma"),
+            ]
+            .into_iter(),
+        ));
+        let config = CoffeeMapConfig {
+            geocoder_concurrency: 1,
+            ..CoffeeMapConfig::default()
+        };
+
+        let (placemarks, counts) = crawl_cafes(
+            Box::new(source),
+            &config,
+            &FakeGeocoder,
+            &PlacemarkCache::default(),
+            None,
+            None,
+            CrawlDisplay::hidden(LogCounts::new()),
+        )
+        .unwrap();
+
+        let search_terms = placemarks
+            .iter()
+            .map(|computation| computation.get_search_term().extract_str().as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(search_terms, vec!["alpha", "beta bar", "gamma"]);
+        assert_eq!(count(&counts, "queried_with_url"), 3);
+        assert_eq!(count(&counts, "crawler_http_errors"), 1);
+    }
+
+    #[test]
+    fn a_failing_checkpoint_stops_the_crawl() {
+        // A file where the cache folder should be, so saving the checkpoint fails.
+        let cache_folder =
+            std::env::temp_dir().join(format!("coffee_map_blocked_cache_{}", std::process::id()));
+        fs::write(&cache_folder, "").unwrap();
+        let cache = PlacemarkCache::default();
+        let checkpoint = Checkpoint::new(
+            cache_folder.join("cache").display().to_string(),
+            1,
+            &cache,
+            SeenEndpoints::default(),
+            None,
+        );
+
+        // Never ends, so the crawl only returns if the source's thread stops.
+        let source = StubSource(Box::new(std::iter::repeat_with(|| cafe("alpha"))));
+
+        let result = crawl_cafes(
+            Box::new(source),
+            &CoffeeMapConfig::default(),
+            &FakeGeocoder,
+            &cache,
+            Some(checkpoint),
+            None,
+            CrawlDisplay::hidden(LogCounts::new()),
+        );
+        fs::remove_file(&cache_folder).unwrap();
+
+        assert!(matches!(result, Err(IOError::CreateMissingDirectories(_))));
+    }
 }
@@ -1,26 +1,161 @@
-use kml::types::Placemark;
+use crate::cache::ECT_URL_ATTR;
+use crate::cafe_source::{ECTCafeDetails, EXTENDED_DATA_ELEMENT};
+use crate::cafe_status;
+use crate::geocoder::GOOGLE_DATA_PREFIX;
+use crate::google_places::{self, GOOGLE_PLACES_URL};
+use crate::selector_profile::SelectorProfile;
+use clap::ValueEnum;
+use kml::types::{Element, Placemark};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::fmt;
+use std::process::ExitStatus;
 use std::{hash::Hasher, io};
+use url::Url;
 
 use std::hash::Hash;
 
+/// Crawler used by `crawl` when no replay file or cafe list is given.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum CrawlerKind {
+    /// The katana binary, which must be on PATH.
+    #[default]
+    Katana,
+    /// The built-in crawler, which needs no external tools.
+    Native,
+    /// Cafe pages listed in the site's sitemaps; only pages missing from the cache are downloaded.
+    Sitemap,
+}
+
+/// Backend `crawl` and `geocode` use to find where a cafe is.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum GeocoderKind {
+    /// The google places text search, which needs an API key.
+    #[default]
+    Google,
+    /// A Nominatim server, by default the public OpenStreetMap one.
+    Nominatim,
+    /// A Photon server, by default the public komoot one.
+    Photon,
+    /// An offline CSV gazetteer, see `gazetteer_file`.
+    Gazetteer,
+    /// Made-up but repeatable places, for trying the pipeline without a network.
+    Fake,
+}
+
+impl GeocoderKind {
+    /// The name the geocoder records in the placemarks it finds.
+    pub fn name(&self) -> &'static str {
+        match self {
+            GeocoderKind::Google => "google",
+            GeocoderKind::Nominatim => "nominatim",
+            GeocoderKind::Photon => "photon",
+            GeocoderKind::Gazetteer => "gazetteer",
+            GeocoderKind::Fake => "fake",
+        }
+    }
+}
+
+/// What the KML output does with cafes that have closed permanently.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ClosedCafes {
+    /// Put them in a "Closed" folder of each KML file.
+    #[default]
+    Folder,
+    /// Leave them out.
+    Omit,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
 pub struct CoffeeMapConfig {
+    pub crawler: CrawlerKind,
     pub kml_batch_size: usize,
     pub katana_search_depth: u8,
     pub katana_requests_per_second: u8,
     pub cache_folder: Option<String>,
     pub output_folder: String,
     pub output_prefix: String,
+    pub closed_cafes: ClosedCafes,
+    /// Number of newly geocoded placemarks after which a crawl saves its progress to the cache.
+    pub checkpoint_every: usize,
+    /// Maximum number of geocoder requests in flight at once.
+    pub geocoder_concurrency: usize,
+    /// Times a google places request is sent again after a 429, 5xx or network error.
+    pub google_max_retries: u32,
+    /// Google places requests sent per second at most, shared by all requests in flight.
+    pub google_queries_per_second: u32,
+    /// Base URL of the google places API, e.g. of a mock server.
+    pub google_places_url: String,
+    /// Place fields asked for beyond those every search needs, see [`google_places::OPTIONAL_FIELDS`].
+    pub google_place_fields: Vec<String>,
+    pub geocoder: GeocoderKind,
+    /// Endpoint of the nominatim or photon geocoder, when not the public instance.
+    pub geocoder_url: Option<String>,
+    /// Maximum number of nominatim or photon requests sent per second.
+    pub geocoder_queries_per_second: u32,
+    /// Times a nominatim or photon request is sent again after a 429, 5xx or network error.
+    pub geocoder_max_retries: u32,
+    /// CSV file with `name,address,latitude,longitude` columns used by the gazetteer geocoder.
+    pub gazetteer_file: Option<String>,
+    /// Geocoded placemarks matching their cafe with less confidence than this, between 0 and 1, are marked for review.
+    pub match_review_threshold: f64,
+    pub selectors: SelectorProfile,
 }
 
+impl Default for CoffeeMapConfig {
+    fn default() -> Self {
+        CoffeeMapConfig {
+            crawler: CrawlerKind::Katana,
+            kml_batch_size: 1000,
+            katana_search_depth: 14,
+            katana_requests_per_second: 40,
+            cache_folder: Some("./cache/".to_string()),
+            output_folder: "./output/".to_string(),
+            output_prefix: "placemarks".to_string(),
+            closed_cafes: ClosedCafes::Folder,
+            checkpoint_every: 100,
+            geocoder_concurrency: 8,
+            google_max_retries: 5,
+            google_queries_per_second: 10,
+            google_places_url: GOOGLE_PLACES_URL.to_string(),
+            google_place_fields: vec![google_places::BUSINESS_STATUS_FIELD.to_string()],
+            geocoder: GeocoderKind::Google,
+            geocoder_url: None,
+            geocoder_queries_per_second: 1,
+            geocoder_max_retries: 3,
+            gazetteer_file: None,
+            match_review_threshold: 0.6,
+            selectors: SelectorProfile::default(),
+        }
+    }
+}
+
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum PipelineError {
     GoogleHTTPError(String),
-    GooglePlaceNotFoundError(String),
+    PlaceNotFoundError(String),
+    /// Every place the geocoder found is outside the country of the cafe's address.
+    PlaceOutsideCountryError(String),
     GoogleJsonParseError(String),
+    GeocoderHTTPError(String),
+    GeocoderParseError(String),
     KatanaJsonParseError(serde_json::Error),
     KatanaEndpointParseError(Value),
     KatanaIOError(io::Error),
+    KatanaStderr(String),
+    KatanaExitStatus(ExitStatus),
+    CafeListParseError(String),
+    CrawlerHTTPError(String),
+    SitemapParseError(String),
+    /// A cafe page none of the configured selectors could read a name from.
+    ECTPageLayoutUnknown(String),
+    /// A page already yielded earlier in the crawl under another spelling of its URL.
+    DuplicateEndpoint(String),
 }
 
 #[derive(Debug)]
@@ -29,12 +164,133 @@ pub enum IOError {
     KMLFileCreation(io::Error),
     CreateMissingDirectories(io::Error),
     KMLWriteError(kml::Error),
+    ManifestWrite(io::Error),
+    LatestRunLink(io::Error),
+    ReplayFileOpen(io::Error),
+    KatanaArchiveCreate(io::Error),
+    CafeListOpen(io::Error),
+    KatanaMissing,
+    KatanaSpawn(io::Error),
+    SignalHandler(ctrlc::Error),
+    SeenEndpointsWrite(io::Error),
+    CheckpointWrite(io::Error),
+    AsyncRuntime(io::Error),
+    HttpClientBuild(reqwest::Error),
+    GoogleApiKeyMissing,
+    /// `--only-new` or `--stale-after-days` with a crawler that walks the whole site regardless.
+    IncrementalCrawlUnsupported(CrawlerKind),
+    GazetteerRead(String),
+    CacheRead(String),
+    GeocodeFailed(PipelineError),
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::GoogleHTTPError(err) => write!(f, "google places request failed: {}", err),
+            Self::PlaceNotFoundError(searchterm) => {
+                write!(f, "no place found for: {}", searchterm)
+            }
+            Self::PlaceOutsideCountryError(searchterm) => {
+                write!(f, "every place found is outside {}", searchterm)
+            }
+            Self::GoogleJsonParseError(err) => {
+                write!(f, "could not parse google places response: {}", err)
+            }
+            Self::GeocoderHTTPError(err) => write!(f, "geocoder request failed: {}", err),
+            Self::GeocoderParseError(err) => {
+                write!(f, "could not parse geocoder response: {}", err)
+            }
+            Self::KatanaJsonParseError(err) => write!(f, "could not parse katana output: {}", err),
+            Self::KatanaEndpointParseError(json) => {
+                write!(f, "katana output has no valid endpoint: {}", json)
+            }
+            Self::KatanaIOError(err) => write!(f, "could not read katana output: {}", err),
+            Self::KatanaStderr(line) => write!(f, "katana: {}", line),
+            Self::KatanaExitStatus(status) => write!(f, "katana exited with {}", status),
+            Self::CafeListParseError(err) => write!(f, "invalid cafe list row: {}", err),
+            Self::CrawlerHTTPError(err) => write!(f, "crawler request failed: {}", err),
+            Self::SitemapParseError(err) => write!(f, "could not parse sitemap: {}", err),
+            Self::ECTPageLayoutUnknown(url) => {
+                write!(f, "cafe page matched no known layout: {}", url)
+            }
+            Self::DuplicateEndpoint(url) => write!(f, "page already crawled: {}", url),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ReadFile(path, err) => write!(f, "could not read config file {}: {}", path, err),
+            Self::ParseFile(path, err) => write!(f, "invalid config file {}: {}", path, err),
+            Self::InvalidValue(field, reason) => write!(f, "invalid {}: {}", field, reason),
+            Self::Environment(name, reason) => write!(f, "invalid {}: {}", name, reason),
+        }
+    }
+}
+
+impl fmt::Display for IOError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SuperConsoleNotTTY => write!(f, "the terminal gui requires a TTY"),
+            Self::KMLFileCreation(err) => write!(f, "could not create kml file: {}", err),
+            Self::CreateMissingDirectories(err) => {
+                write!(f, "could not create output directories: {}", err)
+            }
+            Self::KMLWriteError(err) => write!(f, "could not write kml file: {}", err),
+            Self::ManifestWrite(err) => write!(f, "could not write run manifest: {}", err),
+            Self::LatestRunLink(err) => write!(f, "could not update latest run link: {}", err),
+            Self::ReplayFileOpen(err) => write!(f, "could not open katana recording: {}", err),
+            Self::KatanaArchiveCreate(err) => {
+                write!(f, "could not create katana output archive: {}", err)
+            }
+            Self::CafeListOpen(err) => write!(f, "could not open cafe list: {}", err),
+            Self::KatanaMissing => write!(
+                f,
+                "katana was not found on PATH; run inside `nix develop` or use --crawler native"
+            ),
+            Self::KatanaSpawn(err) => write!(f, "could not start katana: {}", err),
+            Self::SignalHandler(err) => write!(f, "could not install the signal handler: {}", err),
+            Self::SeenEndpointsWrite(err) => {
+                write!(f, "could not write the record of processed pages: {}", err)
+            }
+            Self::CheckpointWrite(err) => {
+                write!(f, "could not write the crawl checkpoint: {}", err)
+            }
+            Self::AsyncRuntime(err) => write!(f, "could not start the async runtime: {}", err),
+            Self::HttpClientBuild(err) => write!(f, "could not build the http client: {}", err),
+            Self::GoogleApiKeyMissing => write!(
+                f,
+                "the google geocoder needs --google-api-key or GOOGLE_PLACES_API_KEY"
+            ),
+            Self::IncrementalCrawlUnsupported(crawler) => write!(
+                f,
+                "--only-new and --stale-after-days need --crawler sitemap, --replay or --cafe-list; the {} crawler downloads every page anyway",
+                format!("{:?}", crawler).to_lowercase()
+            ),
+            Self::GazetteerRead(err) => write!(f, "could not read the gazetteer: {}", err),
+            Self::CacheRead(err) => write!(f, "could not read the placemark cache: {}", err),
+            Self::GeocodeFailed(err) => write!(f, "{}", err),
+        }
+    }
 }
 
-#[derive(Clone)]
+#[derive(Debug)]
+pub enum ConfigError {
+    ReadFile(String, io::Error),
+    ParseFile(String, toml::de::Error),
+    InvalidValue(&'static str, String),
+    Environment(String, String),
+}
+
+#[derive(Clone, Debug)]
 pub enum SearchTerm {
     UrlFragment(String),
     CafeDetails(String),
+    /// Search term of a cached placemark found from the same ECT page, used
+    /// when the page itself was not downloaded.
+    KnownEndpoint(String),
 }
 
 impl SearchTerm {
@@ -42,51 +298,120 @@ impl SearchTerm {
         match self {
             SearchTerm::UrlFragment(str) => str,
             SearchTerm::CafeDetails(str) => str,
+            SearchTerm::KnownEndpoint(str) => str,
         }
     }
 }
 
+#[allow(clippy::enum_variant_names)]
 pub enum PlacemarkComputation {
     FromCache(SearchTerm, Placemark),
-    FromGoogleQuery(SearchTerm, Placemark),
+    FromGeocoder(SearchTerm, Placemark),
+    /// Placemark built from the coordinates on the cafe's ECT page, without a Google query.
+    FromECTCoordinates(SearchTerm, Placemark),
 }
 
 impl PlacemarkComputation {
-    pub fn to_placemark(self) -> Placemark {
+    pub fn into_placemark(self) -> Placemark {
+        match self {
+            Self::FromCache(_, placemark) => placemark,
+            Self::FromGeocoder(_, placemark) => placemark,
+            Self::FromECTCoordinates(_, placemark) => placemark,
+        }
+    }
+
+    pub fn get_placemark(&self) -> &Placemark {
         match self {
             Self::FromCache(_, placemark) => placemark,
-            Self::FromGoogleQuery(_, placemark) => placemark,
+            Self::FromGeocoder(_, placemark) => placemark,
+            Self::FromECTCoordinates(_, placemark) => placemark,
         }
     }
 
-    fn get_placemark(&self) -> &Placemark {
+    fn get_placemark_mut(&mut self) -> &mut Placemark {
         match self {
-            Self::FromCache(_, placemark) => &placemark,
-            Self::FromGoogleQuery(_, placemark) => &placemark,
+            Self::FromCache(_, placemark) => placemark,
+            Self::FromGeocoder(_, placemark) => placemark,
+            Self::FromECTCoordinates(_, placemark) => placemark,
         }
     }
 
+    pub fn with_ect_url(mut self, ect_url: &Url) -> Self {
+        self.get_placemark_mut()
+            .attrs
+            .insert(ECT_URL_ATTR.to_string(), ect_url.to_string());
+
+        self
+    }
+
+    /// Replaces the placemark's ECT details with the ones from a freshly parsed
+    /// page, and records whether the page says it is closed. Details already on
+    /// the placemark are kept when the page had none.
+    pub fn with_ect_details(mut self, details: &ECTCafeDetails) -> Self {
+        cafe_status::record_page_status(self.get_placemark_mut(), details.closed);
+
+        if let Some(mut extended_data) = details.to_extended_data() {
+            let children = &mut self.get_placemark_mut().children;
+            // The geocoder's details stay, the page's replace those of earlier crawls.
+            let google_data = children
+                .iter()
+                .filter(|child| child.name == EXTENDED_DATA_ELEMENT)
+                .flat_map(|child| child.children.iter())
+                .filter(|data| {
+                    data.attrs
+                        .get("name")
+                        .is_some_and(|name| name.starts_with(GOOGLE_DATA_PREFIX))
+                })
+                .cloned()
+                .collect::<Vec<Element>>();
+            extended_data.children.extend(google_data);
+
+            children.retain(|child| child.name != EXTENDED_DATA_ELEMENT);
+            children.push(extended_data);
+        }
+
+        self
+    }
+
     pub fn get_id(&self) -> Option<&String> {
         self.get_placemark().attrs.get("id")
     }
 
+    /// The Google place id, or for placemarks that never went through Google
+    /// the ECT page they were built from.
+    fn dedup_key(&self) -> Option<&String> {
+        self.get_id()
+            .or_else(|| self.get_placemark().attrs.get(ECT_URL_ATTR))
+    }
+
+    /// The ECT page this placemark was computed from, unless it was only
+    /// looked up in the cache by that page's URL without processing the page.
+    pub fn processed_endpoint(&self) -> Option<Url> {
+        if let Self::FromCache(SearchTerm::KnownEndpoint(_), _) = self {
+            return None;
+        }
+
+        Url::parse(self.get_placemark().attrs.get(ECT_URL_ATTR)?).ok()
+    }
+
     pub fn get_search_term(&self) -> &SearchTerm {
         match &self {
             Self::FromCache(searchterm, _) => searchterm,
-            Self::FromGoogleQuery(searchterm, _) => searchterm,
+            Self::FromGeocoder(searchterm, _) => searchterm,
+            Self::FromECTCoordinates(searchterm, _) => searchterm,
         }
     }
 }
 
 impl Hash for PlacemarkComputation {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.get_id().hash(state);
+        self.dedup_key().hash(state);
     }
 }
 
 impl PartialEq for PlacemarkComputation {
     fn eq(&self, other: &Self) -> bool {
-        self.get_id() == other.get_id()
+        self.dedup_key() == other.dedup_key()
     }
 }
 
@@ -0,0 +1,325 @@
+//! Geocoding with OpenStreetMap data through a Nominatim or Photon server.
+
+use futures::future::{BoxFuture, FutureExt};
+use serde::Deserialize;
+
+use crate::backoff::RetryPolicy;
+use crate::geocoder::{GeocodeCandidate, GeocodeQuery, Geocoder, PlaceDetails};
+use crate::model::{CoffeeMapConfig, IOError, PipelineError};
+use crate::retrying_client::RetryingClient;
+
+const NOMINATIM_URL: &str = "https://nominatim.openstreetmap.org/search";
+const PHOTON_URL: &str = "https://photon.komoot.io/api";
+const CANDIDATE_LIMIT: &str = "5";
+
+/// The API spoken by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OsmFlavour {
+    Nominatim,
+    Photon,
+}
+
+/// Geocoder backed by a Nominatim or Photon server, the public one unless
+/// `geocoder_url` names another.
+pub struct OsmGeocoder {
+    client: RetryingClient,
+    url: String,
+    flavour: OsmFlavour,
+}
+
+#[derive(Deserialize)]
+struct NominatimPlace {
+    osm_type: String,
+    osm_id: u64,
+    lat: String,
+    lon: String,
+    display_name: String,
+    name: Option<String>,
+    /// Only there when asked for with `addressdetails`.
+    address: Option<NominatimAddress>,
+    category: Option<String>,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NominatimAddress {
+    /// Lowercase, e.g. `de`.
+    country_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PhotonResponse {
+    features: Vec<PhotonFeature>,
+}
+
+#[derive(Deserialize)]
+struct PhotonFeature {
+    geometry: PhotonGeometry,
+    properties: PhotonProperties,
+}
+
+#[derive(Deserialize)]
+struct PhotonGeometry {
+    /// Longitude then latitude.
+    coordinates: (f64, f64),
+}
+
+#[derive(Deserialize)]
+struct PhotonProperties {
+    osm_type: Option<String>,
+    osm_id: Option<u64>,
+    osm_value: Option<String>,
+    name: Option<String>,
+    street: Option<String>,
+    housenumber: Option<String>,
+    postcode: Option<String>,
+    city: Option<String>,
+    country: Option<String>,
+    countrycode: Option<String>,
+}
+
+impl OsmGeocoder {
+    pub fn new(config: &CoffeeMapConfig, flavour: OsmFlavour) -> Result<Self, IOError> {
+        let default_url = match flavour {
+            OsmFlavour::Nominatim => NOMINATIM_URL,
+            OsmFlavour::Photon => PHOTON_URL,
+        };
+
+        Self::with_url(
+            config
+                .geocoder_url
+                .clone()
+                .unwrap_or(default_url.to_string()),
+            flavour,
+            RetryPolicy::new(config.geocoder_max_retries),
+            config.geocoder_queries_per_second,
+        )
+    }
+
+    fn with_url(
+        url: String,
+        flavour: OsmFlavour,
+        retry_policy: RetryPolicy,
+        requests_per_second: u32,
+    ) -> Result<Self, IOError> {
+        Ok(OsmGeocoder {
+            client: RetryingClient::new(retry_policy, requests_per_second)?,
+            url,
+            flavour,
+        })
+    }
+
+    /// Nominatim is limited to the query's country, and photon prefers
+    /// places near its city and limited to the country's bounding box.
+    async fn search(&self, query: &GeocodeQuery) -> Result<Vec<GeocodeCandidate>, PipelineError> {
+        let search_term = query.search_term.as_str();
+        let body = self
+            .client
+            .send(|client| {
+                let request = client
+                    .get(self.url.as_str())
+                    .query(&[("q", search_term), ("limit", CANDIDATE_LIMIT)]);
+
+                match (self.flavour, &query.region) {
+                    (OsmFlavour::Nominatim, None) => {
+                        request.query(&[("format", "jsonv2"), ("addressdetails", "1")])
+                    }
+                    (OsmFlavour::Nominatim, Some(region)) => request.query(&[
+                        ("format", "jsonv2".to_string()),
+                        ("addressdetails", "1".to_string()),
+                        ("countrycodes", region.country.code.to_lowercase()),
+                    ]),
+                    (OsmFlavour::Photon, None) => request,
+                    (OsmFlavour::Photon, Some(region)) => {
+                        let (latitude, longitude) = region.center();
+                        let bounds = region.country.bounds;
+
+                        request.query(&[
+                            ("lat", latitude.to_string()),
+                            ("lon", longitude.to_string()),
+                            (
+                                "bbox",
+                                format!(
+                                    "{},{},{},{}",
+                                    bounds.west, bounds.south, bounds.east, bounds.north
+                                ),
+                            ),
+                        ])
+                    }
+                }
+            })
+            .await
+            .map_err(|err| {
+                PipelineError::GeocoderHTTPError(format!("search_term: {}, {}", search_term, err))
+            })?;
+
+        match self.flavour {
+            OsmFlavour::Nominatim => parse_nominatim_response(body.as_str()),
+            OsmFlavour::Photon => parse_photon_response(body.as_str()),
+        }
+    }
+}
+
+impl Geocoder for OsmGeocoder {
+    fn name(&self) -> &'static str {
+        match self.flavour {
+            OsmFlavour::Nominatim => "nominatim",
+            OsmFlavour::Photon => "photon",
+        }
+    }
+
+    fn geocode<'a>(
+        &'a self,
+        query: &'a GeocodeQuery,
+    ) -> BoxFuture<'a, Result<Vec<GeocodeCandidate>, PipelineError>> {
+        self.search(query).boxed()
+    }
+
+    fn retries(&self) -> usize {
+        self.client.retries()
+    }
+}
+
+/// OpenStreetMap element ids only identify an element together with its type.
+fn osm_id(osm_type: &str, osm_id: u64) -> String {
+    let osm_type = match osm_type {
+        "N" => "node",
+        "W" => "way",
+        "R" => "relation",
+        osm_type => osm_type,
+    };
+
+    format!("osm:{}/{}", osm_type, osm_id)
+}
+
+fn parse_nominatim_response(body: &str) -> Result<Vec<GeocodeCandidate>, PipelineError> {
+    let places = serde_json::from_str::<Vec<NominatimPlace>>(body)
+        .map_err(|err| PipelineError::GeocoderParseError(err.to_string()))?;
+
+    places
+        .into_iter()
+        .map(|place| {
+            let parse_coordinate = |coordinate: &str| {
+                coordinate
+                    .parse::<f64>()
+                    .map_err(|err| PipelineError::GeocoderParseError(err.to_string()))
+            };
+            let name = place
+                .name
+                .filter(|name| !name.is_empty())
+                .or_else(|| place.display_name.split(',').next().map(str::to_string))
+                .unwrap_or_default();
+
+            Ok(GeocodeCandidate {
+                id: Some(osm_id(&place.osm_type, place.osm_id)),
+                name,
+                latitude: parse_coordinate(&place.lat)?,
+                longitude: parse_coordinate(&place.lon)?,
+                country_code: place.address.and_then(|address| address.country_code),
+                address: place.display_name,
+                url: None,
+                categories: place.category.into_iter().chain(place.kind).collect(),
+                business_status: None,
+                details: PlaceDetails::default(),
+            })
+        })
+        .collect()
+}
+
+fn parse_photon_response(body: &str) -> Result<Vec<GeocodeCandidate>, PipelineError> {
+    let response = serde_json::from_str::<PhotonResponse>(body)
+        .map_err(|err| PipelineError::GeocoderParseError(err.to_string()))?;
+
+    Ok(response
+        .features
+        .into_iter()
+        .map(|feature| {
+            let properties = feature.properties;
+            let (longitude, latitude) = feature.geometry.coordinates;
+            let join = |parts: Vec<Option<String>>| {
+                parts
+                    .into_iter()
+                    .flatten()
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            };
+            let address = [
+                join(vec![properties.street, properties.housenumber]),
+                join(vec![properties.postcode, properties.city]),
+                properties.country.unwrap_or_default(),
+            ]
+            .into_iter()
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<String>>()
+            .join(", ");
+
+            GeocodeCandidate {
+                id: properties
+                    .osm_type
+                    .zip(properties.osm_id)
+                    .map(|(osm_type, id)| osm_id(&osm_type, id)),
+                name: properties.name.unwrap_or_default(),
+                address,
+                latitude,
+                longitude,
+                country_code: properties.countrycode,
+                url: None,
+                categories: properties.osm_value.into_iter().collect(),
+                business_status: None,
+                details: PlaceDetails::default(),
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_server::{TestResponse, TestServer};
+
+    const NOMINATIM_BODY: &str = r#"[{"place_id": 1, "osm_type": "node", "osm_id": 42, "lat": "52.5200", "lon": "13.4050", "display_name": "Test Cafe, Street 1, Berlin, Germany", "name": "Test Cafe", "address": {"country_code": "de"}, "category": "amenity", "type": "cafe"}]"#;
+    const PHOTON_BODY: &str = r#"{"type": "FeatureCollection", "features": [{"type": "Feature", "geometry": {"type": "Point", "coordinates": [13.405, 52.52]}, "properties": {"osm_type": "N", "osm_id": 42, "osm_key": "amenity", "osm_value": "cafe", "name": "Test Cafe", "street": "Street", "housenumber": "1", "postcode": "10115", "city": "Berlin", "country": "Germany", "countrycode": "DE"}}]}"#;
+
+    async fn geocode(flavour: OsmFlavour, body: &'static str) -> GeocodeCandidate {
+        let server = TestServer::with_handler(move |_| TestResponse::ok(body.to_string()));
+        let retry_policy = RetryPolicy {
+            max_retries: 0,
+            base_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+        };
+        let geocoder = OsmGeocoder::with_url(
+            server.url("/search").to_string(),
+            flavour,
+            retry_policy,
+            1000,
+        )
+        .unwrap();
+
+        geocoder
+            .geocode(&GeocodeQuery::new("Test Cafe Berlin".to_string(), None))
+            .await
+            .unwrap()
+            .remove(0)
+    }
+
+    #[tokio::test]
+    async fn reads_nominatim_and_photon_results_alike() {
+        let nominatim = geocode(OsmFlavour::Nominatim, NOMINATIM_BODY).await;
+        let photon = geocode(OsmFlavour::Photon, PHOTON_BODY).await;
+
+        assert_eq!(nominatim.id.as_deref(), Some("osm:node/42"));
+        assert_eq!(photon.id, nominatim.id);
+        assert_eq!(photon.name, "Test Cafe");
+        assert_eq!(nominatim.name, "Test Cafe");
+        assert_eq!(photon.address, "Street 1, 10115 Berlin, Germany");
+        assert_eq!((photon.latitude, photon.longitude), (52.52, 13.405));
+        assert_eq!((nominatim.latitude, nominatim.longitude), (52.52, 13.405));
+        assert!(nominatim.categories.contains(&"cafe".to_string()));
+        assert_eq!(photon.categories, vec!["cafe"]);
+        assert_eq!(nominatim.country_code.as_deref(), Some("de"));
+        assert_eq!(photon.country_code.as_deref(), Some("DE"));
+    }
+}
@@ -0,0 +1,327 @@
+//! HTTP client shared by the crawlers that download pages from europeancoffeetrip.com.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use reqwest::blocking;
+use url::Url;
+
+use crate::model::{IOError, PipelineError};
+
+pub const USER_AGENT: &str = concat!(
+    "coffee_map/",
+    env!("CARGO_PKG_VERSION"),
+    " (+https://github.com/oscardarwin/coffee_map)"
+);
+
+/// Blocking client that spaces requests to stay under a requests-per-second
+/// limit and only fetches paths the site's robots.txt allows.
+pub struct PoliteClient {
+    client: blocking::Client,
+    request_interval: Duration,
+    last_request: Option<Instant>,
+    robots: RobotsRules,
+}
+
+impl PoliteClient {
+    /// Reads the robots.txt of the site `site_url` belongs to.
+    pub fn new(site_url: &Url, requests_per_second: u8) -> Result<Self, IOError> {
+        let client = blocking::Client::builder()
+            .user_agent(USER_AGENT)
+            .build()
+            .map_err(IOError::HttpClientBuild)?;
+
+        let robots = fetch_robots_rules(&client, site_url);
+
+        let request_interval = Duration::from_secs_f64(1.0 / f64::from(requests_per_second.max(1)))
+            .max(robots.crawl_delay.unwrap_or_default());
+
+        Ok(PoliteClient {
+            client,
+            request_interval,
+            last_request: None,
+            robots,
+        })
+    }
+
+    pub fn is_allowed(&self, url: &Url) -> bool {
+        self.robots.is_allowed(url.path())
+    }
+
+    /// Sitemaps advertised by the site's robots.txt.
+    pub fn sitemaps(&self) -> &[String] {
+        &self.robots.sitemaps
+    }
+
+    pub fn fetch_text(&mut self, url: &Url) -> Result<String, PipelineError> {
+        self.fetch(url)
+            .map(|bytes| String::from_utf8_lossy(&bytes).to_string())
+    }
+
+    pub fn fetch(&mut self, url: &Url) -> Result<Vec<u8>, PipelineError> {
+        self.throttle();
+
+        let response = self
+            .client
+            .get(url.clone())
+            .send()
+            .map_err(|err| PipelineError::CrawlerHTTPError(format!("{}: {}", url, err)))?;
+
+        if !response.status().is_success() {
+            return Err(PipelineError::CrawlerHTTPError(format!(
+                "{}: response code {}",
+                url,
+                response.status()
+            )));
+        }
+
+        response
+            .bytes()
+            .map(|bytes| bytes.to_vec())
+            .map_err(|err| PipelineError::CrawlerHTTPError(format!("{}: {}", url, err)))
+    }
+
+    fn throttle(&mut self) {
+        if let Some(last_request) = self.last_request {
+            let elapsed = last_request.elapsed();
+            if elapsed < self.request_interval {
+                thread::sleep(self.request_interval - elapsed);
+            }
+        }
+
+        self.last_request = Some(Instant::now());
+    }
+}
+
+/// As in RFC 9309, a missing robots.txt (a 4xx) allows every path, while one
+/// that cannot be read (a 5xx or no response) disallows every path.
+fn fetch_robots_rules(client: &blocking::Client, site_url: &Url) -> RobotsRules {
+    let Ok(robots_url) = site_url.join("/robots.txt") else {
+        return RobotsRules::default();
+    };
+
+    let robots_txt = client
+        .get(robots_url.clone())
+        .send()
+        .map_err(|err| err.to_string())
+        .and_then(|response| {
+            let status = response.status();
+            if status.is_client_error() {
+                Ok(None)
+            } else if status.is_success() {
+                response.text().map(Some).map_err(|err| err.to_string())
+            } else {
+                Err(format!("response code {}", status))
+            }
+        });
+
+    match robots_txt {
+        Ok(Some(robots_txt)) => RobotsRules::parse(robots_txt.as_str()),
+        Ok(None) => RobotsRules::default(),
+        Err(err) => {
+            println!("{}: {}; not crawling any page of the site", robots_url, err);
+            RobotsRules::disallow_all()
+        }
+    }
+}
+
+/// The `User-agent: *` rules of a robots.txt file.
+#[derive(Default, Debug)]
+struct RobotsRules {
+    allow: Vec<String>,
+    disallow: Vec<String>,
+    crawl_delay: Option<Duration>,
+    sitemaps: Vec<String>,
+}
+
+impl RobotsRules {
+    fn disallow_all() -> Self {
+        RobotsRules {
+            disallow: vec!["/".to_string()],
+            ..RobotsRules::default()
+        }
+    }
+
+    fn parse(robots_txt: &str) -> Self {
+        let mut rules = RobotsRules::default();
+        let mut in_wildcard_group = false;
+        let mut previous_line_was_user_agent = false;
+
+        for line in robots_txt.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let key = key.trim().to_lowercase();
+            let value = value.trim();
+
+            // Sitemap lines apply to every user agent.
+            if key == "sitemap" {
+                rules.sitemaps.push(value.to_string());
+                continue;
+            }
+
+            if key == "user-agent" {
+                // Consecutive user-agent lines share the group that follows them.
+                let is_wildcard = value == "*";
+                in_wildcard_group = if previous_line_was_user_agent {
+                    in_wildcard_group || is_wildcard
+                } else {
+                    is_wildcard
+                };
+                previous_line_was_user_agent = true;
+                continue;
+            }
+            previous_line_was_user_agent = false;
+
+            if !in_wildcard_group {
+                continue;
+            }
+
+            match key.as_str() {
+                "allow" if !value.is_empty() => rules.allow.push(value.to_string()),
+                "disallow" if !value.is_empty() => rules.disallow.push(value.to_string()),
+                "crawl-delay" => {
+                    rules.crawl_delay = value
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+                        .map(Duration::from_secs_f64)
+                }
+                _ => {}
+            }
+        }
+
+        rules
+    }
+
+    /// The longest matching rule wins and allow wins ties, as in RFC 9309.
+    fn is_allowed(&self, path: &str) -> bool {
+        let longest_match = |patterns: &Vec<String>| {
+            patterns
+                .iter()
+                .filter(|pattern| pattern_matches(pattern, path))
+                .map(String::len)
+                .max()
+        };
+
+        match (longest_match(&self.allow), longest_match(&self.disallow)) {
+            (_, None) => true,
+            (None, Some(_)) => false,
+            (Some(allow), Some(disallow)) => allow >= disallow,
+        }
+    }
+}
+
+/// Whether `pattern` matches the start of `path`, where `*` stands for any
+/// run of characters and a trailing `$` for the end of the path.
+fn pattern_matches(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+
+    let mut pieces = pattern.split('*');
+    let Some(mut rest) = pieces.next().and_then(|prefix| path.strip_prefix(prefix)) else {
+        return false;
+    };
+
+    let pieces = pieces.collect::<Vec<&str>>();
+    let Some((last, middle)) = pieces.split_last() else {
+        return !anchored || rest.is_empty();
+    };
+
+    // Taking the earliest match of each piece leaves the most room for the rest.
+    for piece in middle {
+        match rest.find(piece) {
+            Some(index) => rest = &rest[index + piece.len()..],
+            None => return false,
+        }
+    }
+
+    if anchored {
+        rest.ends_with(last)
+    } else {
+        rest.contains(last)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{TestResponse, TestServer};
+
+    #[test]
+    fn robots_rules_prefer_the_longest_match() {
+        let rules = RobotsRules::parse(
+            "User-agent: other\nDisallow: /\n\nUser-agent: *\nDisallow: /cafe/\nAllow: /cafe/open/\n",
+        );
+
+        assert!(rules.is_allowed("/about/"));
+        assert!(!rules.is_allowed("/cafe/closed/"));
+        assert!(rules.is_allowed("/cafe/open/page/"));
+    }
+
+    #[test]
+    fn robots_rules_match_wildcards_and_end_anchors() {
+        let rules = RobotsRules::parse(
+            "User-agent: *\nDisallow: /*?replytocom=\nDisallow: /*.pdf$\nDisallow: /cafe/*/menu\nAllow: /cafe/*/menu/$\n",
+        );
+
+        assert!(rules.is_allowed("/cafe/alpha/"));
+        assert!(!rules.is_allowed("/cafe/alpha/?replytocom=12"));
+        assert!(!rules.is_allowed("/guides/berlin.pdf"));
+        assert!(rules.is_allowed("/guides/berlin.pdf?page=2"));
+        assert!(!rules.is_allowed("/cafe/alpha/menu/drinks/"));
+        assert!(rules.is_allowed("/cafe/alpha/menu/"));
+    }
+
+    #[test]
+    fn robots_rules_collect_sitemaps_outside_groups() {
+        let rules = RobotsRules::parse(
+            "User-agent: other\nDisallow: /\nSitemap: https://example.com/sitemap_index.xml\n",
+        );
+
+        assert!(rules.is_allowed("/cafe/"));
+        assert_eq!(
+            rules.sitemaps,
+            vec!["https://example.com/sitemap_index.xml"]
+        );
+    }
+
+    fn client_for_robots_status(status: u16) -> (TestServer, PoliteClient) {
+        let server = TestServer::with_handler(move |request| match request.path.as_str() {
+            "/robots.txt" => TestResponse::status(status),
+            _ => TestResponse::ok(String::new()),
+        });
+        let client = PoliteClient::new(&server.url("/"), 255).unwrap();
+
+        (server, client)
+    }
+
+    #[test]
+    fn a_missing_robots_txt_allows_every_page() {
+        let (server, client) = client_for_robots_status(404);
+
+        assert!(client.is_allowed(&server.url("/cafe/alpha/")));
+    }
+
+    #[test]
+    fn an_unavailable_robots_txt_disallows_every_page() {
+        let (server, client) = client_for_robots_status(503);
+
+        assert!(!client.is_allowed(&server.url("/cafe/alpha/")));
+    }
+
+    #[test]
+    fn an_unreachable_site_disallows_every_page() {
+        let unreachable = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap()
+        };
+
+        let client = PoliteClient::new(&unreachable, 255).unwrap();
+
+        assert!(!client.is_allowed(&unreachable.join("/cafe/alpha/").unwrap()));
+    }
+}
@@ -0,0 +1,490 @@
+//! The country, and where known the city, a cafe is in, read from the end of
+//! its ECT address, e.g. `Nørrebrogade 12, 2200 Copenhagen, Denmark`.
+//!
+//! Geocoders use it to prefer places nearby, and places found outside the
+//! country are rejected.
+
+use crate::geocoder::GeocodeCandidate;
+
+/// Radius around a city within which places are preferred.
+pub const CITY_RADIUS_METERS: f64 = 25_000.0;
+/// Leeway, in degrees, given to places just outside a country's bounding box.
+const BOUNDS_MARGIN: f64 = 0.1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    pub south: f64,
+    pub west: f64,
+    pub north: f64,
+    pub east: f64,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Country {
+    /// ISO 3166-1 alpha-2 code, e.g. `DK`.
+    pub code: &'static str,
+    /// The English name first, then the names the country goes by locally.
+    names: &'static [&'static str],
+    pub bounds: Bounds,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct City {
+    country_code: &'static str,
+    names: &'static [&'static str],
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub country: &'static Country,
+    pub city: Option<&'static City>,
+}
+
+impl Region {
+    /// The region named by the last parts of `address`, if its country is known.
+    pub fn from_address(address: &str) -> Option<Region> {
+        let mut parts = address.rsplit(',').map(place_name);
+        let country_name = parts.next()?;
+        let country = COUNTRIES.iter().find(|country| {
+            country
+                .names
+                .iter()
+                .any(|name| name.to_lowercase() == country_name)
+        })?;
+
+        let city = parts.find_map(|part| {
+            CITIES.iter().find(|city| {
+                city.country_code == country.code
+                    && city.names.iter().any(|name| name.to_lowercase() == part)
+            })
+        });
+
+        Some(Region { country, city })
+    }
+
+    /// Whether a place at these coordinates can be in the region's country.
+    pub fn contains(&self, latitude: f64, longitude: f64) -> bool {
+        let bounds = self.country.bounds;
+
+        (bounds.south - BOUNDS_MARGIN..=bounds.north + BOUNDS_MARGIN).contains(&latitude)
+            && (bounds.west - BOUNDS_MARGIN..=bounds.east + BOUNDS_MARGIN).contains(&longitude)
+    }
+
+    /// Whether the candidate is in the region's country, by the country the
+    /// geocoder gives for it or, failing that, by its coordinates.
+    pub fn holds(&self, candidate: &GeocodeCandidate) -> bool {
+        match &candidate.country_code {
+            Some(code) => code.eq_ignore_ascii_case(self.country.code),
+            None => self.contains(candidate.latitude, candidate.longitude),
+        }
+    }
+
+    /// The candidates in the region's country.
+    pub fn within(&self, candidates: Vec<GeocodeCandidate>) -> Vec<GeocodeCandidate> {
+        candidates
+            .into_iter()
+            .filter(|candidate| self.holds(candidate))
+            .collect()
+    }
+
+    /// The middle of the city, or otherwise of the country's bounding box.
+    pub fn center(&self) -> (f64, f64) {
+        match self.city {
+            Some(city) => (city.latitude, city.longitude),
+            None => {
+                let bounds = self.country.bounds;
+
+                (
+                    (bounds.south + bounds.north) / 2.0,
+                    (bounds.west + bounds.east) / 2.0,
+                )
+            }
+        }
+    }
+}
+
+/// Code of the country named at the end of `address`, if it is a known one.
+pub fn country_code_in(address: &str) -> Option<String> {
+    Region::from_address(address).map(|region| region.country.code.to_string())
+}
+
+impl Country {
+    pub fn name(&self) -> &'static str {
+        self.names[0]
+    }
+}
+
+/// Lowercase `part` of an address without postcodes or house numbers.
+fn place_name(part: &str) -> String {
+    part.split_whitespace()
+        .filter(|word| !word.chars().any(|c| c.is_ascii_digit()))
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+const fn bounds(west: f64, south: f64, east: f64, north: f64) -> Bounds {
+    Bounds {
+        south,
+        west,
+        north,
+        east,
+    }
+}
+
+const fn country(code: &'static str, names: &'static [&'static str], bounds: Bounds) -> Country {
+    Country {
+        code,
+        names,
+        bounds,
+    }
+}
+
+const fn city(
+    country_code: &'static str,
+    names: &'static [&'static str],
+    latitude: f64,
+    longitude: f64,
+) -> City {
+    City {
+        country_code,
+        names,
+        latitude,
+        longitude,
+    }
+}
+
+/// Countries with cafes on ECT and their bounding boxes, islands included.
+static COUNTRIES: [Country; 46] = [
+    country("AD", &["Andorra"], bounds(1.41, 42.43, 1.79, 42.66)),
+    country(
+        "AL",
+        &["Albania", "Shqipëri", "Shqipëria"],
+        bounds(19.26, 39.62, 21.06, 42.69),
+    ),
+    country(
+        "AM",
+        &["Armenia", "Հայաստան"],
+        bounds(43.58, 38.74, 46.63, 41.30),
+    ),
+    country(
+        "AT",
+        &["Austria", "Österreich"],
+        bounds(9.48, 46.37, 17.16, 49.02),
+    ),
+    country(
+        "BA",
+        &["Bosnia and Herzegovina", "Bosna i Hercegovina"],
+        bounds(15.72, 42.55, 19.62, 45.28),
+    ),
+    country(
+        "BE",
+        &["Belgium", "België", "Belgique", "Belgien"],
+        bounds(2.54, 49.49, 6.41, 51.51),
+    ),
+    country(
+        "BG",
+        &["Bulgaria", "България"],
+        bounds(22.36, 41.23, 28.61, 44.22),
+    ),
+    country(
+        "BY",
+        &["Belarus", "Беларусь"],
+        bounds(23.18, 51.26, 32.78, 56.17),
+    ),
+    country(
+        "CH",
+        &["Switzerland", "Schweiz", "Suisse", "Svizzera"],
+        bounds(5.96, 45.82, 10.49, 47.81),
+    ),
+    country(
+        "CY",
+        &["Cyprus", "Κύπρος", "Kıbrıs"],
+        bounds(32.27, 34.56, 34.60, 35.70),
+    ),
+    country(
+        "CZ",
+        &["Czech Republic", "Czechia", "Česko", "Česká republika"],
+        bounds(12.09, 48.55, 18.86, 51.06),
+    ),
+    country(
+        "DE",
+        &["Germany", "Deutschland"],
+        bounds(5.87, 47.27, 15.04, 55.06),
+    ),
+    country(
+        "DK",
+        &["Denmark", "Danmark"],
+        bounds(8.07, 54.56, 15.20, 57.75),
+    ),
+    country(
+        "EE",
+        &["Estonia", "Eesti"],
+        bounds(21.76, 57.51, 28.21, 59.68),
+    ),
+    country(
+        "ES",
+        &["Spain", "España"],
+        bounds(-18.17, 27.64, 4.33, 43.79),
+    ),
+    country(
+        "FI",
+        &["Finland", "Suomi"],
+        bounds(19.47, 59.69, 31.59, 70.09),
+    ),
+    country("FR", &["France"], bounds(-5.14, 41.33, 9.56, 51.09)),
+    country(
+        "GB",
+        &[
+            "United Kingdom",
+            "UK",
+            "England",
+            "Scotland",
+            "Wales",
+            "Northern Ireland",
+        ],
+        bounds(-8.65, 49.86, 1.77, 60.86),
+    ),
+    country(
+        "GE",
+        &["Georgia", "საქართველო"],
+        bounds(40.01, 41.05, 46.72, 43.59),
+    ),
+    country(
+        "GR",
+        &["Greece", "Ελλάδα", "Ελλάς"],
+        bounds(19.37, 34.80, 29.65, 41.75),
+    ),
+    country(
+        "HR",
+        &["Croatia", "Hrvatska"],
+        bounds(13.49, 42.39, 19.45, 46.56),
+    ),
+    country(
+        "HU",
+        &["Hungary", "Magyarország"],
+        bounds(16.11, 45.74, 22.90, 48.59),
+    ),
+    country(
+        "IE",
+        &["Ireland", "Éire"],
+        bounds(-10.69, 51.42, -5.99, 55.39),
+    ),
+    country(
+        "IS",
+        &["Iceland", "Ísland"],
+        bounds(-24.55, 63.29, -13.49, 66.57),
+    ),
+    country(
+        "IT",
+        &["Italy", "Italia"],
+        bounds(6.63, 35.49, 18.52, 47.09),
+    ),
+    country("LI", &["Liechtenstein"], bounds(9.47, 47.05, 9.64, 47.27)),
+    country(
+        "LT",
+        &["Lithuania", "Lietuva"],
+        bounds(20.94, 53.90, 26.84, 56.45),
+    ),
+    country(
+        "LU",
+        &["Luxembourg", "Lëtzebuerg", "Luxemburg"],
+        bounds(5.73, 49.45, 6.53, 50.18),
+    ),
+    country(
+        "LV",
+        &["Latvia", "Latvija"],
+        bounds(20.97, 55.67, 28.24, 58.09),
+    ),
+    country("MC", &["Monaco"], bounds(7.40, 43.72, 7.44, 43.76)),
+    country("MD", &["Moldova"], bounds(26.62, 45.47, 30.13, 48.49)),
+    country(
+        "ME",
+        &["Montenegro", "Crna Gora", "Црна Гора"],
+        bounds(18.43, 41.85, 20.36, 43.56),
+    ),
+    country(
+        "MK",
+        &["North Macedonia", "Macedonia", "Северна Македонија"],
+        bounds(20.45, 40.85, 23.03, 42.37),
+    ),
+    country("MT", &["Malta"], bounds(14.18, 35.79, 14.58, 36.08)),
+    country(
+        "NL",
+        &["Netherlands", "The Netherlands", "Nederland"],
+        bounds(3.31, 50.75, 7.23, 53.56),
+    ),
+    country(
+        "NO",
+        &["Norway", "Norge", "Noreg"],
+        bounds(4.50, 57.96, 31.17, 71.19),
+    ),
+    country(
+        "PL",
+        &["Poland", "Polska"],
+        bounds(14.12, 49.00, 24.15, 54.84),
+    ),
+    country("PT", &["Portugal"], bounds(-31.28, 32.40, -6.19, 42.15)),
+    country(
+        "RO",
+        &["Romania", "România"],
+        bounds(20.26, 43.62, 29.76, 48.27),
+    ),
+    country(
+        "RS",
+        &["Serbia", "Srbija", "Србија"],
+        bounds(18.82, 42.23, 23.01, 46.19),
+    ),
+    country(
+        "SE",
+        &["Sweden", "Sverige"],
+        bounds(10.96, 55.34, 24.17, 69.06),
+    ),
+    country(
+        "SI",
+        &["Slovenia", "Slovenija"],
+        bounds(13.38, 45.42, 16.61, 46.88),
+    ),
+    country(
+        "SK",
+        &["Slovakia", "Slovensko"],
+        bounds(16.83, 47.73, 22.57, 49.61),
+    ),
+    country(
+        "TR",
+        &["Turkey", "Türkiye"],
+        bounds(25.66, 35.81, 44.82, 42.11),
+    ),
+    country(
+        "UA",
+        &["Ukraine", "Україна"],
+        bounds(22.14, 44.38, 40.23, 52.38),
+    ),
+    country(
+        "XK",
+        &["Kosovo", "Kosova"],
+        bounds(20.01, 41.85, 21.79, 43.27),
+    ),
+];
+
+/// Cities with many cafes on ECT, by the names ECT addresses use for them.
+static CITIES: [City; 60] = [
+    city("AL", &["Tirana", "Tiranë"], 41.3275, 19.8187),
+    city("AM", &["Yerevan", "Երևան"], 40.1872, 44.5152),
+    city("AT", &["Vienna", "Wien"], 48.2082, 16.3738),
+    city("AT", &["Graz"], 47.0707, 15.4395),
+    city("BA", &["Sarajevo"], 43.8563, 18.4131),
+    city("BE", &["Brussels", "Bruxelles", "Brussel"], 50.8503, 4.3517),
+    city("BE", &["Antwerp", "Antwerpen"], 51.2194, 4.4025),
+    city("BG", &["Sofia", "София"], 42.6977, 23.3219),
+    city("BY", &["Minsk", "Мінск", "Минск"], 53.9006, 27.5590),
+    city("CH", &["Zürich", "Zurich"], 47.3769, 8.5417),
+    city("CH", &["Geneva", "Genève"], 46.2044, 6.1432),
+    city("CH", &["Basel"], 47.5596, 7.5886),
+    city("CH", &["Bern"], 46.9480, 7.4474),
+    city("CY", &["Nicosia", "Λευκωσία"], 35.1856, 33.3823),
+    city("CZ", &["Prague", "Praha"], 50.0755, 14.4378),
+    city("CZ", &["Brno"], 49.1951, 16.6068),
+    city("DE", &["Berlin"], 52.5200, 13.4050),
+    city("DE", &["Hamburg"], 53.5511, 9.9937),
+    city("DE", &["Munich", "München"], 48.1351, 11.5820),
+    city("DE", &["Cologne", "Köln"], 50.9375, 6.9603),
+    city("DE", &["Frankfurt", "Frankfurt am Main"], 50.1109, 8.6821),
+    city("DK", &["Copenhagen", "København"], 55.6761, 12.5683),
+    city("DK", &["Aarhus"], 56.1629, 10.2039),
+    city("EE", &["Tallinn"], 59.4370, 24.7536),
+    city("ES", &["Madrid"], 40.4168, -3.7038),
+    city("ES", &["Barcelona"], 41.3874, 2.1686),
+    city("ES", &["Valencia", "València"], 39.4699, -0.3763),
+    city("FI", &["Helsinki"], 60.1699, 24.9384),
+    city("FR", &["Paris"], 48.8566, 2.3522),
+    city("FR", &["Lyon"], 45.7640, 4.8357),
+    city("GB", &["London"], 51.5072, -0.1276),
+    city("GB", &["Edinburgh"], 55.9533, -3.1883),
+    city("GB", &["Manchester"], 53.4808, -2.2426),
+    city("GE", &["Tbilisi", "თბილისი"], 41.7151, 44.8271),
+    city("GR", &["Athens", "Αθήνα"], 37.9838, 23.7275),
+    city("GR", &["Thessaloniki", "Θεσσαλονίκη"], 40.6401, 22.9444),
+    city("HR", &["Zagreb"], 45.8150, 15.9819),
+    city("HU", &["Budapest"], 47.4979, 19.0402),
+    city("IE", &["Dublin", "Baile Átha Cliath"], 53.3498, -6.2603),
+    city("IS", &["Reykjavik", "Reykjavík"], 64.1466, -21.9426),
+    city("IT", &["Rome", "Roma"], 41.9028, 12.4964),
+    city("IT", &["Milan", "Milano"], 45.4642, 9.1900),
+    city("IT", &["Florence", "Firenze"], 43.7696, 11.2558),
+    city("LT", &["Vilnius"], 54.6872, 25.2797),
+    city("LU", &["Luxembourg", "Lëtzebuerg"], 49.6116, 6.1319),
+    city("LV", &["Riga", "Rīga"], 56.9496, 24.1052),
+    city("MT", &["Valletta"], 35.8989, 14.5146),
+    city("NL", &["Amsterdam"], 52.3676, 4.9041),
+    city("NL", &["Rotterdam"], 51.9244, 4.4777),
+    city("NO", &["Oslo"], 59.9139, 10.7522),
+    city("PL", &["Warsaw", "Warszawa"], 52.2297, 21.0122),
+    city("PL", &["Kraków", "Krakow", "Cracow"], 50.0647, 19.9450),
+    city("PT", &["Lisbon", "Lisboa"], 38.7223, -9.1393),
+    city("PT", &["Porto"], 41.1579, -8.6291),
+    city("RO", &["Bucharest", "București"], 44.4268, 26.1025),
+    city("RS", &["Belgrade", "Beograd", "Београд"], 44.7866, 20.4489),
+    city("SE", &["Stockholm"], 59.3293, 18.0686),
+    city("SI", &["Ljubljana"], 46.0569, 14.5058),
+    city("TR", &["Istanbul", "İstanbul"], 41.0082, 28.9784),
+    city("UA", &["Kyiv", "Kiev", "Київ"], 50.4501, 30.5234),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_country_and_city_from_the_end_of_the_address() {
+        let copenhagen = Region::from_address("Nørrebrogade 12, 2200 Copenhagen, Denmark").unwrap();
+        let sofia = Region::from_address("ул. „Шипка“ 6, 1504 София, България").unwrap();
+        let porto = Region::from_address("Rua das Flores 1, Vila Nova de Gaia, Portugal").unwrap();
+
+        assert_eq!(copenhagen.country.code, "DK");
+        assert_eq!(copenhagen.center(), (55.6761, 12.5683));
+        assert_eq!(sofia.country.code, "BG");
+        assert!(sofia.city.is_some());
+        assert_eq!(porto.country.code, "PT");
+        assert_eq!(porto.city, None);
+        assert_eq!(Region::from_address("Somewhere 1, Atlantis"), None);
+        assert_eq!(Region::from_address(""), None);
+    }
+
+    #[test]
+    fn tells_places_in_the_country_from_places_outside_it() {
+        let hungary = Region::from_address("Király u. 1, 1061 Budapest, Hungary").unwrap();
+
+        assert!(hungary.contains(47.4979, 19.0402));
+        assert!(!hungary.contains(41.0082, 28.9784));
+        assert!(!hungary.contains(40.7128, -74.0060));
+    }
+
+    #[test]
+    fn trusts_the_geocoders_country_over_the_bounding_box() {
+        let austria = Region::from_address("Kärntner Str. 1, 1010 Wien, Österreich").unwrap();
+        // Inside the bounding boxes of both Austria and Germany.
+        let salzburg_area = |country_code: Option<&str>| GeocodeCandidate {
+            id: None,
+            name: "Cafe".to_string(),
+            address: String::new(),
+            latitude: 47.75,
+            longitude: 12.95,
+            country_code: country_code.map(str::to_string),
+            url: None,
+            categories: vec![],
+            business_status: None,
+            details: Default::default(),
+        };
+
+        assert!(austria.holds(&salzburg_area(Some("at"))));
+        assert!(!austria.holds(&salzburg_area(Some("DE"))));
+        assert!(austria.holds(&salzburg_area(None)));
+        assert_eq!(
+            country_code_in("Street 1, Berlin, Germany").as_deref(),
+            Some("DE")
+        );
+    }
+}
@@ -0,0 +1,116 @@
+//! Async HTTP client shared by the geocoders that call a web API.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use chrono::Utc;
+use reqwest::{Client, RequestBuilder, StatusCode};
+
+use crate::backoff::{self, RetryPolicy};
+use crate::model::IOError;
+use crate::polite_client::USER_AGENT;
+use crate::shutdown;
+use crate::token_bucket::TokenBucket;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Sends requests under a requests-per-second limit and sends them again
+/// after a 429, a 5xx or a network error, as [`RetryPolicy`] allows.
+pub struct RetryingClient {
+    client: Client,
+    retry_policy: RetryPolicy,
+    rate_limit: TokenBucket,
+    retries: AtomicUsize,
+}
+
+/// Why a request failed, and whether sending it again might work.
+enum FailedAttempt {
+    Retryable(String, Option<Duration>),
+    Fatal(String),
+}
+
+impl RetryingClient {
+    pub fn new(retry_policy: RetryPolicy, requests_per_second: u32) -> Result<Self, IOError> {
+        let client = Client::builder()
+            .user_agent(USER_AGENT)
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(IOError::HttpClientBuild)?;
+
+        Ok(RetryingClient {
+            client,
+            retry_policy,
+            rate_limit: TokenBucket::new(requests_per_second),
+            retries: AtomicUsize::new(0),
+        })
+    }
+
+    /// Number of requests sent again so far.
+    pub fn retries(&self) -> usize {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// Body of the first successful response to the request built by
+    /// `request`, or a description of the last failure.
+    pub async fn send<F>(&self, request: F) -> Result<String, String>
+    where
+        F: Fn(&Client) -> RequestBuilder,
+    {
+        let mut retries = 0;
+
+        loop {
+            self.rate_limit.acquire().await;
+
+            let (err, retry_after) = match send_once(request(&self.client)).await {
+                Ok(body) => return Ok(body),
+                Err(FailedAttempt::Fatal(err)) => return Err(err),
+                Err(FailedAttempt::Retryable(err, retry_after)) => (err, retry_after),
+            };
+
+            if retries >= self.retry_policy.max_retries || shutdown::is_interrupted() {
+                return Err(err);
+            }
+
+            let Some(delay) = self.retry_policy.delay(retries, retry_after) else {
+                return Err(format!(
+                    "{}, and the server asked to wait {}s before retrying",
+                    err,
+                    retry_after.unwrap_or_default().as_secs()
+                ));
+            };
+
+            tokio::time::sleep(delay).await;
+            retries += 1;
+            self.retries.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+async fn send_once(request: RequestBuilder) -> Result<String, FailedAttempt> {
+    let response = request
+        .send()
+        .await
+        .map_err(|err| FailedAttempt::Retryable(format!("{:#?}", err), None))?;
+
+    let status = response.status();
+    if status != StatusCode::OK {
+        let err = format!("response code: {:#?}", status);
+
+        if !backoff::is_retryable(status) {
+            return Err(FailedAttempt::Fatal(err));
+        }
+
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| backoff::parse_retry_after(value, Utc::now()));
+
+        return Err(FailedAttempt::Retryable(err, retry_after));
+    }
+
+    response
+        .text()
+        .await
+        .map_err(|err| FailedAttempt::Retryable(format!("{:#?}", err), None))
+}
@@ -0,0 +1,238 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::model::{CoffeeMapConfig, IOError};
+use crate::terminal_gui::LogCounts;
+
+const MANIFEST_FILENAME: &str = "manifest.json";
+const LATEST_RUN_NAME: &str = "latest";
+/// File marking the directory of a run that failed before it finished.
+pub const FAILED_MARKER_FILENAME: &str = "FAILED";
+const RUN_TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H-%M-%S%.3fZ";
+
+/// Folder `<output_folder>/<timestamp>/` holding everything written by a single run.
+///
+/// When the run fails before [`RunDirectory::finish`], the folder is removed if
+/// nothing was written into it, and otherwise kept with a [`FAILED_MARKER_FILENAME`]
+/// file, so that e.g. the katana archive of the failed crawl can be replayed.
+pub struct RunDirectory {
+    pub path: PathBuf,
+    output_folder: PathBuf,
+    started_at: DateTime<Utc>,
+    started: Instant,
+    finished: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RunManifest {
+    pub command: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub duration_seconds: f64,
+    pub config: CoffeeMapConfig,
+    pub chunk_files: Vec<String>,
+    pub crawl: Option<CrawlSummary>,
+}
+
+/// Where a crawl read its cafes from and what became of them.
+#[derive(Serialize, Deserialize)]
+pub struct CrawlSummary {
+    pub source: String,
+    pub katana_version: Option<String>,
+    pub katana_archive: Option<String>,
+    pub counts: LogCounts,
+    /// Whether a signal stopped the crawl before its source was exhausted.
+    #[serde(default)]
+    pub interrupted: bool,
+}
+
+impl RunDirectory {
+    pub fn create(config: &CoffeeMapConfig) -> Result<Self, IOError> {
+        let started_at = Utc::now();
+        let output_folder = PathBuf::from(&config.output_folder);
+        let timestamp = started_at.format(RUN_TIMESTAMP_FORMAT).to_string();
+
+        fs::create_dir_all(&output_folder).map_err(IOError::CreateMissingDirectories)?;
+        let path = create_unique_dir(&output_folder, &timestamp)
+            .map_err(IOError::CreateMissingDirectories)?;
+
+        Ok(RunDirectory {
+            path,
+            output_folder,
+            started_at,
+            started: Instant::now(),
+            finished: false,
+        })
+    }
+
+    pub fn path_string(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    /// Writes `manifest.json` into the run directory and points `latest` at it.
+    pub fn finish(
+        mut self,
+        command: &str,
+        config: &CoffeeMapConfig,
+        chunk_files: Vec<String>,
+        crawl: Option<CrawlSummary>,
+    ) -> Result<(), IOError> {
+        let manifest = RunManifest {
+            command: command.to_string(),
+            started_at: self.started_at,
+            finished_at: Utc::now(),
+            duration_seconds: self.started.elapsed().as_secs_f64(),
+            config: config.clone(),
+            chunk_files,
+            crawl,
+        };
+
+        let manifest_json = serde_json::to_string_pretty(&manifest)
+            .map_err(|err| IOError::ManifestWrite(err.into()))?;
+
+        fs::write(self.path.join(MANIFEST_FILENAME), manifest_json)
+            .map_err(IOError::ManifestWrite)?;
+
+        update_latest_link(&self.output_folder, &self.path).map_err(IOError::LatestRunLink)?;
+        self.finished = true;
+
+        Ok(())
+    }
+}
+
+impl Drop for RunDirectory {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+
+        // Only removes the directory when it is empty.
+        if fs::remove_dir(&self.path).is_err() {
+            let _ = fs::write(
+                self.path.join(FAILED_MARKER_FILENAME),
+                "this run failed before it finished; its files may be incomplete\n",
+            );
+        }
+    }
+}
+
+/// Creates `<parent>/<name>`, or `<name>-2`, `<name>-3`, … when runs started at the same time.
+fn create_unique_dir(parent: &Path, name: &str) -> std::io::Result<PathBuf> {
+    let mut attempt = 1;
+    loop {
+        let path = match attempt {
+            1 => parent.join(name),
+            _ => parent.join(format!("{}-{}", name, attempt)),
+        };
+
+        match fs::create_dir(&path) {
+            Ok(()) => return Ok(path),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => attempt += 1,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Reads the manifest of the newest run in `output_folder`, if there is one.
+pub fn read_latest_manifest(output_folder: &str) -> Option<RunManifest> {
+    let manifest_path = Path::new(output_folder)
+        .join(LATEST_RUN_NAME)
+        .join(MANIFEST_FILENAME);
+
+    let manifest_string = fs::read_to_string(manifest_path).ok()?;
+    serde_json::from_str(manifest_string.as_str()).ok()
+}
+
+#[cfg(unix)]
+fn update_latest_link(output_folder: &Path, run_path: &Path) -> std::io::Result<()> {
+    let link = output_folder.join(LATEST_RUN_NAME);
+    let target = run_path.file_name().unwrap_or(run_path.as_os_str());
+
+    if fs::symlink_metadata(&link).is_ok() {
+        fs::remove_file(&link)?;
+    }
+
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(not(unix))]
+fn update_latest_link(output_folder: &Path, run_path: &Path) -> std::io::Result<()> {
+    let latest = output_folder.join(LATEST_RUN_NAME);
+
+    if latest.exists() {
+        fs::remove_dir_all(&latest)?;
+    }
+
+    fs::create_dir_all(&latest)?;
+    for entry in fs::read_dir(run_path)? {
+        let entry = entry?;
+        fs::copy(entry.path(), latest.join(entry.file_name()))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_in(name: &str) -> CoffeeMapConfig {
+        let output_folder =
+            std::env::temp_dir().join(format!("coffee_map_runs_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&output_folder);
+
+        CoffeeMapConfig {
+            output_folder: output_folder.display().to_string(),
+            ..CoffeeMapConfig::default()
+        }
+    }
+
+    #[test]
+    fn writes_a_manifest_and_points_latest_at_the_newest_run() {
+        let config = config_in("latest");
+
+        let first = RunDirectory::create(&config).unwrap();
+        let second = RunDirectory::create(&config).unwrap();
+        assert_ne!(first.path, second.path);
+
+        first
+            .finish("export", &config, vec!["first.kml".to_string()], None)
+            .unwrap();
+        second
+            .finish("export", &config, vec!["second.kml".to_string()], None)
+            .unwrap();
+
+        let manifest = read_latest_manifest(&config.output_folder).unwrap();
+        assert_eq!(manifest.command, "export");
+        assert_eq!(manifest.chunk_files, vec!["second.kml".to_string()]);
+        assert_eq!(manifest.config.output_folder, config.output_folder);
+
+        fs::remove_dir_all(&config.output_folder).unwrap();
+    }
+
+    #[test]
+    fn keeps_what_a_run_that_did_not_finish_wrote_and_marks_it_failed() {
+        let config = config_in("failed");
+
+        let empty_run = RunDirectory::create(&config).unwrap();
+        let empty_path = empty_run.path.clone();
+        assert!(empty_path.is_dir());
+        drop(empty_run);
+        assert!(!empty_path.exists());
+
+        let crawl_run = RunDirectory::create(&config).unwrap();
+        let crawl_path = crawl_run.path.clone();
+        fs::write(crawl_path.join("katana.jsonl.gz"), "recording").unwrap();
+        drop(crawl_run);
+
+        assert!(crawl_path.join("katana.jsonl.gz").is_file());
+        assert!(crawl_path.join(FAILED_MARKER_FILENAME).is_file());
+        assert!(read_latest_manifest(&config.output_folder).is_none());
+
+        fs::remove_dir_all(&config.output_folder).unwrap();
+    }
+}
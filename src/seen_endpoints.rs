@@ -0,0 +1,94 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::atomic_file;
+use crate::ect_page;
+use crate::model::IOError;
+
+const SEEN_ENDPOINTS_FILENAME: &str = "seen_endpoints.json";
+
+/// When each ECT page was last processed, kept next to the placemark cache so
+/// later crawls can skip pages processed recently.
+#[derive(Serialize, Deserialize, Default)]
+pub struct SeenEndpoints {
+    /// Last processing time by [`ect_page::endpoint_key`].
+    last_processed: HashMap<String, DateTime<Utc>>,
+}
+
+impl SeenEndpoints {
+    /// Reads the record from `cache_folder`, starting empty when there is none yet.
+    pub fn load(cache_folder: &str) -> Self {
+        fs::read_to_string(Path::new(cache_folder).join(SEEN_ENDPOINTS_FILENAME))
+            .ok()
+            .and_then(|contents| serde_json::from_str(contents.as_str()).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, cache_folder: &str) -> Result<(), IOError> {
+        fs::create_dir_all(cache_folder).map_err(IOError::CreateMissingDirectories)?;
+
+        let contents = serde_json::to_string_pretty(self)
+            .map_err(|err| IOError::SeenEndpointsWrite(err.into()))?;
+        atomic_file::write(
+            &Path::new(cache_folder).join(SEEN_ENDPOINTS_FILENAME),
+            contents.as_bytes(),
+        )
+        .map_err(IOError::SeenEndpointsWrite)
+    }
+
+    pub fn record(&mut self, endpoint: &Url, processed_at: DateTime<Utc>) {
+        self.last_processed
+            .insert(ect_page::endpoint_key(endpoint), processed_at);
+    }
+
+    /// Keys of the pages processed at any time, or within the last
+    /// `max_age_days` days when given.
+    pub fn processed_since(
+        &self,
+        max_age_days: Option<u32>,
+        now: DateTime<Utc>,
+    ) -> HashSet<String> {
+        let cutoff = max_age_days.map(|days| now - Duration::days(days.into()));
+
+        self.processed_after(cutoff)
+    }
+
+    /// Keys of the pages processed at or after `cutoff`, or at any time when `None`.
+    pub fn processed_after(&self, cutoff: Option<DateTime<Utc>>) -> HashSet<String> {
+        self.last_processed
+            .iter()
+            .filter(|(_, processed_at)| cutoff.is_none_or(|cutoff| **processed_at >= cutoff))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selects_pages_processed_within_the_window() {
+        let now = Utc::now();
+        let mut seen = SeenEndpoints::default();
+        seen.record(
+            &Url::parse("https://europeancoffeetrip.com/cafe/recent/").unwrap(),
+            now - Duration::days(2),
+        );
+        seen.record(
+            &Url::parse("https://europeancoffeetrip.com/cafe/stale/").unwrap(),
+            now - Duration::days(40),
+        );
+
+        assert_eq!(
+            seen.processed_since(Some(30), now),
+            HashSet::from(["europeancoffeetrip.com/cafe/recent".to_string()])
+        );
+        assert_eq!(seen.processed_since(None, now).len(), 2);
+    }
+}
@@ -0,0 +1,189 @@
+//! CSS selectors used to read ECT cafe pages.
+//!
+//! They live in the `[selectors]` table of the config file so a change to the
+//! site's markup can be handled without a new release. Each detail has a list
+//! of selectors that are tried in order, the first one matching the page wins.
+//!
+//! The defaults are unverified: they follow the markup of the pages in
+//! `tests/fixtures/ect`, which were written by hand rather than saved from
+//! europeancoffeetrip.com. Check them against a live cafe page, saved in
+//! `tests/fixtures/ect/live`, before relying on them.
+
+use scraper::Selector;
+use serde::{Deserialize, Serialize};
+
+use crate::model::ConfigError;
+
+/// Version of the profile format understood by this build.
+pub const SELECTOR_PROFILE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SelectorProfile {
+    /// Must be [`SELECTOR_PROFILE_VERSION`], so a profile written for another
+    /// format is rejected instead of silently matching nothing.
+    pub version: u32,
+    pub name: Vec<String>,
+    pub address: Vec<String>,
+    pub opening_hours: Vec<String>,
+    pub website: Vec<String>,
+    pub social_links: Vec<String>,
+    pub roasters: Vec<String>,
+    pub brewing_methods: Vec<String>,
+    pub price_range: Vec<String>,
+    pub description: Vec<String>,
+    pub photos: Vec<String>,
+    /// Any match marks the cafe as permanently closed.
+    pub closed: Vec<String>,
+}
+
+impl Default for SelectorProfile {
+    fn default() -> Self {
+        let selectors = |selectors: &[&str]| selectors.iter().map(|s| s.to_string()).collect();
+
+        // Unverified against real ECT markup, see the module documentation.
+
+        SelectorProfile {
+            version: SELECTOR_PROFILE_VERSION,
+            name: selectors(&["h1.cafe-name", ".cafe-name"]),
+            address: selectors(&["div.cafe-address", ".cafe-address"]),
+            opening_hours: selectors(&[".cafe-opening-hours li"]),
+            website: selectors(&["a.cafe-website[href]", ".cafe-website a[href]"]),
+            social_links: selectors(&[".cafe-social a[href]"]),
+            roasters: selectors(&[".cafe-roasters li"]),
+            brewing_methods: selectors(&[".cafe-brewing-methods li"]),
+            price_range: selectors(&[".cafe-price-range"]),
+            description: selectors(&[".cafe-description"]),
+            photos: selectors(&[".cafe-gallery img[src]"]),
+            closed: selectors(&[".cafe-closed", ".cafe-permanently-closed"]),
+        }
+    }
+}
+
+impl SelectorProfile {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.version != SELECTOR_PROFILE_VERSION {
+            return Err(ConfigError::InvalidValue(
+                "selectors.version",
+                format!(
+                    "profile version {} is not supported, expected {}",
+                    self.version, SELECTOR_PROFILE_VERSION
+                ),
+            ));
+        }
+
+        for (field, selectors) in self.fields() {
+            if let Some(invalid) = selectors
+                .iter()
+                .find(|selector| Selector::parse(selector).is_err())
+            {
+                return Err(ConfigError::InvalidValue(
+                    field,
+                    format!("`{}` is not a valid CSS selector", invalid),
+                ));
+            }
+        }
+
+        if self.name.is_empty() || self.address.is_empty() {
+            return Err(ConfigError::InvalidValue(
+                "selectors",
+                "name and address need at least one selector each".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Parses every selector. The profile must have passed [`Self::validate`].
+    pub fn compile(&self) -> CafePageSelectors {
+        let compile = |selectors: &Vec<String>| {
+            selectors
+                .iter()
+                .map(|selector| Selector::parse(selector).expect("selector profile is validated"))
+                .collect()
+        };
+
+        CafePageSelectors {
+            name: compile(&self.name),
+            address: compile(&self.address),
+            opening_hours: compile(&self.opening_hours),
+            website: compile(&self.website),
+            social_links: compile(&self.social_links),
+            roasters: compile(&self.roasters),
+            brewing_methods: compile(&self.brewing_methods),
+            price_range: compile(&self.price_range),
+            description: compile(&self.description),
+            photos: compile(&self.photos),
+            closed: compile(&self.closed),
+        }
+    }
+
+    fn fields(&self) -> [(&'static str, &Vec<String>); 11] {
+        [
+            ("selectors.name", &self.name),
+            ("selectors.address", &self.address),
+            ("selectors.opening_hours", &self.opening_hours),
+            ("selectors.website", &self.website),
+            ("selectors.social_links", &self.social_links),
+            ("selectors.roasters", &self.roasters),
+            ("selectors.brewing_methods", &self.brewing_methods),
+            ("selectors.price_range", &self.price_range),
+            ("selectors.description", &self.description),
+            ("selectors.photos", &self.photos),
+            ("selectors.closed", &self.closed),
+        ]
+    }
+}
+
+/// A [`SelectorProfile`] with every selector parsed, ready to be matched against pages.
+#[derive(Debug, Clone)]
+pub struct CafePageSelectors {
+    pub name: Vec<Selector>,
+    pub address: Vec<Selector>,
+    pub opening_hours: Vec<Selector>,
+    pub website: Vec<Selector>,
+    pub social_links: Vec<Selector>,
+    pub roasters: Vec<Selector>,
+    pub brewing_methods: Vec<Selector>,
+    pub price_range: Vec<Selector>,
+    pub description: Vec<Selector>,
+    pub photos: Vec<Selector>,
+    pub closed: Vec<Selector>,
+}
+
+impl Default for CafePageSelectors {
+    fn default() -> Self {
+        SelectorProfile::default().compile()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_profile_is_valid() {
+        assert!(SelectorProfile::default().validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_other_versions_and_broken_selectors() {
+        let newer = SelectorProfile {
+            version: SELECTOR_PROFILE_VERSION + 1,
+            ..Default::default()
+        };
+        let broken = SelectorProfile {
+            roasters: vec!["li[".to_string()],
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            newer.validate(),
+            Err(ConfigError::InvalidValue("selectors.version", _))
+        ));
+        assert!(matches!(
+            broken.validate(),
+            Err(ConfigError::InvalidValue("selectors.roasters", _))
+        ));
+    }
+}
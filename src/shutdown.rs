@@ -0,0 +1,63 @@
+//! Ctrl-C and SIGTERM handling, so that no katana crawl outlives the program.
+//!
+//! The first signal stops katana and asks the crawl to stop after the cafe it
+//! is processing, so what was collected so far is still written out. A second
+//! signal exits straight away.
+
+use std::process::{self, Child};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::model::IOError;
+
+/// Exit code conventionally used by programs stopped with SIGINT.
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+static KATANA_CHILDREN: Mutex<Vec<Weak<Mutex<Child>>>> = Mutex::new(Vec::new());
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Kills every registered katana process and marks the crawl as interrupted
+/// on Ctrl-C or SIGTERM, or exits if it already was.
+pub fn install_handler() -> Result<(), IOError> {
+    ctrlc::set_handler(|| {
+        kill_children();
+
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            process::exit(INTERRUPTED_EXIT_CODE);
+        }
+    })
+    .map_err(IOError::SignalHandler)
+}
+
+/// Whether a signal asked the program to stop.
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Exits with the conventional status of a program stopped by a signal if one was received.
+pub fn exit_if_interrupted() {
+    if is_interrupted() {
+        process::exit(INTERRUPTED_EXIT_CODE);
+    }
+}
+
+pub fn register_child(child: &Arc<Mutex<Child>>) {
+    if let Ok(mut children) = KATANA_CHILDREN.lock() {
+        children.retain(|child| child.strong_count() > 0);
+        children.push(Arc::downgrade(child));
+    }
+}
+
+fn kill_children() {
+    let Ok(children) = KATANA_CHILDREN.lock() else {
+        return;
+    };
+
+    for child in children.iter().filter_map(Weak::upgrade) {
+        // A locked child is already being waited on because katana is exiting.
+        if let Ok(mut child) = child.try_lock() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
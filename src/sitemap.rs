@@ -0,0 +1,331 @@
+use std::collections::{HashSet, VecDeque};
+use std::io::Read;
+
+use flate2::read::GzDecoder;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use url::Url;
+
+use crate::cafe_source::{CafeSource, ECTCafeResult};
+use crate::ect_page::{self, ECT_CAFE_INDEX_URL};
+use crate::model::{CoffeeMapConfig, IOError, PipelineError};
+use crate::polite_client::PoliteClient;
+use crate::selector_profile::CafePageSelectors;
+use crate::shutdown;
+
+/// Discovers cafe pages from the site's sitemaps instead of following links.
+///
+/// Sitemaps are taken from robots.txt, falling back to `/sitemap.xml`, and
+/// nested sitemap indexes are followed. Cafe pages already in the cache are
+/// yielded without details and never downloaded, so the pipeline resolves
+/// them from the cache by their URL.
+pub struct SitemapSource {
+    client: PoliteClient,
+    site_url: Url,
+    sitemap_queue: VecDeque<Url>,
+    page_queue: VecDeque<Url>,
+    seen_sitemaps: HashSet<Url>,
+    seen_pages: HashSet<String>,
+    cached_pages: HashSet<String>,
+    page_selectors: CafePageSelectors,
+    pending_errors: VecDeque<PipelineError>,
+}
+
+impl SitemapSource {
+    pub fn new(
+        site_url: Url,
+        cached_pages: HashSet<String>,
+        requests_per_second: u8,
+        page_selectors: CafePageSelectors,
+    ) -> Result<Self, IOError> {
+        let client = PoliteClient::new(&site_url, requests_per_second)?;
+
+        let mut sitemaps = client
+            .sitemaps()
+            .iter()
+            .filter_map(|sitemap| site_url.join(sitemap).ok())
+            .collect::<VecDeque<Url>>();
+
+        if sitemaps.is_empty() {
+            sitemaps.extend(site_url.join("/sitemap.xml").ok());
+        }
+
+        Ok(SitemapSource {
+            client,
+            site_url,
+            seen_sitemaps: sitemaps.iter().cloned().collect(),
+            sitemap_queue: sitemaps,
+            page_queue: VecDeque::new(),
+            seen_pages: HashSet::new(),
+            cached_pages,
+            page_selectors,
+            pending_errors: VecDeque::new(),
+        })
+    }
+
+    /// Sitemap crawl of ECT that does not download the pages in `skipped_pages`,
+    /// keyed by [`ect_page::endpoint_key`].
+    pub fn from_config(
+        config: &CoffeeMapConfig,
+        skipped_pages: HashSet<String>,
+    ) -> Result<Self, IOError> {
+        let site_url = Url::parse(ECT_CAFE_INDEX_URL).expect("ECT cafe index url is valid");
+
+        Self::new(
+            site_url,
+            skipped_pages,
+            config.katana_requests_per_second,
+            config.selectors.compile(),
+        )
+    }
+
+    fn read_sitemap(&mut self, sitemap_url: &Url) -> Result<(), PipelineError> {
+        let mut bytes = self.client.fetch(sitemap_url)?;
+
+        if bytes.starts_with(&[0x1f, 0x8b]) {
+            let mut decompressed = vec![];
+            GzDecoder::new(bytes.as_slice())
+                .read_to_end(&mut decompressed)
+                .map_err(|err| {
+                    PipelineError::SitemapParseError(format!("{}: {}", sitemap_url, err))
+                })?;
+            bytes = decompressed;
+        }
+
+        let sitemap = parse_sitemap(bytes.as_slice())
+            .map_err(|err| PipelineError::SitemapParseError(format!("{}: {}", sitemap_url, err)))?;
+
+        let locations = sitemap
+            .locations
+            .iter()
+            .filter_map(|location| sitemap_url.join(location).ok());
+
+        if sitemap.is_index {
+            for location in locations {
+                if self.seen_sitemaps.insert(location.clone()) {
+                    self.sitemap_queue.push_back(location);
+                }
+            }
+        } else {
+            for location in locations.filter(ect_page::is_cafe_page) {
+                if self.seen_pages.insert(ect_page::endpoint_key(&location)) {
+                    self.page_queue.push_back(location);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_page(&mut self, page_url: Url) -> Result<ECTCafeResult, PipelineError> {
+        if self
+            .cached_pages
+            .contains(&ect_page::endpoint_key(&page_url))
+        {
+            return Ok(ECTCafeResult {
+                endpoint: page_url,
+                details: None,
+            });
+        }
+
+        let html_body = self.client.fetch_text(&page_url)?;
+        let details = ect_page::read_cafe_page(
+            &page_url,
+            html_body.as_str(),
+            &self.page_selectors,
+            &mut self.pending_errors,
+        );
+
+        Ok(ECTCafeResult {
+            endpoint: page_url,
+            details,
+        })
+    }
+}
+
+impl Iterator for SitemapSource {
+    type Item = Result<ECTCafeResult, PipelineError>;
+
+    fn next(&mut self) -> Option<Result<ECTCafeResult, PipelineError>> {
+        if let Some(err) = self.pending_errors.pop_front() {
+            return Some(Err(err));
+        }
+
+        loop {
+            // Reading nested sitemaps can take many requests before a cafe is yielded.
+            if shutdown::is_interrupted() {
+                return None;
+            }
+
+            if let Some(page_url) = self.page_queue.pop_front() {
+                if !self.client.is_allowed(&page_url) {
+                    continue;
+                }
+
+                return Some(self.read_page(page_url));
+            }
+
+            let sitemap_url = self.sitemap_queue.pop_front()?;
+            if let Err(err) = self.read_sitemap(&sitemap_url) {
+                return Some(Err(err));
+            }
+        }
+    }
+}
+
+impl CafeSource for SitemapSource {
+    fn describe(&self) -> String {
+        format!("sitemap discovery on {}", self.site_url)
+    }
+}
+
+struct Sitemap {
+    is_index: bool,
+    locations: Vec<String>,
+}
+
+fn parse_sitemap(xml: &[u8]) -> Result<Sitemap, quick_xml::Error> {
+    let mut reader = Reader::from_reader(xml);
+    reader.trim_text(true);
+
+    let mut buffer = vec![];
+    let mut root_element = None;
+    let mut in_location = false;
+    let mut locations = vec![];
+
+    loop {
+        match reader.read_event_into(&mut buffer)? {
+            Event::Start(element) => {
+                let name = String::from_utf8_lossy(element.local_name().as_ref()).to_string();
+                in_location = name == "loc";
+                root_element.get_or_insert(name);
+            }
+            Event::Text(text) if in_location => {
+                locations.push(text.unescape()?.trim().to_string());
+            }
+            Event::CData(data) if in_location => {
+                locations.push(String::from_utf8_lossy(&data).trim().to_string());
+            }
+            Event::End(_) => in_location = false,
+            Event::Eof => break,
+            _ => {}
+        }
+
+        buffer.clear();
+    }
+
+    Ok(Sitemap {
+        is_index: root_element.as_deref() == Some("sitemapindex"),
+        locations,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::TestServer;
+
+    fn sitemap_index(locations: &[&str]) -> String {
+        let sitemaps = locations
+            .iter()
+            .map(|location| format!("<sitemap><loc>{}</loc></sitemap>", location))
+            .collect::<String>();
+
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?><sitemapindex xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">{}</sitemapindex>"#,
+            sitemaps
+        )
+    }
+
+    fn url_set(locations: &[&str]) -> String {
+        let urls = locations
+            .iter()
+            .map(|location| format!("<url><loc>{}</loc></url>", location))
+            .collect::<String>();
+
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?><urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">{}</urlset>"#,
+            urls
+        )
+    }
+
+    fn cafe_page(name: &str) -> String {
+        format!(
+            r#"<html><body><h1 class="cafe-name">{}</h1><div class="cafe-address">Street 1, 1000 City, Country</div></body></html>"#,
+            name
+        )
+    }
+
+    #[test]
+    fn follows_sitemap_indexes_and_skips_cached_pages() {
+        let server = TestServer::start(vec![
+            (
+                "/sitemap.xml",
+                sitemap_index(&["/cafe-sitemap-1.xml", "/cafe-sitemap-2.xml"]),
+            ),
+            (
+                "/cafe-sitemap-1.xml",
+                url_set(&["/cafe/alpha/", "/cafe/beta/", "/about/"]),
+            ),
+            (
+                "/cafe-sitemap-2.xml",
+                url_set(&["/cafe/beta", "/cafe/gamma/"]),
+            ),
+            ("/cafe/alpha/", cafe_page("Alpha")),
+            ("/cafe/beta/", cafe_page("Beta")),
+            ("/cafe/gamma/", cafe_page("Gamma")),
+        ]);
+
+        let cached_pages = HashSet::from([ect_page::endpoint_key(&server.url("/cafe/beta/"))]);
+
+        let results = SitemapSource::new(
+            server.url("/"),
+            cached_pages,
+            255,
+            CafePageSelectors::default(),
+        )
+        .unwrap()
+        .map(|result| {
+            let result = result.unwrap();
+            (
+                result.endpoint.path().to_string(),
+                result.details.map(|details| details.name),
+            )
+        })
+        .collect::<Vec<_>>();
+
+        assert_eq!(
+            results,
+            vec![
+                ("/cafe/alpha/".to_string(), Some("Alpha".to_string())),
+                ("/cafe/beta/".to_string(), None),
+                ("/cafe/gamma/".to_string(), Some("Gamma".to_string())),
+            ]
+        );
+        assert_eq!(server.hits("/cafe/beta/"), 0);
+        assert_eq!(server.hits("/about/"), 0);
+    }
+
+    #[test]
+    fn uses_sitemaps_listed_in_robots_txt() {
+        let server = TestServer::start(vec![
+            ("/robots.txt", "Sitemap: /cafes.xml\n".to_string()),
+            ("/cafes.xml", url_set(&["/cafe/alpha/"])),
+            ("/cafe/alpha/", cafe_page("Alpha")),
+        ]);
+
+        let names = SitemapSource::new(
+            server.url("/"),
+            HashSet::new(),
+            255,
+            CafePageSelectors::default(),
+        )
+        .unwrap()
+        .filter_map(|result| result.unwrap().details)
+        .map(|details| details.name)
+        .collect::<Vec<String>>();
+
+        assert_eq!(names, vec!["Alpha"]);
+        assert_eq!(server.hits("/sitemap.xml"), 0);
+    }
+}
@@ -1,11 +1,13 @@
-use std::convert::TryInto;
+use serde::{Deserialize, Serialize};
 
 use superconsole::components::bordering::{Bordered, BorderedSpec};
 use superconsole::components::splitting::SplitKind;
 use superconsole::components::Split;
-use superconsole::{Component, Dimensions, Direction, DrawMode, Line, Lines};
+use superconsole::{Component, Dimensions, Direction, DrawMode, Line, Lines, SuperConsole};
 
-use crate::model::{PipelineError, PlacemarkComputation, SearchTerm};
+use crate::cafe_status;
+use crate::candidate_match;
+use crate::model::{IOError, PipelineError, PlacemarkComputation, SearchTerm};
 
 struct TableColumn {
     values: Vec<String>,
@@ -15,9 +17,8 @@ impl Component for TableColumn {
     fn draw_unchecked(&self, _dimensions: Dimensions, _mode: DrawMode) -> anyhow::Result<Lines> {
         let lines = Lines(
             self.values
-                .to_owned()
-                .into_iter()
-                .map(|value| vec![value].try_into().unwrap())
+                .iter()
+                .map(|value| table_line(value))
                 .collect::<Vec<Line>>(),
         );
 
@@ -25,23 +26,115 @@ impl Component for TableColumn {
     }
 }
 
-#[derive(Clone)]
+/// `value` as one line of the table. Superconsole only accepts plain spaces,
+/// so whitespace such as the tabs and carriage returns in katana's progress
+/// output becomes a space, and other control characters are dropped.
+fn table_line(value: &str) -> Line {
+    let value = value
+        .chars()
+        .map(|c| if c.is_whitespace() { ' ' } else { c })
+        .filter(|c| !c.is_control())
+        .collect::<String>();
+
+    Line::sanitized(&value)
+}
+
+/// The counts of a running crawl, redrawn on the terminal after every placemark.
+pub struct CrawlDisplay {
+    counts: LogCounts,
+    superconsole: Option<SuperConsole>,
+}
+
+impl CrawlDisplay {
+    /// Draws `counts` and their updates on stderr, which must be a terminal.
+    pub fn on_terminal(counts: LogCounts) -> Result<Self, IOError> {
+        Ok(CrawlDisplay {
+            counts,
+            superconsole: Some(SuperConsole::new().ok_or(IOError::SuperConsoleNotTTY)?),
+        })
+    }
+
+    /// Keeps the counts without drawing them.
+    #[cfg(test)]
+    pub fn hidden(counts: LogCounts) -> Self {
+        CrawlDisplay {
+            counts,
+            superconsole: None,
+        }
+    }
+
+    pub fn update(
+        &mut self,
+        placemark: &Result<PlacemarkComputation, PipelineError>,
+        geocoder_retries: usize,
+    ) {
+        self.counts = self
+            .counts
+            .update(placemark)
+            .with_geocoder_retries(geocoder_retries);
+
+        if let Some(superconsole) = &mut self.superconsole {
+            let _ = superconsole.render(&self.counts.make_component());
+        }
+    }
+
+    /// Draws the final counts below the terminal output and returns them.
+    pub fn finish(self) -> LogCounts {
+        if let Some(superconsole) = self.superconsole {
+            let _ = superconsole.finalize(&self.counts.make_component());
+        }
+
+        self.counts
+    }
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct LogCounts {
     cached_with_url: i32,
     cached_with_cafe_details: i32,
+    cached_with_endpoint: i32,
     queried_with_url: i32,
     queried_with_cafe_details: i32,
+    from_ect_coordinates: i32,
     google_http_errors: i32,
+    geocoder_retries: i32,
     place_not_found_errors: i32,
+    places_outside_country: i32,
     google_json_parse_errors: i32,
+    geocoder_http_errors: i32,
+    geocoder_parse_errors: i32,
     katana_json_parse_errors: i32,
     katana_endpoint_parse_errors: i32,
     katana_io_errors: i32,
+    katana_stderr_lines: i32,
+    cafe_list_parse_errors: i32,
+    crawler_http_errors: i32,
+    sitemap_parse_errors: i32,
+    ect_page_layout_unknown: i32,
+    duplicate_endpoints: i32,
+    closed_cafes: i32,
+    matches_needing_review: i32,
+    last_katana_stderr_line: Option<String>,
+    katana_exit_status: Option<String>,
+    /// Messages about the crawl as a whole, shown under the counts.
+    notes: Vec<String>,
 }
 
 impl LogCounts {
     pub fn update(&self, placemark: &Result<PlacemarkComputation, PipelineError>) -> LogCounts {
-        let mut updated = LogCounts::clone(&self);
+        let mut updated = LogCounts::clone(self);
+
+        if let Ok(computation) = placemark {
+            if cafe_status::is_closed(computation.get_placemark()) {
+                updated.closed_cafes += 1;
+            }
+            if let PlacemarkComputation::FromGeocoder(_, placemark) = computation {
+                if candidate_match::needs_review(placemark) {
+                    updated.matches_needing_review += 1;
+                }
+            }
+        }
 
         match placemark {
             Ok(PlacemarkComputation::FromCache(SearchTerm::CafeDetails(_), _)) => {
@@ -50,37 +143,90 @@ impl LogCounts {
             Ok(PlacemarkComputation::FromCache(SearchTerm::UrlFragment(_), _)) => {
                 updated.cached_with_url += 1
             }
-            Ok(PlacemarkComputation::FromGoogleQuery(SearchTerm::CafeDetails(_), _)) => {
-                updated.queried_with_cafe_details += 1
+            Ok(PlacemarkComputation::FromCache(SearchTerm::KnownEndpoint(_), _)) => {
+                updated.cached_with_endpoint += 1
             }
-            Ok(PlacemarkComputation::FromGoogleQuery(SearchTerm::UrlFragment(_), _)) => {
+            Ok(PlacemarkComputation::FromGeocoder(
+                SearchTerm::CafeDetails(_) | SearchTerm::KnownEndpoint(_),
+                _,
+            )) => updated.queried_with_cafe_details += 1,
+            Ok(PlacemarkComputation::FromGeocoder(SearchTerm::UrlFragment(_), _)) => {
                 updated.queried_with_url += 1
             }
+            Ok(PlacemarkComputation::FromECTCoordinates(_, _)) => updated.from_ect_coordinates += 1,
             Err(PipelineError::GoogleHTTPError(_)) => updated.google_http_errors += 1,
-            Err(PipelineError::GooglePlaceNotFoundError(_)) => updated.place_not_found_errors += 1,
+            Err(PipelineError::PlaceNotFoundError(_)) => updated.place_not_found_errors += 1,
+            Err(PipelineError::PlaceOutsideCountryError(_)) => updated.places_outside_country += 1,
             Err(PipelineError::GoogleJsonParseError(_)) => updated.google_json_parse_errors += 1,
+            Err(PipelineError::GeocoderHTTPError(_)) => updated.geocoder_http_errors += 1,
+            Err(PipelineError::GeocoderParseError(_)) => updated.geocoder_parse_errors += 1,
             Err(PipelineError::KatanaJsonParseError(_)) => updated.katana_json_parse_errors += 1,
             Err(PipelineError::KatanaEndpointParseError(_)) => {
                 updated.katana_endpoint_parse_errors += 1
             }
             Err(PipelineError::KatanaIOError(_)) => updated.katana_io_errors += 1,
+            Err(PipelineError::KatanaStderr(line)) => {
+                updated.katana_stderr_lines += 1;
+                updated.last_katana_stderr_line = Some(line.clone());
+            }
+            Err(PipelineError::KatanaExitStatus(status)) => {
+                updated.katana_exit_status = Some(status.to_string())
+            }
+            Err(PipelineError::CafeListParseError(_)) => updated.cafe_list_parse_errors += 1,
+            Err(PipelineError::CrawlerHTTPError(_)) => updated.crawler_http_errors += 1,
+            Err(PipelineError::SitemapParseError(_)) => updated.sitemap_parse_errors += 1,
+            Err(PipelineError::ECTPageLayoutUnknown(_)) => updated.ect_page_layout_unknown += 1,
+            Err(PipelineError::DuplicateEndpoint(_)) => updated.duplicate_endpoints += 1,
         };
 
         updated
     }
 
+    /// Records the number of geocoder requests sent again so far.
+    pub fn with_geocoder_retries(&self, retries: usize) -> LogCounts {
+        LogCounts {
+            geocoder_retries: retries.try_into().unwrap_or(i32::MAX),
+            ..LogCounts::clone(self)
+        }
+    }
+
+    /// Adds a message about the crawl as a whole.
+    pub fn with_note(&self, note: String) -> LogCounts {
+        let mut updated = LogCounts::clone(self);
+        updated.notes.push(note);
+
+        updated
+    }
+
     pub fn new() -> LogCounts {
         LogCounts {
             cached_with_url: 0,
             cached_with_cafe_details: 0,
+            cached_with_endpoint: 0,
             queried_with_url: 0,
             queried_with_cafe_details: 0,
+            from_ect_coordinates: 0,
             google_http_errors: 0,
+            geocoder_retries: 0,
             place_not_found_errors: 0,
+            places_outside_country: 0,
             google_json_parse_errors: 0,
+            geocoder_http_errors: 0,
+            geocoder_parse_errors: 0,
             katana_json_parse_errors: 0,
             katana_endpoint_parse_errors: 0,
             katana_io_errors: 0,
+            katana_stderr_lines: 0,
+            cafe_list_parse_errors: 0,
+            crawler_http_errors: 0,
+            sitemap_parse_errors: 0,
+            ect_page_layout_unknown: 0,
+            duplicate_endpoints: 0,
+            closed_cafes: 0,
+            matches_needing_review: 0,
+            last_katana_stderr_line: None,
+            katana_exit_status: None,
+            notes: vec![],
         }
     }
 
@@ -88,14 +234,28 @@ impl LogCounts {
         let stat_names = vec![
             "cached_with_url",
             "cached_with_cafe_details",
+            "cached_with_endpoint",
             "queried_with_url",
             "queried_with_cafe_details",
+            "from_ect_coordinates",
+            "closed_cafes",
+            "matches_needing_review",
             "google_http_errors",
+            "geocoder_retries",
             "place_not_found_errors",
+            "places_outside_country",
             "google_json_parse_errors",
+            "geocoder_http_errors",
+            "geocoder_parse_errors",
             "katana_json_parse_errors",
             "katana_endpoint_parse_errors",
             "katana_io_errors",
+            "katana_stderr_lines",
+            "cafe_list_parse_errors",
+            "crawler_http_errors",
+            "sitemap_parse_errors",
+            "ect_page_layout_unknown",
+            "duplicate_endpoints",
         ]
         .into_iter()
         .map(|stat_name| stat_name.to_string())
@@ -104,14 +264,28 @@ impl LogCounts {
         let stat_values = vec![
             self.cached_with_url,
             self.cached_with_cafe_details,
+            self.cached_with_endpoint,
             self.queried_with_url,
             self.queried_with_cafe_details,
+            self.from_ect_coordinates,
+            self.closed_cafes,
+            self.matches_needing_review,
             self.google_http_errors,
+            self.geocoder_retries,
             self.place_not_found_errors,
+            self.places_outside_country,
             self.google_json_parse_errors,
+            self.geocoder_http_errors,
+            self.geocoder_parse_errors,
             self.katana_json_parse_errors,
             self.katana_endpoint_parse_errors,
             self.katana_io_errors,
+            self.katana_stderr_lines,
+            self.cafe_list_parse_errors,
+            self.crawler_http_errors,
+            self.sitemap_parse_errors,
+            self.ect_page_layout_unknown,
+            self.duplicate_endpoints,
         ]
         .into_iter()
         .map(|stat_name| stat_name.to_string())
@@ -125,10 +299,66 @@ impl LogCounts {
         };
         let right_component = Bordered::new(right_column, BorderedSpec::default());
 
-        Box::new(Split::new(
-            vec![Box::new(left_component), Box::new(right_component)],
+        let table = Box::new(Split::new(
+            vec![
+                Box::new(left_component) as Box<dyn Component>,
+                Box::new(right_component),
+            ],
             Direction::Horizontal,
             SplitKind::Adaptive,
+        ));
+
+        let messages = self.messages();
+        if messages.is_empty() {
+            return table;
+        }
+
+        let message_component =
+            Bordered::new(TableColumn { values: messages }, BorderedSpec::default());
+
+        Box::new(Split::new(
+            vec![table as Box<dyn Component>, Box::new(message_component)],
+            Direction::Vertical,
+            SplitKind::Adaptive,
         ))
     }
+
+    fn messages(&self) -> Vec<String> {
+        let last_stderr_line = self
+            .last_katana_stderr_line
+            .as_ref()
+            .map(|line| format!("katana stderr: {}", line));
+        let exit_status = self
+            .katana_exit_status
+            .as_ref()
+            .map(|status| format!("katana exited with {}", status));
+
+        self.notes
+            .iter()
+            .cloned()
+            .chain(last_stderr_line)
+            .chain(exit_status)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn draws_katana_progress_lines_with_tabs_and_carriage_returns() {
+        let counts = LogCounts::new().update(&Err(PipelineError::KatanaStderr(
+            "[INF] 12/40\t30%\r[INF] 13/40\x1b[0m".to_string(),
+        )));
+
+        let lines = counts
+            .make_component()
+            .draw_unchecked(Dimensions::new(120, 60), DrawMode::Normal)
+            .unwrap();
+
+        assert!(lines.0.iter().any(|line| line
+            .to_unstyled()
+            .contains("katana stderr: [INF] 12/40 30% [INF] 13/40[0m")));
+    }
 }
@@ -0,0 +1,150 @@
+//! Minimal HTTP/1.1 fixture server for tests that exercise code talking to a website or API.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use url::Url;
+
+pub struct TestRequest {
+    pub path: String,
+}
+
+pub struct TestResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl TestResponse {
+    pub fn ok(body: String) -> Self {
+        TestResponse {
+            status: 200,
+            headers: vec![],
+            body,
+        }
+    }
+
+    pub fn status(status: u16) -> Self {
+        TestResponse {
+            status,
+            headers: vec![],
+            body: String::new(),
+        }
+    }
+}
+
+type Handler = dyn Fn(&TestRequest) -> TestResponse + Send + Sync;
+
+/// Serves requests on a random localhost port from a background thread until the test exits.
+pub struct TestServer {
+    base_url: Url,
+    hits: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl TestServer {
+    /// Serves fixed bodies by path and answers 404 for everything else.
+    pub fn start(routes: Vec<(&str, String)>) -> Self {
+        let routes = routes
+            .into_iter()
+            .map(|(path, body)| (path.to_string(), body))
+            .collect::<HashMap<String, String>>();
+
+        Self::with_handler(move |request| match routes.get(&request.path) {
+            Some(body) => TestResponse::ok(body.clone()),
+            None => TestResponse::status(404),
+        })
+    }
+
+    pub fn with_handler<F>(handler: F) -> Self
+    where
+        F: Fn(&TestRequest) -> TestResponse + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind test server");
+        let port = listener.local_addr().expect("test server address").port();
+        let hits = Arc::new(Mutex::new(HashMap::<String, usize>::new()));
+
+        let handler: Arc<Handler> = Arc::new(handler);
+        let thread_hits = Arc::clone(&hits);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let handler = Arc::clone(&handler);
+                let hits = Arc::clone(&thread_hits);
+                thread::spawn(move || serve_connection(stream, handler.as_ref(), &hits));
+            }
+        });
+
+        TestServer {
+            base_url: Url::parse(&format!("http://127.0.0.1:{}", port)).expect("test server url"),
+            hits,
+        }
+    }
+
+    pub fn url(&self, path: &str) -> Url {
+        self.base_url.join(path).expect("valid test server path")
+    }
+
+    /// Number of requests received for `path`.
+    pub fn hits(&self, path: &str) -> usize {
+        self.hits
+            .lock()
+            .unwrap()
+            .get(path)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+fn serve_connection(stream: TcpStream, handler: &Handler, hits: &Mutex<HashMap<String, usize>>) {
+    let mut reader = BufReader::new(&stream);
+
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+        return;
+    }
+    let path = request_line
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or_default()
+        .to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut header_line = String::new();
+        if reader.read_line(&mut header_line).is_err() || header_line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header_line.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+
+    let content_length = headers
+        .get("content-length")
+        .and_then(|length| length.parse::<usize>().ok())
+        .unwrap_or_default();
+    let mut body = vec![0; content_length];
+    let _ = reader.read_exact(&mut body);
+
+    *hits.lock().unwrap().entry(path.clone()).or_default() += 1;
+
+    let request = TestRequest { path };
+    let response = handler(&request);
+
+    let extra_headers = response
+        .headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+        .collect::<String>();
+
+    let _ = write!(
+        &stream,
+        "HTTP/1.1 {} Fixture\r\nContent-Length: {}\r\nConnection: close\r\n{}\r\n{}",
+        response.status,
+        response.body.len(),
+        extra_headers,
+        response.body
+    );
+}
@@ -0,0 +1,98 @@
+//! Client-side limit on the rate of requests shared by concurrent tasks.
+
+use std::time::Duration;
+
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+/// Allows bursts of up to `rate` requests and `rate` requests per second on average.
+pub struct TokenBucket {
+    rate: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(requests_per_second: u32) -> Self {
+        let rate = f64::from(requests_per_second.max(1));
+
+        TokenBucket {
+            rate,
+            state: Mutex::new(BucketState {
+                tokens: rate,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Waits until a request may be sent.
+    pub async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut state = self.state.lock().await;
+                let now = Instant::now();
+                let refill = now.duration_since(state.refilled_at).as_secs_f64() * self.rate;
+                state.tokens = (state.tokens + refill).min(self.rate);
+                state.refilled_at = now;
+
+                if state.tokens >= 1.0 {
+                    state.tokens -= 1.0;
+                    return;
+                }
+
+                Duration::from_secs_f64((1.0 - state.tokens) / self.rate)
+            };
+
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+
+    use super::*;
+
+    fn acquires_at_once(bucket: &TokenBucket) -> bool {
+        bucket.acquire().now_or_never().is_some()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn allows_a_burst_then_refills_at_the_rate() {
+        let bucket = TokenBucket::new(20);
+
+        for _ in 0..20 {
+            assert!(acquires_at_once(&bucket));
+        }
+        assert!(!acquires_at_once(&bucket));
+
+        tokio::time::advance(Duration::from_millis(100)).await;
+        assert!(acquires_at_once(&bucket));
+        assert!(acquires_at_once(&bucket));
+        assert!(!acquires_at_once(&bucket));
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        for _ in 0..20 {
+            assert!(acquires_at_once(&bucket));
+        }
+        assert!(!acquires_at_once(&bucket));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_waiting_request_goes_once_a_token_has_refilled() {
+        let bucket = TokenBucket::new(20);
+        for _ in 0..20 {
+            bucket.acquire().await;
+        }
+
+        let started = Instant::now();
+        bucket.acquire().await;
+
+        assert_eq!(started.elapsed(), Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,9 @@
+//! Splitting names and addresses into words, shared by the geocoders, the
+//! candidate matching and the `mock_places` stand-in.
+
+/// Lowercase words of `text`, ignoring punctuation.
+pub fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
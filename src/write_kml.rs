@@ -7,31 +7,101 @@ use kml::{
     Kml, KmlWriter,
 };
 use std::collections::HashMap;
-use std::{
-    fs::{self, File},
-    path::Path,
-};
+use std::{fs, path::Path};
 
-use crate::model::{CoffeeMapConfig, IOError};
+use crate::atomic_file;
+use crate::cafe_status;
+use crate::candidate_match;
+use crate::model::{ClosedCafes, CoffeeMapConfig, IOError};
 
 pub const CUP_STYLE_ID: &str = "icon-1534-0288D1";
 
+/// `<styleUrl>` child giving a placemark the coffee cup icon.
+pub fn cup_style_url() -> Element {
+    Element {
+        name: "styleUrl".to_string(),
+        attrs: HashMap::<String, String>::new(),
+        content: Some(format!("#{}", CUP_STYLE_ID)),
+        children: vec![],
+    }
+}
+
+/// Name of the KML folder holding permanently closed cafes.
+const CLOSED_FOLDER_NAME: &str = "Closed";
+/// Name of the KML folder holding open cafes whose match needs review.
+const NEEDS_REVIEW_FOLDER_NAME: &str = "Needs review";
+
+/// Writes the placemarks into `folder` in chunks of `kml_batch_size` and returns the filenames.
+///
+/// Open cafes whose match needs review come after the others, in a "Needs
+/// review" folder of their chunk. Closed cafes come last, in a "Closed"
+/// folder of their chunk, or are left out, depending on `closed_cafes`.
 pub fn generate_kml_documents(
     config: &CoffeeMapConfig,
+    folder: String,
     placemarks: Vec<Placemark>,
-) -> Result<(), IOError> {
-    let mut chunk_id = 0;
+) -> Result<Vec<String>, IOError> {
+    let (closed, open): (Vec<Placemark>, Vec<Placemark>) =
+        placemarks.into_iter().partition(cafe_status::is_closed);
+    let closed = match config.closed_cafes {
+        ClosedCafes::Folder => closed,
+        ClosedCafes::Omit => vec![],
+    };
+
+    let (needing_review, matched): (Vec<Placemark>, Vec<Placemark>) =
+        open.into_iter().partition(candidate_match::needs_review);
+
+    let mut filenames = vec![];
+
+    for (chunk_id, placemarks_chunk) in (&matched
+        .into_iter()
+        .chain(needing_review)
+        .chain(closed)
+        .chunks(config.kml_batch_size))
+        .into_iter()
+        .enumerate()
+    {
+        let (closed_chunk, open_chunk): (Vec<Placemark>, Vec<Placemark>) =
+            placemarks_chunk.partition(cafe_status::is_closed);
+        let (review_chunk, matched_chunk): (Vec<Placemark>, Vec<Placemark>) = open_chunk
+            .into_iter()
+            .partition(candidate_match::needs_review);
 
-    for placemarks_chunk in &placemarks.into_iter().chunks(config.kml_batch_size) {
-        let placemarks_chunked = placemarks_chunk.into_iter().collect::<Vec<Placemark>>();
+        let mut elements = matched_chunk
+            .into_iter()
+            .map(Kml::Placemark)
+            .collect::<Vec<Kml>>();
+        if !review_chunk.is_empty() {
+            elements.push(folder_of(NEEDS_REVIEW_FOLDER_NAME, review_chunk));
+        }
+        if !closed_chunk.is_empty() {
+            elements.push(folder_of(CLOSED_FOLDER_NAME, closed_chunk));
+        }
 
         let filename = format!("{}_chunk_{}.kml", &config.output_prefix, chunk_id);
-        generate_kml_document(placemarks_chunked, config.output_folder.clone(), filename)?;
+        write_kml_document(elements, folder.clone(), filename.clone())?;
 
-        chunk_id += 1;
+        filenames.push(filename);
     }
 
-    Ok(())
+    Ok(filenames)
+}
+
+fn folder_of(name: &str, placemarks: Vec<Placemark>) -> Kml {
+    let name_tag = Kml::Element(Element {
+        name: "name".to_string(),
+        attrs: HashMap::<String, String>::new(),
+        content: Some(name.to_string()),
+        children: vec![],
+    });
+
+    let mut elements = vec![name_tag];
+    elements.extend(placemarks.into_iter().map(Kml::Placemark));
+
+    Kml::Folder {
+        attrs: HashMap::<String, String>::new(),
+        elements,
+    }
 }
 
 pub fn generate_kml_document(
@@ -39,6 +109,12 @@ pub fn generate_kml_document(
     folder: String,
     filename: String,
 ) -> Result<(), IOError> {
+    let elements = placemarks.into_iter().map(Kml::Placemark).collect();
+
+    write_kml_document(elements, folder, filename)
+}
+
+fn write_kml_document(contents: Vec<Kml>, folder: String, filename: String) -> Result<(), IOError> {
     let mut attrs = HashMap::<String, String>::new();
     attrs.insert(
         "xmlns".to_string(),
@@ -54,12 +130,8 @@ pub fn generate_kml_document(
     let style_tags = generate_styles();
 
     let mut elements = vec![name_tag];
-    elements.extend(style_tags.into_iter());
-    elements.extend(
-        placemarks
-            .into_iter()
-            .map(|placemark| Kml::Placemark(placemark)),
-    );
+    elements.extend(style_tags);
+    elements.extend(contents);
 
     let doc = Kml::Document {
         attrs: HashMap::<String, String>::new(),
@@ -72,23 +144,20 @@ pub fn generate_kml_document(
         elements: vec![doc],
     };
 
-    fs::create_dir_all(Path::new(&folder)).map_err(|err| IOError::CreateMissingDirectories(err))?;
-
-    let mut file = File::create(format!("{}/{}", folder, filename))
-        .map_err(|err| IOError::KMLFileCreation(err))?;
+    fs::create_dir_all(Path::new(&folder)).map_err(IOError::CreateMissingDirectories)?;
 
-    let mut writer = KmlWriter::from_writer(&mut file);
-
-    writer
+    let mut contents = Vec::new();
+    KmlWriter::from_writer(&mut contents)
         .write(&Kml::KmlDocument(document))
-        .map_err(|err| IOError::KMLWriteError(err))?;
+        .map_err(IOError::KMLWriteError)?;
 
-    Ok(())
+    atomic_file::write(&Path::new(&folder).join(filename), &contents)
+        .map_err(IOError::KMLFileCreation)
 }
 
-fn generate_icon_style(id: &String, scale: f64) -> Kml {
+fn generate_icon_style(id: &str, scale: f64) -> Kml {
     Kml::Style(Style {
-        id: Some(id.clone()),
+        id: Some(id.to_string()),
         balloon: None,
         icon: Some(IconStyle {
             id: None,
@@ -144,3 +213,99 @@ fn generate_styles() -> Vec<Kml> {
 
     vec![normal_style, highlight_style, style_map]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache;
+
+    fn placemark(name: &str, status: Option<&str>) -> Placemark {
+        let mut placemark = Placemark {
+            name: Some(name.to_string()),
+            ..Default::default()
+        };
+        if let Some(status) = status {
+            cafe_status::record_status(&mut placemark, status, "google");
+        }
+
+        placemark
+    }
+
+    fn write_and_read(closed_cafes: ClosedCafes) -> Vec<(Option<String>, bool)> {
+        let folder = std::env::temp_dir().join(format!(
+            "coffee_map_write_kml_{:?}_{}",
+            closed_cafes,
+            std::process::id()
+        ));
+        let config = CoffeeMapConfig {
+            closed_cafes,
+            ..Default::default()
+        };
+
+        let filenames = generate_kml_documents(
+            &config,
+            folder.display().to_string(),
+            vec![
+                placemark("Closed", Some(cafe_status::CLOSED_PERMANENTLY)),
+                placemark("Open", Some("OPERATIONAL")),
+                placemark("Unknown", None),
+                needing_review(placemark("Doubtful", Some("OPERATIONAL"))),
+                needing_review(placemark(
+                    "Doubtful and closed",
+                    Some(cafe_status::CLOSED_PERMANENTLY),
+                )),
+            ],
+        )
+        .unwrap();
+        assert_eq!(filenames, vec!["placemarks_chunk_0.kml"]);
+
+        let kml_string = fs::read_to_string(folder.join(&filenames[0])).unwrap();
+        let placemarks = cache::read_placemarks_from_file(folder.join(&filenames[0])).unwrap();
+        fs::remove_dir_all(&folder).unwrap();
+        assert_eq!(
+            kml_string.contains("<Folder><name>Closed</name>"),
+            closed_cafes == ClosedCafes::Folder
+        );
+        assert!(kml_string.contains("<Folder><name>Needs review</name>"));
+
+        placemarks
+            .into_iter()
+            .map(|placemark| (placemark.name.clone(), cafe_status::is_closed(&placemark)))
+            .collect()
+    }
+
+    fn needing_review(mut placemark: Placemark) -> Placemark {
+        placemark.attrs.insert(
+            candidate_match::NEEDS_REVIEW_ATTR.to_string(),
+            "true".to_string(),
+        );
+
+        placemark
+    }
+
+    #[test]
+    fn puts_closed_cafes_in_a_folder_after_the_open_ones() {
+        assert_eq!(
+            write_and_read(ClosedCafes::Folder),
+            vec![
+                (Some("Open".to_string()), false),
+                (Some("Unknown".to_string()), false),
+                (Some("Doubtful".to_string()), false),
+                (Some("Closed".to_string()), true),
+                (Some("Doubtful and closed".to_string()), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn can_leave_closed_cafes_out() {
+        assert_eq!(
+            write_and_read(ClosedCafes::Omit),
+            vec![
+                (Some("Open".to_string()), false),
+                (Some("Unknown".to_string()), false),
+                (Some("Doubtful".to_string()), false),
+            ]
+        );
+    }
+}